@@ -0,0 +1,107 @@
+// Rows per second through the serde `deserialize::<TransactionRow>()` loop
+// against RowParser (--fast-parse), on a generated file. Run with
+// `cargo bench --bench parse_throughput`. The file has 10M rows unless
+// PARSE_BENCH_ROWS says otherwise, and is kept in the temp dir between runs
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use csv::{ByteRecord, ReaderBuilder, StringRecord, Trim};
+use take_home::{RowParser, TransactionRow};
+
+const DEFAULT_ROWS: u64 = 10_000_000;
+
+fn rows() -> u64 {
+    std::env::var("PARSE_BENCH_ROWS")
+        .ok()
+        .and_then(|rows| rows.parse().ok())
+        .unwrap_or(DEFAULT_ROWS)
+}
+
+// Mostly deposits and withdrawals with the odd dispute, spaced the way the
+// test data is
+fn input(rows: u64) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("parse_throughput_{}.csv", rows));
+    if path.exists() {
+        return path;
+    }
+
+    let mut out = BufWriter::new(File::create(&path).unwrap());
+    writeln!(out, "type, client, tx, amount").unwrap();
+    for tx in 1..=rows {
+        let client = tx % 65_536;
+        match tx % 10 {
+            9 => writeln!(out, "dispute, {}, {},", client, tx - 1),
+            3 | 6 => writeln!(
+                out,
+                "withdrawal, {}, {}, {}.{:04}",
+                client,
+                tx,
+                tx % 50,
+                tx % 10_000
+            ),
+            _ => writeln!(
+                out,
+                "deposit, {}, {}, {}.{:04}",
+                client,
+                tx,
+                tx % 1_000,
+                tx % 10_000
+            ),
+        }
+        .unwrap();
+    }
+    out.flush().unwrap();
+    path
+}
+
+fn reader(path: &Path, trim: Trim) -> csv::Reader<BufReader<File>> {
+    ReaderBuilder::new()
+        .trim(trim)
+        .flexible(true)
+        .from_reader(BufReader::new(File::open(path).unwrap()))
+}
+
+fn serde_loop(path: &Path) -> u64 {
+    let mut reader = reader(path, Trim::All);
+    let headers = reader.headers().unwrap().clone();
+    let mut record = StringRecord::new();
+    let mut parsed = 0;
+    while reader.read_record(&mut record).unwrap() {
+        if record.deserialize::<TransactionRow>(Some(&headers)).is_ok() {
+            parsed += 1;
+        }
+    }
+    parsed
+}
+
+fn fast_loop(path: &Path) -> u64 {
+    let mut reader = reader(path, Trim::None);
+    let parser = RowParser::new(reader.byte_headers().unwrap());
+    let mut record = ByteRecord::new();
+    let mut parsed = 0;
+    while reader.read_byte_record(&mut record).unwrap() {
+        if parser.parse(&record).is_ok() {
+            parsed += 1;
+        }
+    }
+    parsed
+}
+
+fn parse_throughput(c: &mut Criterion) {
+    let rows = rows();
+    let path = input(rows);
+
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(rows));
+    group.bench_function("serde", |b| b.iter(|| assert_eq!(serde_loop(&path), rows)));
+    group.bench_function("fast_parse", |b| {
+        b.iter(|| assert_eq!(fast_loop(&path), rows))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse_throughput);
+criterion_main!(benches);
@@ -0,0 +1,114 @@
+// Heap bytes per row kept by a whole Engine once the rows are applied,
+// against the HashMap<u16, AccountRecord> and HashMap<u32, TransactionRow>
+// the engine started out with. Run with `cargo bench --bench tx_store_memory`
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rust_decimal::Decimal;
+use take_home::{AccountRecord, Engine, TransactionRow, TransactionType};
+
+// Counts live heap bytes, so the difference around a build is what it kept
+struct CountingAlloc;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LIVE.fetch_add(new_size, Ordering::Relaxed);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+const ROWS: u32 = 1_000_000;
+
+// Each client makes three deposits and then a withdrawal it can cover
+fn row(index: u32, tx: u32) -> TransactionRow {
+    let client = (index / 4 % 65_536) as u16;
+    if index % 4 == 3 {
+        TransactionRow::new(
+            TransactionType::Withdrawal,
+            client,
+            tx,
+            Some(Decimal::new(1, 4)),
+        )
+    } else {
+        TransactionRow::new(
+            TransactionType::Deposit,
+            client,
+            tx,
+            Some(Decimal::new(tx as i64 % 10_000 + 1, 4)),
+        )
+    }
+}
+
+fn bytes_per_row<T>(rows: &[TransactionRow], build: impl Fn(&[TransactionRow]) -> T) -> f64 {
+    let before = LIVE.load(Ordering::Relaxed);
+    let kept = build(rows);
+    let used = LIVE.load(Ordering::Relaxed) - before;
+    drop(kept);
+    used as f64 / rows.len() as f64
+}
+
+// What the engine kept before: every account, and every deposit whole
+fn hash_maps(
+    rows: &[TransactionRow],
+) -> (HashMap<u16, AccountRecord>, HashMap<u32, TransactionRow>) {
+    let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
+    let mut transactions = HashMap::new();
+    for row in rows {
+        let account = accounts.entry(row.client).or_default();
+        let amount = row.amount.unwrap();
+        if row.tx_type == TransactionType::Deposit {
+            account.available += amount;
+            transactions.insert(row.tx, row.clone());
+        } else {
+            account.available -= amount;
+        }
+    }
+    (accounts, transactions)
+}
+
+fn engine(rows: &[TransactionRow]) -> Engine {
+    let mut engine = Engine::new();
+    for row in rows {
+        engine.apply(row.clone()).unwrap();
+    }
+    engine
+}
+
+fn main() {
+    let sequential: Vec<TransactionRow> = (0..ROWS).map(|i| row(i, i + 1)).collect();
+    // Spread over the whole u32 range, so the store has to go sparse
+    let scattered: Vec<TransactionRow> = (0..ROWS)
+        .map(|i| row(i, (i + 1).wrapping_mul(2_654_435_761)))
+        .collect();
+
+    println!(
+        "{} rows (3 deposits to 1 withdrawal), heap bytes per row",
+        ROWS
+    );
+    println!("ids        HashMaps (before)  Engine");
+    for (name, rows) in [("sequential", &sequential), ("scattered", &scattered)] {
+        println!(
+            "{:<10} {:>17.1}  {:>6.1}",
+            name,
+            bytes_per_row(rows, hash_maps),
+            bytes_per_row(rows, engine)
+        );
+    }
+}
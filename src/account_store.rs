@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::EngineError;
+use crate::model::AccountRecord;
+
+// Where the Engine keeps client accounts. Like TransactionStore, reads hand
+// back copies and every change is written back with upsert(), so the
+// handlers work the same whatever holds the accounts
+pub trait AccountStore: fmt::Debug {
+    fn get(&self, client: u16) -> Result<Option<AccountRecord>, EngineError>;
+
+    fn upsert(&mut self, client: u16, account: AccountRecord) -> Result<(), EngineError>;
+
+    // Every account, in no particular order
+    fn for_each(&self, f: &mut dyn FnMut(u16, AccountRecord)) -> Result<(), EngineError>;
+
+    // Makes everything written so far durable, for stores that batch writes
+    fn flush(&mut self) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    // Whether what is written outlives the process once flushed, in which
+    // case a Snapshot leaves the accounts where they are
+    fn is_durable(&self) -> bool {
+        false
+    }
+
+    // Engine::apply() brackets each row's writes with these. A store that
+    // batches writes only commits between rows, and undoes a row that failed
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    fn end_row(&mut self, _applied: bool) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    // Read, change and write back an existing account. Nothing is written
+    // when `f` fails, and a client without an account is UnknownAccount
+    fn update(
+        &mut self,
+        client: u16,
+        f: &mut dyn FnMut(&mut AccountRecord) -> Result<(), EngineError>,
+    ) -> Result<(), EngineError> {
+        let mut account = self
+            .get(client)?
+            .ok_or(EngineError::UnknownAccount { client })?;
+        f(&mut account)?;
+        self.upsert(client, account)
+    }
+}
+
+impl<S: AccountStore + ?Sized> AccountStore for Box<S> {
+    fn get(&self, client: u16) -> Result<Option<AccountRecord>, EngineError> {
+        (**self).get(client)
+    }
+
+    fn upsert(&mut self, client: u16, account: AccountRecord) -> Result<(), EngineError> {
+        (**self).upsert(client, account)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(u16, AccountRecord)) -> Result<(), EngineError> {
+        (**self).for_each(f)
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        (**self).flush()
+    }
+
+    fn is_durable(&self) -> bool {
+        (**self).is_durable()
+    }
+
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        (**self).begin_row()
+    }
+
+    fn end_row(&mut self, applied: bool) -> Result<(), EngineError> {
+        (**self).end_row(applied)
+    }
+}
+
+impl AccountStore for HashMap<u16, AccountRecord> {
+    fn get(&self, client: u16) -> Result<Option<AccountRecord>, EngineError> {
+        Ok(HashMap::get(self, &client).copied())
+    }
+
+    fn upsert(&mut self, client: u16, account: AccountRecord) -> Result<(), EngineError> {
+        self.insert(client, account);
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(u16, AccountRecord)) -> Result<(), EngineError> {
+        self.iter()
+            .for_each(|(client, account)| f(*client, *account));
+        Ok(())
+    }
+}
+
+// Which of `shards` shards a client belongs to, wherever accounts are sharded
+pub(crate) fn shard_of(client: u16, shards: usize) -> usize {
+    client as usize % shards
+}
+
+// Accounts spread over a fixed number of maps by client ID. Every client
+// always lands in the same shard, so shards can be split between workers
+#[derive(Debug)]
+pub struct ShardedAccounts {
+    shards: Vec<HashMap<u16, AccountRecord>>,
+}
+
+impl ShardedAccounts {
+    pub fn new(shards: usize) -> Self {
+        ShardedAccounts {
+            shards: vec![HashMap::new(); shards.max(1)],
+        }
+    }
+
+    pub fn shard_of(&self, client: u16) -> usize {
+        shard_of(client, self.shards.len())
+    }
+}
+
+impl AccountStore for ShardedAccounts {
+    fn get(&self, client: u16) -> Result<Option<AccountRecord>, EngineError> {
+        Ok(self.shards[self.shard_of(client)].get(&client).copied())
+    }
+
+    fn upsert(&mut self, client: u16, account: AccountRecord) -> Result<(), EngineError> {
+        let shard = self.shard_of(client);
+        self.shards[shard].insert(client, account);
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(u16, AccountRecord)) -> Result<(), EngineError> {
+        for shard in &self.shards {
+            shard
+                .iter()
+                .for_each(|(client, account)| f(*client, *account));
+        }
+        Ok(())
+    }
+}
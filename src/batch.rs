@@ -0,0 +1,151 @@
+use crate::disk_store::storage_error;
+use crate::error::EngineError;
+
+// Rows are committed (and so fsync'd) this many at a time
+pub(crate) const BATCH_SIZE: usize = 10_000;
+
+// The database transaction a store keeps open across many rows
+pub(crate) trait Backend {
+    // Before the row's first write
+    fn begin_row(&mut self) -> Result<(), EngineError>;
+
+    // Leaves the row's writes in the open transaction
+    fn keep_row(&mut self) -> Result<(), EngineError>;
+
+    // Takes the row's writes back out of it
+    fn undo_row(&mut self) -> Result<(), EngineError>;
+
+    // Nothing to do when no transaction is open
+    fn commit(&mut self) -> Result<(), EngineError>;
+
+    fn rollback(&mut self);
+}
+
+// Commits a store's writes BATCH_SIZE rows at a time, and only ever between
+// rows. The Engine brackets each row's writes with begin_row() and end_row(),
+// and a row that failed is undone. A write made outside a row (restoring a
+// snapshot, say) is a row of its own. Once something can't be undone the
+// whole batch is rolled back, and every later write fails, rather than
+// commit half a row. A read-only batch never commits, and is rolled back
+// once dropped
+pub(crate) struct Batch<B: Backend> {
+    pub(crate) backend: B,
+    rows: usize,
+    in_row: bool,
+    failed: bool,
+    read_only: bool,
+}
+
+impl<B: Backend> Batch<B> {
+    pub(crate) fn new(backend: B, read_only: bool) -> Self {
+        Batch {
+            backend,
+            rows: 0,
+            in_row: false,
+            failed: false,
+            read_only,
+        }
+    }
+
+    // Rows written since the last commit
+    pub(crate) fn pending(&self) -> usize {
+        self.rows
+    }
+
+    // Both stores of a pair call this, the second finds the row already begun
+    pub(crate) fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.check()?;
+        if !self.in_row {
+            self.backend.begin_row()?;
+            self.in_row = true;
+        }
+        Ok(())
+    }
+
+    // Every write goes through here
+    pub(crate) fn write<T>(
+        &mut self,
+        f: impl FnOnce(&mut B) -> Result<T, EngineError>,
+    ) -> Result<T, EngineError> {
+        self.check()?;
+        let written = f(&mut self.backend);
+        if !self.in_row {
+            match &written {
+                Ok(_) => self.row_done()?,
+                Err(_) => self.fail(),
+            }
+        }
+        written
+    }
+
+    // As with begin_row(), the second store of a pair finds the row ended
+    pub(crate) fn end_row(&mut self, applied: bool) -> Result<(), EngineError> {
+        if !self.in_row {
+            return Ok(());
+        }
+        self.in_row = false;
+        let ended = if applied {
+            self.backend.keep_row()
+        } else {
+            self.backend.undo_row()
+        };
+        if let Err(err) = ended {
+            self.fail();
+            return Err(err);
+        }
+        self.row_done()
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), EngineError> {
+        self.check()?;
+        if self.read_only {
+            return Ok(());
+        }
+        if self.in_row {
+            // Committed as soon as the row ends
+            self.rows = BATCH_SIZE;
+            return Ok(());
+        }
+        if let Err(err) = self.backend.commit() {
+            self.fail();
+            return Err(err);
+        }
+        self.rows = 0;
+        Ok(())
+    }
+
+    fn row_done(&mut self) -> Result<(), EngineError> {
+        self.rows += 1;
+        if self.rows >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn fail(&mut self) {
+        self.failed = true;
+        self.backend.rollback();
+    }
+
+    fn check(&self) -> Result<(), EngineError> {
+        if self.failed {
+            return Err(storage_error(
+                "an earlier write failed, everything since the last commit was rolled back",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Whatever is still in the batch is committed once the last handle goes,
+// apart from a row that never ended. An error here has nowhere to go,
+// callers that care flush() first
+impl<B: Backend> Drop for Batch<B> {
+    fn drop(&mut self) {
+        if self.read_only {
+            self.backend.rollback();
+        } else if self.end_row(false).is_ok() {
+            let _ = self.flush();
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::ffi::OsString;
+
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+use take_home::{RedisputePolicy, SortBy, TxIdPolicy, DEFAULT_SYNC_EVERY};
+
+/// Payments engine: applies transactions csvs and reports client balances.
+///
+/// With no subcommand the arguments are those of `process`, so
+/// `take_home transactions.csv > accounts.csv` keeps working.
+///
+/// Exit codes: 0 every row applied, 1 fatal I/O error, 2 usage error,
+/// 3 finished but one or more rows were rejected.
+#[derive(Debug, Parser)]
+#[command(name = "take_home")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    // clap can't default to a subcommand that takes a positional, so `process`
+    // is slipped in when the first argument isn't a subcommand or --help
+    pub fn parse_with_default() -> Self {
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        let first = args.get(1).and_then(|arg| arg.to_str()).unwrap_or_default();
+        let is_top_level = Cli::command()
+            .get_subcommands()
+            .any(|command| command.get_name() == first)
+            || matches!(first, "help" | "-h" | "--help" | "-V" | "--version");
+        if !is_top_level {
+            args.insert(1, "process".into());
+        }
+        Cli::parse_from(args)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Apply transactions and write the account report
+    Process(ProcessArgs),
+    /// Apply transactions and write the rejections report instead of balances
+    Validate(ReportArgs),
+    /// Process a batch that may overlap earlier ones, identical rows are skipped
+    Replay(ProcessArgs),
+    /// Apply transactions, then show a single account or stored transaction
+    Inspect(InspectArgs),
+    /// Rebuild the accounts from a --wal log alone, checking every entry
+    ReplayWal(ReplayWalArgs),
+    /// Accept csv rows from many connections at once, answering each row
+    Serve(ServeArgs),
+    /// Serve a JSON API for submitting transactions and reading accounts
+    Http(HttpArgs),
+    /// Apply transactions and write the accounts and every row to Parquet
+    Export(ExportArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct LogArgs {
+    /// Where the run log is written
+    #[arg(long, default_value = "run_log.txt")]
+    pub log_file: String,
+
+    /// trace, debug, info, warn, error or off
+    #[arg(long, default_value = "debug")]
+    pub log_level: String,
+}
+
+// Everything needed to feed an input file through the Engine
+#[derive(Debug, Args)]
+pub struct InputArgs {
+    /// Transactions csvs, applied in order to one set of accounts. '-' reads stdin
+    #[arg(required = true, num_args = 1..)]
+    pub inputs: Vec<String>,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    #[command(flatten)]
+    pub engine: EngineArgs,
+
+    #[command(flatten)]
+    pub rows: RowReportArgs,
+
+    /// Apply rows on this many threads, each owning the clients whose ID
+    /// falls in its shard. The output is the same as with one. The
+    /// accounts are kept in memory, so --tx-store and --sqlite can't be used
+    #[arg(long, default_value_t = 1, conflicts_with_all = ["tx_store", "sqlite"])]
+    pub workers: usize,
+
+    #[command(flatten)]
+    pub parse: ParseArgs,
+
+    /// csv or jsonl. By default .jsonl and .ndjson inputs are read as JSON
+    /// lines and everything else (stdin included) as csv
+    #[arg(long, value_enum)]
+    pub input_format: Option<InputFormat>,
+}
+
+// The per-row reports a command can write alongside its own output
+#[derive(Debug, Args)]
+pub struct RowReportArgs {
+    /// Also write every skipped or failed row to this csv
+    #[arg(long)]
+    pub rejections: Option<String>,
+
+    /// Write a row for every applied transaction to this csv: what it
+    /// changed and the balances it left
+    #[arg(long)]
+    pub ledger: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ParseArgs {
+    /// Parse rows with the allocation free reader instead of serde. Same
+    /// rows accepted and rejected, only the log messages differ
+    #[arg(long)]
+    pub fast_parse: bool,
+}
+
+// How the Engine is set up and where its state is kept, for every command
+// that applies transactions
+#[derive(Debug, Args)]
+pub struct EngineArgs {
+    /// Allow withdrawals to be disputed as well as deposits
+    #[arg(long)]
+    pub dispute_withdrawals: bool,
+
+    /// What to do with a reused tx ID: reject, warn or replay
+    #[arg(long, default_value = "reject")]
+    pub tx_id_policy: TxIdPolicy,
+
+    /// Whether a resolved transaction can be disputed again: allow or deny
+    #[arg(long, default_value = "allow")]
+    pub redispute: RedisputePolicy,
+
+    /// Start from a snapshot written by --save-state instead of empty accounts
+    #[arg(long)]
+    pub load_state: Option<String>,
+
+    /// Keep accounts and stored transactions in this on-disk store rather
+    /// than in memory. It is created if missing and kept between runs
+    #[arg(long, conflicts_with = "sqlite")]
+    pub tx_store: Option<String>,
+
+    /// Keep accounts, stored transactions and dispute events in this SQLite
+    /// database. It is created (or migrated) if needed and kept between runs
+    #[arg(long)]
+    pub sqlite: Option<String>,
+
+    /// Log every applied transaction here, replaying any already in it first.
+    /// --tx-store and --sqlite keep the state themselves, so can't be used
+    #[arg(long, conflicts_with_all = ["tx_store", "sqlite"])]
+    pub wal: Option<String>,
+
+    /// fsync the --wal after this many transactions (and at the end of the run)
+    #[arg(long, default_value_t = DEFAULT_SYNC_EVERY)]
+    pub wal_sync_every: usize,
+}
+
+// Where and how a command writes its report
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// Write the report here instead of stdout
+    #[arg(long, short)]
+    pub output: Option<String>,
+
+    /// How the report is written
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub format: OutputFormat,
+}
+
+// What goes in an account report
+#[derive(Debug, Args)]
+pub struct AccountsArgs {
+    /// Order accounts by client, available, held or total
+    #[arg(long, default_value = "client")]
+    pub sort_by: SortBy,
+
+    /// Add each account's status (active, disputed or locked) and number of
+    /// open disputes to the report
+    #[arg(long)]
+    pub details: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ProcessArgs {
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    #[command(flatten)]
+    pub accounts: AccountsArgs,
+
+    /// Write a snapshot of the final state here, for a later --load-state
+    #[arg(long)]
+    pub save_state: Option<String>,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false, id = "target")]
+pub struct InspectTarget {
+    /// Show this client's account
+    #[arg(long)]
+    pub client: Option<u16>,
+
+    /// Show this stored transaction
+    #[arg(long)]
+    pub tx: Option<u32>,
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    #[command(flatten)]
+    pub target: InspectTarget,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayWalArgs {
+    /// Log written by --wal
+    pub wal: String,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    #[command(flatten)]
+    pub accounts: AccountsArgs,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = true, id = "exports")]
+pub struct ExportTargets {
+    /// Write the final account table, by client, to this Parquet file
+    #[arg(long)]
+    pub accounts: Option<String>,
+
+    /// Write every processed row to this Parquet file, in input order, with
+    /// whether it was accepted and the state it left its transaction in
+    #[arg(long)]
+    pub transactions: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub exports: ExportTargets,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false, id = "address")]
+pub struct ListenAddress {
+    /// Listen on this TCP address, e.g. 127.0.0.1:7878 (port 0 picks one)
+    #[arg(long)]
+    pub listen: Option<String>,
+
+    /// Listen on a Unix socket at this path
+    #[cfg(unix)]
+    #[arg(long)]
+    pub unix: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    pub address: ListenAddress,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    #[command(flatten)]
+    pub engine: EngineArgs,
+
+    #[command(flatten)]
+    pub rows: RowReportArgs,
+
+    #[command(flatten)]
+    pub parse: ParseArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HttpArgs {
+    /// Listen on this TCP address, e.g. 127.0.0.1:8080 (port 0 picks one)
+    #[arg(long)]
+    pub listen: String,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    #[command(flatten)]
+    pub engine: EngineArgs,
+
+    #[command(flatten)]
+    pub rows: RowReportArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    Csv,
+    /// One JSON object per line, with the csv's column names as keys
+    Jsonl,
+}
+
+impl InputFormat {
+    // A compressed input is named for what it holds, e.g. events.jsonl.gz
+    pub fn of_path(path: &str) -> Self {
+        let extension = std::path::Path::new(crate::compress::strip_compression(path))
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("jsonl" | "ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    /// One JSON array of objects, amounts as strings
+    Json,
+    /// One JSON object per line, amounts as strings
+    Jsonl,
+}
@@ -0,0 +1,91 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// Inputs are decompressed when they start with a gzip or zstd header,
+// whatever they are called, so stdin works too. Anything else is read as is
+pub fn decompress(input: Box<dyn Read>) -> std::io::Result<Box<dyn Read>> {
+    let mut input = BufReader::new(input);
+    let start = input.fill_buf()?;
+    Ok(if start.starts_with(GZIP_MAGIC) {
+        // Concatenated members, as `cat a.gz b.gz` makes, are read as one
+        Box::new(MultiGzDecoder::new(input))
+    } else if start.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(input)?)
+    } else {
+        Box::new(input)
+    })
+}
+
+// The path without a .gz, .zst or .zstd ending, to find what the file holds
+pub fn strip_compression(path: &str) -> &str {
+    [".gz", ".zst", ".zstd"]
+        .iter()
+        .find_map(|extension| {
+            let split = path.len().checked_sub(extension.len())?;
+            path.get(split..)?
+                .eq_ignore_ascii_case(extension)
+                .then(|| &path[..split])
+        })
+        .unwrap_or(path)
+}
+
+// Where a report goes, compressed when its path ends in .gz, .zst or .zstd.
+// finish() has to be called to complete a compressed file
+pub enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+}
+
+impl Output {
+    pub fn for_path(path: &str, out: Box<dyn Write>) -> std::io::Result<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        Ok(match extension.as_deref() {
+            Some("gz") => Output::Gzip(GzEncoder::new(out, flate2::Compression::default())),
+            Some("zst" | "zstd") => Output::Zstd(zstd::Encoder::new(out, 0)?),
+            _ => Output::Plain(out),
+        })
+    }
+
+    // Writes the compressed stream's trailer. Nothing can be written after
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(out) => out.flush(),
+            Output::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()
+            }
+            Output::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(out) => out.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+            Output::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(out) => out.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// What to do when a deposit or withdrawal reuses a tx ID seen earlier
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TxIdPolicy {
+    // Refuse the row with EngineError::DuplicateTx
+    #[default]
+    Reject,
+    // Let the row through flagged as reused. It is still refused where it
+    // would overwrite a stored transaction (e.g. a second deposit)
+    Warn,
+    // Skip a row identical to the original, so replayed batches are harmless,
+    // and refuse any other reuse
+    IdempotentReplay,
+}
+
+impl TxIdPolicy {
+    // Same spelling FromStr accepts
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxIdPolicy::Reject => "reject",
+            TxIdPolicy::Warn => "warn",
+            TxIdPolicy::IdempotentReplay => "replay",
+        }
+    }
+}
+
+impl std::str::FromStr for TxIdPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(TxIdPolicy::Reject),
+            "warn" => Ok(TxIdPolicy::Warn),
+            "replay" => Ok(TxIdPolicy::IdempotentReplay),
+            other => Err(format!(
+                "Unknown tx ID policy: {} (expected reject|warn|replay)",
+                other
+            )),
+        }
+    }
+}
+
+// Whether a transaction whose dispute was resolved can be disputed again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedisputePolicy {
+    #[default]
+    Allow,
+    // A resolve is final, a later dispute is refused with RedisputeNotAllowed
+    Deny,
+}
+
+impl RedisputePolicy {
+    // Same spelling FromStr accepts
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RedisputePolicy::Allow => "allow",
+            RedisputePolicy::Deny => "deny",
+        }
+    }
+}
+
+impl std::str::FromStr for RedisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(RedisputePolicy::Allow),
+            "deny" => Ok(RedisputePolicy::Deny),
+            other => Err(format!(
+                "Unknown re-dispute policy: {} (expected allow|deny)",
+                other
+            )),
+        }
+    }
+}
+
+// Per deployment knobs for the engine. Defaults match the Specification
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineConfig {
+    // Card-payments deployments can dispute withdrawals as well as deposits
+    pub dispute_withdrawals: bool,
+    pub tx_id_policy: TxIdPolicy,
+    pub redispute: RedisputePolicy,
+}
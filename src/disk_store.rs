@@ -0,0 +1,506 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use rust_decimal::Decimal;
+
+use crate::account_store::AccountStore;
+use crate::batch::{Backend, Batch};
+use crate::error::EngineError;
+use crate::lifecycle::TxState;
+use crate::model::{AccountRecord, TransactionRow, TransactionType};
+use crate::store::{StoredTx, TransactionStore};
+
+const ACCOUNTS: TableDefinition<u16, [u8; 33]> = TableDefinition::new("accounts");
+const TRANSACTIONS: TableDefinition<u32, [u8; 20]> = TableDefinition::new("transactions");
+
+pub(crate) fn storage_error(err: impl fmt::Display) -> EngineError {
+    EngineError::Storage {
+        message: err.to_string(),
+    }
+}
+
+// AccountRecord as it lies on disk: available, held, locked
+fn encode_account(account: &AccountRecord) -> [u8; 33] {
+    let mut bytes = [0; 33];
+    bytes[..16].copy_from_slice(&account.available.serialize());
+    bytes[16..32].copy_from_slice(&account.held.serialize());
+    bytes[32] = account.locked as u8;
+    bytes
+}
+
+fn decode_account(client: u16, bytes: [u8; 33]) -> Result<AccountRecord, EngineError> {
+    let mut available = [0; 16];
+    available.copy_from_slice(&bytes[..16]);
+    let mut held = [0; 16];
+    held.copy_from_slice(&bytes[16..32]);
+    Ok(AccountRecord {
+        available: Decimal::deserialize(available),
+        held: Decimal::deserialize(held),
+        locked: match bytes[32] {
+            0 => false,
+            1 => true,
+            _ => {
+                return Err(storage_error(format!(
+                    "stored account: {} is corrupt",
+                    client
+                )))
+            }
+        },
+    })
+}
+
+// StoredTx as it lies on disk: amount, client, type, state
+fn encode(stored: &StoredTx) -> [u8; 20] {
+    let mut bytes = [0; 20];
+    bytes[..16].copy_from_slice(&stored.amount.serialize());
+    bytes[16..18].copy_from_slice(&stored.client.to_le_bytes());
+    bytes[18] = match stored.tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+    };
+    bytes[19] = match stored.state {
+        TxState::Processed => 0,
+        TxState::Disputed => 1,
+        TxState::Resolved => 2,
+        TxState::ChargedBack => 3,
+    };
+    bytes
+}
+
+fn decode(tx: u32, bytes: [u8; 20]) -> Result<StoredTx, EngineError> {
+    let mut amount = [0; 16];
+    amount.copy_from_slice(&bytes[..16]);
+    let corrupt = || storage_error(format!("stored transaction: {} is corrupt", tx));
+    Ok(StoredTx {
+        amount: Decimal::deserialize(amount),
+        client: u16::from_le_bytes([bytes[16], bytes[17]]),
+        tx_type: match bytes[18] {
+            0 => TransactionType::Deposit,
+            1 => TransactionType::Withdrawal,
+            2 => TransactionType::Dispute,
+            3 => TransactionType::Resolve,
+            4 => TransactionType::Chargeback,
+            _ => return Err(corrupt()),
+        },
+        state: match bytes[19] {
+            0 => TxState::Processed,
+            1 => TxState::Disputed,
+            2 => TxState::Resolved,
+            3 => TxState::ChargedBack,
+            _ => return Err(corrupt()),
+        },
+    })
+}
+
+// Every account in client order, from either a read or a write transaction's table
+fn visit_accounts(
+    table: &impl ReadableTable<u16, [u8; 33]>,
+    f: &mut dyn FnMut(u16, AccountRecord),
+) -> Result<(), EngineError> {
+    for entry in table.iter().map_err(storage_error)? {
+        let (client, bytes) = entry.map_err(storage_error)?;
+        f(
+            client.value(),
+            decode_account(client.value(), bytes.value())?,
+        );
+    }
+    Ok(())
+}
+
+// Every entry in tx order, from either a read or a write transaction's table
+fn visit(
+    table: &impl ReadableTable<u32, [u8; 20]>,
+    f: &mut dyn FnMut(u32, StoredTx),
+) -> Result<(), EngineError> {
+    for entry in table.iter().map_err(storage_error)? {
+        let (tx, bytes) = entry.map_err(storage_error)?;
+        f(tx.value(), decode(tx.value(), bytes.value())?);
+    }
+    Ok(())
+}
+
+// What a row's write replaced, put back if the row is undone
+enum Undo {
+    Account(u16, Option<[u8; 33]>),
+    Transaction(u32, Option<[u8; 20]>),
+}
+
+// The database both halves write through, so accounts and transactions land
+// in the same batch and are committed together
+struct Redb {
+    db: Database,
+    batch: Option<WriteTransaction>,
+    transactions: usize,
+    // What the open row has overwritten so far. redb only takes savepoints
+    // before a transaction's first write, so a row is undone by hand
+    undo: Option<Vec<Undo>>,
+}
+
+impl Redb {
+    fn batch(&mut self) -> Result<&WriteTransaction, EngineError> {
+        if self.batch.is_none() {
+            self.batch = Some(self.db.begin_write().map_err(storage_error)?);
+        }
+        Ok(self.batch.as_ref().expect("batch was just opened"))
+    }
+
+    // Stores `bytes` under `client`, or removes it when None. Hands back what was there
+    fn write_account(
+        &mut self,
+        client: u16,
+        bytes: Option<[u8; 33]>,
+    ) -> Result<Option<[u8; 33]>, EngineError> {
+        let replaced = {
+            let mut table = self.batch()?.open_table(ACCOUNTS).map_err(storage_error)?;
+            let replaced = match bytes {
+                Some(bytes) => table.insert(client, bytes),
+                None => table.remove(client),
+            }
+            .map_err(storage_error)?
+            .map(|value| value.value());
+            replaced
+        };
+        if let Some(undo) = &mut self.undo {
+            undo.push(Undo::Account(client, replaced));
+        }
+        Ok(replaced)
+    }
+
+    // As write_account(), keeping count of the transactions
+    fn write_transaction(
+        &mut self,
+        tx: u32,
+        bytes: Option<[u8; 20]>,
+    ) -> Result<Option<[u8; 20]>, EngineError> {
+        let replaced = {
+            let mut table = self
+                .batch()?
+                .open_table(TRANSACTIONS)
+                .map_err(storage_error)?;
+            let replaced = match bytes {
+                Some(bytes) => table.insert(tx, bytes),
+                None => table.remove(tx),
+            }
+            .map_err(storage_error)?
+            .map(|value| value.value());
+            replaced
+        };
+        match (bytes.is_some(), replaced.is_some()) {
+            (true, false) => self.transactions += 1,
+            (false, true) => self.transactions -= 1,
+            _ => {}
+        }
+        if let Some(undo) = &mut self.undo {
+            undo.push(Undo::Transaction(tx, replaced));
+        }
+        Ok(replaced)
+    }
+}
+
+impl Backend for Redb {
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.undo = Some(Vec::new());
+        Ok(())
+    }
+
+    fn keep_row(&mut self) -> Result<(), EngineError> {
+        self.undo = None;
+        Ok(())
+    }
+
+    // Puts back what each write replaced, latest first
+    fn undo_row(&mut self) -> Result<(), EngineError> {
+        for write in self.undo.take().into_iter().flatten().rev() {
+            match write {
+                Undo::Account(client, bytes) => {
+                    self.write_account(client, bytes)?;
+                }
+                Undo::Transaction(tx, bytes) => {
+                    self.write_transaction(tx, bytes)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), EngineError> {
+        match self.batch.take() {
+            Some(batch) => batch.commit().map_err(storage_error),
+            None => Ok(()),
+        }
+    }
+
+    fn rollback(&mut self) {
+        self.undo = None;
+        if let Some(batch) = self.batch.take() {
+            let _ = batch.abort();
+        }
+    }
+}
+
+// An embedded B-tree file (redb) holding accounts and stored transactions,
+// for state that doesn't fit in memory. Everything in it survives the
+// process, so a later run can still dispute a transaction applied months
+// ago. accounts() and transactions() hand out the two stores the Engine
+// takes, both writing into one open transaction that is committed every
+// BATCH_SIZE rows and on flush()
+#[derive(Clone)]
+pub struct DiskStore {
+    shared: Rc<RefCell<Batch<Redb>>>,
+}
+
+impl fmt::Debug for DiskStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared = self.shared.borrow();
+        f.debug_struct("DiskStore")
+            .field("pending", &shared.pending())
+            .field("transactions", &shared.backend.transactions)
+            .finish()
+    }
+}
+
+impl DiskStore {
+    // Opens the store at `path`, creating it if it doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        DiskStore::with_database(Database::create(path).map_err(storage_error)?, false)
+    }
+
+    // Opens an existing store for a run that must leave it as it was. It
+    // takes writes like any other, but they are never committed
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        DiskStore::with_database(Database::open(path).map_err(storage_error)?, true)
+    }
+
+    fn with_database(db: Database, read_only: bool) -> Result<Self, EngineError> {
+        let batch = db.begin_write().map_err(storage_error)?;
+        batch.open_table(ACCOUNTS).map_err(storage_error)?;
+        let transactions = batch
+            .open_table(TRANSACTIONS)
+            .map_err(storage_error)?
+            .len()
+            .map_err(storage_error)? as usize;
+        // A read-only store keeps the one transaction open until it is dropped
+        let batch = if read_only {
+            Some(batch)
+        } else {
+            batch.commit().map_err(storage_error)?;
+            None
+        };
+
+        Ok(DiskStore {
+            shared: Rc::new(RefCell::new(Batch::new(
+                Redb {
+                    db,
+                    batch,
+                    transactions,
+                    undo: None,
+                },
+                read_only,
+            ))),
+        })
+    }
+
+    pub fn accounts(&self) -> DiskAccounts {
+        DiskAccounts {
+            store: self.clone(),
+        }
+    }
+
+    pub fn transactions(&self) -> DiskTxStore {
+        DiskTxStore {
+            store: self.clone(),
+        }
+    }
+
+    // Commits everything written so far
+    pub fn flush(&self) -> Result<(), EngineError> {
+        self.shared.borrow_mut().flush()
+    }
+}
+
+// The accounts table as an AccountStore
+#[derive(Debug, Clone)]
+pub struct DiskAccounts {
+    store: DiskStore,
+}
+
+impl AccountStore for DiskAccounts {
+    // Reads go through the open batch when there is one, so they see its writes
+    fn get(&self, client: u16) -> Result<Option<AccountRecord>, EngineError> {
+        let shared = self.store.shared.borrow();
+        let bytes = match &shared.backend.batch {
+            Some(batch) => batch
+                .open_table(ACCOUNTS)
+                .map_err(storage_error)?
+                .get(client)
+                .map_err(storage_error)?
+                .map(|value| value.value()),
+            None => shared
+                .backend
+                .db
+                .begin_read()
+                .map_err(storage_error)?
+                .open_table(ACCOUNTS)
+                .map_err(storage_error)?
+                .get(client)
+                .map_err(storage_error)?
+                .map(|value| value.value()),
+        };
+        bytes.map(|bytes| decode_account(client, bytes)).transpose()
+    }
+
+    fn upsert(&mut self, client: u16, account: AccountRecord) -> Result<(), EngineError> {
+        let bytes = encode_account(&account);
+        self.store
+            .shared
+            .borrow_mut()
+            .write(|db| db.write_account(client, Some(bytes)))
+            .map(|_| ())
+    }
+
+    // In client order
+    fn for_each(&self, f: &mut dyn FnMut(u16, AccountRecord)) -> Result<(), EngineError> {
+        let shared = self.store.shared.borrow();
+        match &shared.backend.batch {
+            Some(batch) => visit_accounts(&batch.open_table(ACCOUNTS).map_err(storage_error)?, f),
+            None => visit_accounts(
+                &shared
+                    .backend
+                    .db
+                    .begin_read()
+                    .map_err(storage_error)?
+                    .open_table(ACCOUNTS)
+                    .map_err(storage_error)?,
+                f,
+            ),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        self.store.flush()
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().begin_row()
+    }
+
+    fn end_row(&mut self, applied: bool) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().end_row(applied)
+    }
+}
+
+// The transactions table as a TransactionStore
+#[derive(Debug, Clone)]
+pub struct DiskTxStore {
+    store: DiskStore,
+}
+
+impl DiskTxStore {
+    // Opens the store at `path` for its transactions alone, creating it if it
+    // doesn't exist yet. DiskStore::open() gets at the accounts as well
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        Ok(DiskStore::open(path)?.transactions())
+    }
+}
+
+impl TransactionStore for DiskTxStore {
+    // Reads go through the open batch when there is one, so they see its writes
+    fn get(&self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        let shared = self.store.shared.borrow();
+        let bytes = match &shared.backend.batch {
+            Some(batch) => batch
+                .open_table(TRANSACTIONS)
+                .map_err(storage_error)?
+                .get(tx)
+                .map_err(storage_error)?
+                .map(|value| value.value()),
+            None => shared
+                .backend
+                .db
+                .begin_read()
+                .map_err(storage_error)?
+                .open_table(TRANSACTIONS)
+                .map_err(storage_error)?
+                .get(tx)
+                .map_err(storage_error)?
+                .map(|value| value.value()),
+        };
+        bytes.map(|bytes| decode(tx, bytes)).transpose()
+    }
+
+    fn insert(&mut self, row: &TransactionRow) -> Result<(), EngineError> {
+        let bytes = encode(&StoredTx::of(row)?);
+        self.store
+            .shared
+            .borrow_mut()
+            .write(|db| db.write_transaction(row.tx, Some(bytes)))
+            .map(|_| ())
+    }
+
+    fn set_state(&mut self, tx: u32, state: TxState) -> Result<(), EngineError> {
+        let mut stored = self.get(tx)?.ok_or(EngineError::UnknownTx { tx })?;
+        stored.state = state;
+        let bytes = encode(&stored);
+        self.store
+            .shared
+            .borrow_mut()
+            .write(|db| db.write_transaction(tx, Some(bytes)))
+            .map(|_| ())
+    }
+
+    fn remove(&mut self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        let bytes = self
+            .store
+            .shared
+            .borrow_mut()
+            .write(|db| db.write_transaction(tx, None))?;
+        bytes.map(|bytes| decode(tx, bytes)).transpose()
+    }
+
+    fn len(&self) -> usize {
+        self.store.shared.borrow().backend.transactions
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(u32, StoredTx)) -> Result<(), EngineError> {
+        let shared = self.store.shared.borrow();
+        match &shared.backend.batch {
+            Some(batch) => visit(&batch.open_table(TRANSACTIONS).map_err(storage_error)?, f),
+            None => visit(
+                &shared
+                    .backend
+                    .db
+                    .begin_read()
+                    .map_err(storage_error)?
+                    .open_table(TRANSACTIONS)
+                    .map_err(storage_error)?,
+                f,
+            ),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        self.store.flush()
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().begin_row()
+    }
+
+    fn end_row(&mut self, applied: bool) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().end_row(applied)
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+use crate::account_store::AccountStore;
+use crate::config::{EngineConfig, TxIdPolicy};
+use crate::error::EngineError;
+use crate::handlers::*;
+use crate::lifecycle::TxState;
+use crate::model::{AccountRecord, TransactionRow, TransactionType};
+use crate::store::{StoredTx, TransactionStore, TxStore};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutcomeKind {
+    Applied,
+    // Applied, but the tx ID had already been used (TxIdPolicy::Warn)
+    AppliedReusedId,
+    // Identical to an earlier row, so nothing was applied (TxIdPolicy::IdempotentReplay)
+    Replayed,
+}
+
+// What a successful apply() did, with the account as it stood before and as
+// it stands afterwards
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub kind: OutcomeKind,
+    pub tx_type: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    pub before: AccountRecord,
+    pub account: AccountRecord,
+}
+
+// Owns all Account & Transaction state, so callers only ever hand it rows
+#[derive(Debug)]
+pub struct Engine {
+    pub(crate) config: EngineConfig,
+    pub(crate) accounts: Box<dyn AccountStore>,
+    pub(crate) transactions: Box<dyn TransactionStore>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::with_config(EngineConfig::default())
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine::default()
+    }
+
+    pub fn with_config(config: EngineConfig) -> Self {
+        Engine::with_transaction_store(config, TxStore::default())
+    }
+
+    // e.g. a DiskTxStore, when the transaction history won't fit in memory
+    pub fn with_transaction_store(
+        config: EngineConfig,
+        transactions: impl TransactionStore + 'static,
+    ) -> Self {
+        Engine::with_stores(config, HashMap::new(), transactions)
+    }
+
+    // Both stores at once, e.g. when accounts live somewhere other than a HashMap
+    pub fn with_stores(
+        config: EngineConfig,
+        accounts: impl AccountStore + 'static,
+        transactions: impl TransactionStore + 'static,
+    ) -> Self {
+        Engine {
+            config,
+            accounts: Box::new(accounts),
+            transactions: Box::new(transactions),
+        }
+    }
+
+    pub fn apply(&mut self, transaction: TransactionRow) -> Result<Outcome, EngineError> {
+        // A store that batches writes never commits part of a row, and takes
+        // back whatever a failed row wrote
+        let result = self
+            .accounts
+            .begin_row()
+            .and_then(|()| self.transactions.begin_row())
+            .and_then(|()| self.apply_row(transaction));
+        let applied = result.is_ok();
+        let ended = self.accounts.end_row(applied);
+        ended.and(self.transactions.end_row(applied)).and(result)
+    }
+
+    fn apply_row(&mut self, transaction: TransactionRow) -> Result<Outcome, EngineError> {
+        let (tx_type, client, tx) = (transaction.tx_type, transaction.client, transaction.tx);
+
+        // Deposits and withdrawals share one tx ID space, and every ID one of
+        // them used is in the store, see apply_withdrawal()
+        let claims_id = matches!(
+            tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        let mut kind = OutcomeKind::Applied;
+        let mut claimed = None;
+        if claims_id {
+            claimed = self.transactions.get(tx)?;
+            if let Some(stored) = &claimed {
+                match (is_replay(stored, &transaction), self.config.tx_id_policy) {
+                    (true, TxIdPolicy::IdempotentReplay) => kind = OutcomeKind::Replayed,
+                    (_, TxIdPolicy::Warn) => kind = OutcomeKind::AppliedReusedId,
+                    (_, _) => return Err(EngineError::DuplicateTx { tx }),
+                }
+            }
+        }
+
+        let before = self.accounts.get(client)?.unwrap_or_default();
+        if kind == OutcomeKind::Replayed {
+            return self.outcome(kind, tx_type, client, tx, before);
+        }
+
+        if !claims_id {
+            self.check_disputable(tx)?;
+        }
+
+        // Check the type of operation this single transaction is
+        let result = match tx_type {
+            TransactionType::Deposit => self.apply_deposit(transaction, claimed),
+            TransactionType::Withdrawal => self.apply_withdrawal(transaction, claimed),
+            TransactionType::Dispute => handle_dispute(
+                &transaction,
+                self.accounts.as_mut(),
+                self.transactions.as_mut(),
+                self.config.redispute,
+            ),
+            TransactionType::Resolve => handle_resolve(
+                &transaction,
+                self.accounts.as_mut(),
+                self.transactions.as_mut(),
+            ),
+            TransactionType::Chargeback => handle_chargeback(
+                &transaction,
+                self.accounts.as_mut(),
+                self.transactions.as_mut(),
+            ),
+        };
+        result?;
+
+        self.outcome(kind, tx_type, client, tx, before)
+    }
+
+    fn outcome(
+        &self,
+        kind: OutcomeKind,
+        tx_type: TransactionType,
+        client: u16,
+        tx: u32,
+        before: AccountRecord,
+    ) -> Result<Outcome, EngineError> {
+        Ok(Outcome {
+            kind,
+            tx_type,
+            client,
+            tx,
+            before,
+            // Every handler that succeeds has an account for the client
+            account: self.accounts.get(client)?.unwrap_or_default(),
+        })
+    }
+
+    // A withdrawal that can't be disputed is only stored to hold its tx ID.
+    // Anything else is kept from it as if it weren't there at all
+    fn disputable(&self, stored: &StoredTx) -> bool {
+        stored.tx_type != TransactionType::Withdrawal || self.config.dispute_withdrawals
+    }
+
+    fn check_disputable(&self, tx: u32) -> Result<(), EngineError> {
+        match self.transactions.get(tx)? {
+            Some(stored) if !self.disputable(&stored) => Err(EngineError::UnknownTx { tx }),
+            _ => Ok(()),
+        }
+    }
+
+    // `claimed` is what was stored under the row's tx ID beforehand
+    fn apply_deposit(
+        &mut self,
+        transaction: TransactionRow,
+        claimed: Option<StoredTx>,
+    ) -> Result<(), EngineError> {
+        let tx = transaction.tx;
+        // Reusing the ID of a withdrawal that only holds it (TxIdPolicy::Warn)
+        // stores the deposit in its place, unless the deposit fails
+        let holder = claimed.filter(|stored| !self.disputable(stored));
+        if holder.is_some() {
+            self.transactions.remove(tx)?;
+        }
+        let result = handle_deposit(
+            transaction,
+            self.accounts.as_mut(),
+            self.transactions.as_mut(),
+        );
+        if let (Err(_), Some(holder)) = (&result, holder) {
+            self.transactions.insert(&holder.row(tx))?;
+        }
+        result
+    }
+
+    fn apply_withdrawal(
+        &mut self,
+        transaction: TransactionRow,
+        claimed: Option<StoredTx>,
+    ) -> Result<(), EngineError> {
+        // A disputable withdrawal must not clobber a stored transaction
+        if claimed.is_some() && self.config.dispute_withdrawals {
+            return Err(EngineError::DuplicateTx { tx: transaction.tx });
+        }
+        handle_withdrawal(&transaction, self.accounts.as_mut())?;
+
+        // Stored even when it can't be disputed, since the store is what
+        // remembers which tx IDs are taken. The first row to use an ID keeps it
+        if claimed.is_none() {
+            self.transactions.insert(&transaction)?;
+        }
+
+        Ok(())
+    }
+
+    // Removes whatever is stored under `tx`, for put_tx() on another Engine
+    pub(crate) fn take_tx(&mut self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        self.transactions.remove(tx)
+    }
+
+    pub(crate) fn put_tx(&mut self, tx: u32, stored: Option<StoredTx>) -> Result<(), EngineError> {
+        match stored {
+            Some(stored) => self.transactions.insert(&stored.row(tx)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn account(&self, client: u16) -> Result<Option<AccountRecord>, EngineError> {
+        self.accounts.get(client)
+    }
+
+    // Deposits, and withdrawals when they can be disputed
+    pub fn transaction(&self, tx: u32) -> Result<Option<TransactionRow>, EngineError> {
+        Ok(self
+            .transactions
+            .get(tx)?
+            .filter(|stored| self.disputable(stored))
+            .map(|stored| stored.row(tx)))
+    }
+
+    // Makes every account and stored transaction durable, for stores that batch writes
+    pub fn flush(&mut self) -> Result<(), EngineError> {
+        self.accounts.flush()?;
+        self.transactions.flush()
+    }
+
+    // How many stored transactions each client has under dispute. Clients
+    // with none are left out
+    pub fn open_disputes(&self) -> Result<HashMap<u16, u32>, EngineError> {
+        let mut open = HashMap::new();
+        self.transactions.for_each(&mut |_, stored| {
+            if stored.state() == TxState::Disputed {
+                *open.entry(stored.client()).or_insert(0) += 1;
+            }
+        })?;
+        Ok(open)
+    }
+
+    // In no particular order, whichever store holds them
+    pub fn accounts(&self) -> Result<Vec<(u16, AccountRecord)>, EngineError> {
+        let mut accounts = Vec::new();
+        self.accounts
+            .for_each(&mut |client, account| accounts.push((client, account)))?;
+        Ok(accounts)
+    }
+}
+
+// The same row as the one stored under its tx ID. Amounts are compared
+// serialized, which keeps the scale, so "1.0" and "1.00" are different rows
+fn is_replay(stored: &StoredTx, transaction: &TransactionRow) -> bool {
+    stored.tx_type == transaction.tx_type
+        && stored.client == transaction.client
+        && transaction.amount.map(|amount| amount.serialize()) == Some(stored.amount.serialize())
+}
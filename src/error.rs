@@ -0,0 +1,137 @@
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::model::TransactionType;
+
+// Every reason the engine can refuse a transaction. The code() strings are
+// stable and are what log alerting and the rejection report key off of
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError {
+    DuplicateTx {
+        tx: u32,
+    },
+    InvalidAmount {
+        tx: u32,
+        tx_type: TransactionType,
+    },
+    UnknownAccount {
+        client: u16,
+    },
+    AccountLocked {
+        client: u16,
+    },
+    InsufficientFunds {
+        requested: Decimal,
+        available: Decimal,
+    },
+    UnknownTx {
+        tx: u32,
+    },
+    ClientMismatch {
+        tx: u32,
+        client: u16,
+        owner: u16,
+    },
+    AlreadyDisputed {
+        tx: u32,
+    },
+    NotDisputed {
+        tx: u32,
+    },
+    MissingAmount {
+        tx: u32,
+    },
+    BalanceOverflow {
+        tx: u32,
+        client: u16,
+    },
+    RedisputeNotAllowed {
+        tx: u32,
+    },
+    ChargedBack {
+        tx: u32,
+    },
+    // The transaction store couldn't be read or written. Unlike the others
+    // this says nothing about the row, callers should stop rather than skip it
+    Storage {
+        message: String,
+    },
+}
+
+impl EngineError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::DuplicateTx { .. } => "duplicate_tx",
+            EngineError::InvalidAmount { .. } => "invalid_amount",
+            EngineError::UnknownAccount { .. } => "unknown_account",
+            EngineError::AccountLocked { .. } => "account_locked",
+            EngineError::InsufficientFunds { .. } => "insufficient_funds",
+            EngineError::UnknownTx { .. } => "unknown_tx",
+            EngineError::ClientMismatch { .. } => "client_mismatch",
+            EngineError::AlreadyDisputed { .. } => "already_disputed",
+            EngineError::NotDisputed { .. } => "not_disputed",
+            EngineError::MissingAmount { .. } => "missing_amount",
+            EngineError::BalanceOverflow { .. } => "balance_overflow",
+            EngineError::RedisputeNotAllowed { .. } => "redispute_not_allowed",
+            EngineError::ChargedBack { .. } => "charged_back",
+            EngineError::Storage { .. } => "storage_error",
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::DuplicateTx { tx } => write!(f, "Duplicate transaction ID: {}", tx),
+            EngineError::InvalidAmount { tx, tx_type } => write!(
+                f,
+                "{:?} transaction:{} must have a valid amount up to four decimals",
+                tx_type, tx
+            ),
+            EngineError::UnknownAccount { client } => {
+                write!(f, "Account: {} does not exist", client)
+            }
+            EngineError::AccountLocked { client } => write!(f, "Account: {} is locked", client),
+            EngineError::InsufficientFunds {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Insufficient funds: tried to withdraw {} from available {}",
+                requested, available
+            ),
+            EngineError::UnknownTx { tx } => {
+                write!(f, "Reference to non-existent transaction: {}", tx)
+            }
+            EngineError::ClientMismatch { tx, client, owner } => write!(
+                f,
+                "Client: {} cannot reference transaction: {} belonging to client: {}",
+                client, tx, owner
+            ),
+            EngineError::AlreadyDisputed { tx } => {
+                write!(f, "Transaction: {} is already under dispute", tx)
+            }
+            EngineError::NotDisputed { tx } => {
+                write!(f, "Transaction: {} is not under dispute", tx)
+            }
+            EngineError::MissingAmount { tx } => write!(f, "Transaction: {} has no amount", tx),
+            EngineError::BalanceOverflow { tx, client } => write!(
+                f,
+                "Transaction: {} would overflow the balance of account: {}",
+                tx, client
+            ),
+            EngineError::RedisputeNotAllowed { tx } => write!(
+                f,
+                "Transaction: {} was resolved and cannot be disputed again",
+                tx
+            ),
+            EngineError::ChargedBack { tx } => {
+                write!(f, "Transaction: {} has been charged back", tx)
+            }
+            EngineError::Storage { message } => write!(f, "Storage error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
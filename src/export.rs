@@ -0,0 +1,285 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::builder::{
+    BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder, UInt32Builder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use rust_decimal::Decimal;
+
+use crate::engine::{Engine, Outcome, OutcomeKind};
+use crate::error::EngineError;
+use crate::lifecycle::TxState;
+use crate::model::{TransactionRow, TransactionType};
+use crate::report::{account_report, RejectionRecord, SortBy};
+
+// Amounts are exported as decimal128 with this scale, the precision the
+// engine accepts, and the widest precision decimal128 has
+pub const EXPORT_SCALE: i8 = 4;
+const EXPORT_PRECISION: u8 = 38;
+
+// History rows buffered before they are written out as a record batch
+const BATCH_ROWS: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ExportError {
+    Parquet(ParquetError),
+    Arrow(ArrowError),
+    // The Engine's accounts couldn't be read
+    Store(EngineError),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Parquet(err) => write!(f, "Parquet export failed: {}", err),
+            ExportError::Arrow(err) => write!(f, "Arrow export failed: {}", err),
+            ExportError::Store(err) => write!(f, "Export couldn't read the engine: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<ParquetError> for ExportError {
+    fn from(err: ParquetError) -> Self {
+        ExportError::Parquet(err)
+    }
+}
+
+impl From<ArrowError> for ExportError {
+    fn from(err: ArrowError) -> Self {
+        ExportError::Arrow(err)
+    }
+}
+
+impl From<EngineError> for ExportError {
+    fn from(err: EngineError) -> Self {
+        ExportError::Store(err)
+    }
+}
+
+fn amount_field(name: &str, nullable: bool) -> Field {
+    Field::new(
+        name,
+        DataType::Decimal128(EXPORT_PRECISION, EXPORT_SCALE),
+        nullable,
+    )
+}
+
+// The amount's mantissa at EXPORT_SCALE. None when it has more decimal places
+// than that, which only a rejected row can have
+fn scaled(amount: Decimal) -> Option<i128> {
+    let scale = amount.scale();
+    (scale <= EXPORT_SCALE as u32)
+        .then(|| amount.mantissa() * 10i128.pow(EXPORT_SCALE as u32 - scale))
+}
+
+fn decimal_builder(capacity: usize) -> Decimal128Builder {
+    Decimal128Builder::with_capacity(capacity)
+        .with_precision_and_scale(EXPORT_PRECISION, EXPORT_SCALE)
+        .expect("valid decimal128 precision and scale")
+}
+
+pub fn accounts_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        amount_field("available", false),
+        amount_field("held", false),
+        amount_field("total", false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+}
+
+// The final account table, in client order
+pub fn write_accounts_parquet<W: Write + Send>(engine: &Engine, out: W) -> Result<(), ExportError> {
+    let records = account_report(engine, SortBy::Client)?;
+    let mut client = UInt16Builder::with_capacity(records.len());
+    let mut available = decimal_builder(records.len());
+    let mut held = decimal_builder(records.len());
+    let mut total = decimal_builder(records.len());
+    let mut locked = BooleanBuilder::with_capacity(records.len());
+    for record in &records {
+        client.append_value(record.client);
+        available.append_option(scaled(record.available));
+        held.append_option(scaled(record.held));
+        total.append_option(scaled(record.total));
+        locked.append_value(record.locked);
+    }
+
+    let schema = accounts_schema();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(client.finish()),
+        Arc::new(available.finish()),
+        Arc::new(held.finish()),
+        Arc::new(total.finish()),
+        Arc::new(locked.finish()),
+    ];
+    let mut writer = ArrowWriter::try_new(out, schema.clone(), None)?;
+    writer.write(&RecordBatch::try_new(schema, columns)?)?;
+    writer.close()?;
+    Ok(())
+}
+
+// One processed row of the transaction log: where it came from, what it
+// was, whether it was accepted (and why not), and the state its tx ID was
+// left in. Rows that didn't parse have only their source, line and reason
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryRecord {
+    pub source: String,
+    pub line: u64,
+    pub tx_type: Option<TransactionType>,
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+    pub amount: Option<Decimal>,
+    pub result: &'static str,
+    pub reason: Option<&'static str>,
+    pub state: Option<TxState>,
+}
+
+impl HistoryRecord {
+    pub fn applied(
+        source: &str,
+        line: u64,
+        transaction: &TransactionRow,
+        result: &Result<Outcome, EngineError>,
+    ) -> Self {
+        let (result, reason, state) = match result {
+            Ok(outcome) if outcome.kind == OutcomeKind::Replayed => {
+                ("accepted", Some("replayed"), None)
+            }
+            Ok(outcome) => {
+                let state = match outcome.tx_type {
+                    TransactionType::Deposit | TransactionType::Withdrawal => TxState::Processed,
+                    TransactionType::Dispute => TxState::Disputed,
+                    TransactionType::Resolve => TxState::Resolved,
+                    TransactionType::Chargeback => TxState::ChargedBack,
+                };
+                ("accepted", None, Some(state))
+            }
+            Err(e) => ("rejected", Some(e.code()), None),
+        };
+        HistoryRecord {
+            source: source.to_string(),
+            line,
+            tx_type: Some(transaction.tx_type),
+            client: Some(transaction.client),
+            tx: Some(transaction.tx),
+            amount: transaction.amount,
+            result,
+            reason,
+            state,
+        }
+    }
+
+    pub fn unparsed(rejection: &RejectionRecord) -> Self {
+        HistoryRecord {
+            source: rejection.source.clone(),
+            line: rejection.line,
+            tx_type: None,
+            client: None,
+            tx: None,
+            amount: None,
+            result: "rejected",
+            reason: Some(rejection.reason),
+            state: None,
+        }
+    }
+}
+
+pub fn history_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("seq", DataType::UInt64, false),
+        Field::new("source", DataType::Utf8, false),
+        Field::new("line", DataType::UInt64, false),
+        Field::new("type", DataType::Utf8, true),
+        Field::new("client", DataType::UInt16, true),
+        Field::new("tx", DataType::UInt32, true),
+        amount_field("amount", true),
+        Field::new("result", DataType::Utf8, false),
+        Field::new("reason", DataType::Utf8, true),
+        Field::new("state", DataType::Utf8, true),
+    ]))
+}
+
+// Streams HistoryRecords to a Parquet file, numbering them in the order they
+// are pushed. Nothing is readable until finish()
+pub struct HistoryWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    pending: Vec<HistoryRecord>,
+    seq: u64,
+}
+
+impl<W: Write + Send> HistoryWriter<W> {
+    pub fn new(out: W) -> Result<Self, ExportError> {
+        Ok(HistoryWriter {
+            writer: ArrowWriter::try_new(out, history_schema(), None)?,
+            pending: Vec::new(),
+            seq: 0,
+        })
+    }
+
+    pub fn push(&mut self, record: HistoryRecord) -> Result<(), ExportError> {
+        self.pending.push(record);
+        if self.pending.len() >= BATCH_ROWS {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), ExportError> {
+        self.write_pending()?;
+        self.writer.close()?;
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> Result<(), ExportError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let rows = self.pending.len();
+        let mut seq = UInt64Builder::with_capacity(rows);
+        let mut source = StringBuilder::new();
+        let mut line = UInt64Builder::with_capacity(rows);
+        let mut tx_type = StringBuilder::new();
+        let mut client = UInt16Builder::with_capacity(rows);
+        let mut tx = UInt32Builder::with_capacity(rows);
+        let mut amount = decimal_builder(rows);
+        let mut result = StringBuilder::new();
+        let mut reason = StringBuilder::new();
+        let mut state = StringBuilder::new();
+        for record in self.pending.drain(..) {
+            self.seq += 1;
+            seq.append_value(self.seq);
+            source.append_value(&record.source);
+            line.append_value(record.line);
+            tx_type.append_option(record.tx_type.map(|t| t.as_str()));
+            client.append_option(record.client);
+            tx.append_option(record.tx);
+            amount.append_option(record.amount.and_then(scaled));
+            result.append_value(record.result);
+            reason.append_option(record.reason);
+            state.append_option(record.state.map(|s| s.as_str()));
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(seq.finish()),
+            Arc::new(source.finish()),
+            Arc::new(line.finish()),
+            Arc::new(tx_type.finish()),
+            Arc::new(client.finish()),
+            Arc::new(tx.finish()),
+            Arc::new(amount.finish()),
+            Arc::new(result.finish()),
+            Arc::new(reason.finish()),
+            Arc::new(state.finish()),
+        ];
+        self.writer
+            .write(&RecordBatch::try_new(history_schema(), columns)?)?;
+        Ok(())
+    }
+}
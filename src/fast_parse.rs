@@ -0,0 +1,150 @@
+use std::fmt;
+use std::str::FromStr;
+
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+
+use crate::model::{TransactionRow, TransactionType};
+
+// Most digits a plain amount may have to fit in a Decimal without rounding
+const MAX_DIGITS: usize = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowParseError {
+    // Some field is not UTF-8, which the serde path refuses for the whole row
+    Utf8,
+    Missing(&'static str),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for RowParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowParseError::Utf8 => write!(f, "row is not valid UTF-8"),
+            RowParseError::Missing(field) => write!(f, "missing field `{}`", field),
+            RowParseError::Invalid(field) => write!(f, "invalid value for field `{}`", field),
+        }
+    }
+}
+
+impl std::error::Error for RowParseError {}
+
+// Reads TransactionRows straight out of a reused ByteRecord, for inputs too
+// big to go through serde row by row. Fields are trimmed and parsed in place
+// rather than copied into a trimmed record first. Accepts and refuses exactly
+// the rows `deserialize::<TransactionRow>()` does on a trimmed record
+#[derive(Debug, Clone)]
+pub struct RowParser {
+    tx_type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+}
+
+impl RowParser {
+    // Columns are found by name, in whatever order the header row has them
+    pub fn new(headers: &ByteRecord) -> Self {
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| field_str(header).map(str::trim) == Ok(name))
+        };
+        RowParser {
+            tx_type: column("type"),
+            client: column("client"),
+            tx: column("tx"),
+            amount: column("amount"),
+        }
+    }
+
+    // The row's type, client, tx and amount as written, trimmed, for a
+    // rejection. A missing or non UTF-8 field is empty
+    pub fn fields<'r>(&self, record: &'r ByteRecord) -> [&'r str; 4] {
+        [self.tx_type, self.client, self.tx, self.amount].map(|index| {
+            index
+                .and_then(|index| record.get(index))
+                .and_then(|field| field_str(field).ok())
+                .map_or("", str::trim)
+        })
+    }
+
+    pub fn parse(&self, record: &ByteRecord) -> Result<TransactionRow, RowParseError> {
+        if record.iter().any(|field| field_str(field).is_err()) {
+            return Err(RowParseError::Utf8);
+        }
+        let field = |index: Option<usize>, name: &'static str| {
+            index
+                .and_then(|index| record.get(index))
+                .and_then(|field| field_str(field).ok())
+                .map(str::trim)
+                .ok_or(RowParseError::Missing(name))
+        };
+
+        let tx_type = match field(self.tx_type, "type")? {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            _ => return Err(RowParseError::Invalid("type")),
+        };
+        let client = parse_int(field(self.client, "client")?, u16::from_str_radix)
+            .ok_or(RowParseError::Invalid("client"))?;
+        let tx = parse_int(field(self.tx, "tx")?, u32::from_str_radix)
+            .ok_or(RowParseError::Invalid("tx"))?;
+        let amount = match field(self.amount, "amount") {
+            Ok("") | Err(_) => None,
+            Ok(amount) => Some(parse_amount(amount).ok_or(RowParseError::Invalid("amount"))?),
+        };
+
+        Ok(TransactionRow::new(tx_type, client, tx, amount))
+    }
+}
+
+fn field_str(field: &[u8]) -> Result<&str, std::str::Utf8Error> {
+    std::str::from_utf8(field)
+}
+
+// As the csv deserializer reads integers, "0x" prefixed ones in hex
+fn parse_int<T: FromStr>(
+    text: &str,
+    from_str_radix: fn(&str, u32) -> Result<T, std::num::ParseIntError>,
+) -> Option<T> {
+    match text.strip_prefix("0x") {
+        Some(digits) => from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Plain amounts ("12", "0.5000") are built digit by digit. Anything else
+// (signs, exponents, separators, too many digits) goes the way serde would
+// send it, so both paths always agree
+pub fn parse_amount(text: &str) -> Option<Decimal> {
+    parse_plain(text.as_bytes()).or_else(|| {
+        Decimal::from_str(text)
+            .or_else(|_| Decimal::from_scientific(text))
+            .ok()
+    })
+}
+
+fn parse_plain(bytes: &[u8]) -> Option<Decimal> {
+    let (integer, fraction) = match bytes.iter().position(|b| *b == b'.') {
+        Some(dot) => (&bytes[..dot], &bytes[dot + 1..]),
+        None => (bytes, &[][..]),
+    };
+    if integer.is_empty()
+        || (fraction.is_empty() && integer.len() < bytes.len())
+        || integer.len() + fraction.len() > MAX_DIGITS
+    {
+        return None;
+    }
+
+    let mut mantissa: i128 = 0;
+    for byte in integer.iter().chain(fraction) {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        mantissa = mantissa * 10 + (byte - b'0') as i128;
+    }
+    Decimal::try_from_i128_with_scale(mantissa, fraction.len() as u32).ok()
+}
@@ -0,0 +1,255 @@
+use rust_decimal::Decimal;
+
+use crate::account_store::AccountStore;
+use crate::config::RedisputePolicy;
+use crate::error::EngineError;
+use crate::model::{AccountRecord, TransactionRow, TransactionType};
+use crate::store::TransactionStore;
+
+// Decimal::checked_add only fails once the integer part no longer fits. Close to
+// the limit it quietly drops decimal places instead (MAX + 0.0001 == MAX), which
+// would lose money just the same, so a sum that doesn't round trip also fails
+fn exact_add(a: Decimal, b: Decimal) -> Option<Decimal> {
+    let sum = a.checked_add(b)?;
+    (sum.checked_sub(a)? == b).then_some(sum)
+}
+
+// All balance changes go through here. The new balances (and their total, which
+// is what gets reported) are computed with checked arithmetic before anything
+// is written, so an overflow rejects the transaction and leaves the account as is
+fn adjust_balances(
+    account: &mut AccountRecord,
+    transaction: &TransactionRow,
+    available_delta: Decimal,
+    held_delta: Decimal,
+) -> Result<(), EngineError> {
+    let overflow = || EngineError::BalanceOverflow {
+        tx: transaction.tx,
+        client: transaction.client,
+    };
+
+    // Amounts are parsed from their text, scale and all, but go into balances
+    // without trailing zeros, as when they were parsed through f64: "2.0" is
+    // reported as 2, while a sum keeps the scale its arithmetic gives it. A
+    // balance that doesn't change isn't touched, since 0.0 + 0 would be 0
+    let change = |balance: Decimal, delta: Decimal| {
+        if delta.is_zero() {
+            return Some(balance);
+        }
+        exact_add(balance, delta.normalize())
+    };
+    let available = change(account.available, available_delta).ok_or_else(overflow)?;
+    let held = change(account.held, held_delta).ok_or_else(overflow)?;
+    exact_add(available, held).ok_or_else(overflow)?;
+
+    account.available = available;
+    account.held = held;
+
+    Ok(())
+}
+
+pub(crate) fn handle_deposit(
+    transaction: TransactionRow,
+    accounts: &mut dyn AccountStore,
+    transactions: &mut dyn TransactionStore,
+) -> Result<(), EngineError> {
+    let amount = transaction
+        .amount
+        .filter(|a| a.scale() <= 4) // Reject > 4 decimal places
+        .filter(|a| *a > Decimal::ZERO) // Don't allow zero deposit
+        .ok_or(EngineError::InvalidAmount {
+            tx: transaction.tx,
+            tx_type: transaction.tx_type,
+        })?;
+
+    if transactions.contains(transaction.tx)? {
+        return Err(EngineError::DuplicateTx { tx: transaction.tx });
+    }
+
+    // Only create the account when there is a valid amount
+    // Only persist the account when there is a valid amount
+    let account = accounts.get(transaction.client)?.unwrap_or_default();
+
+    // This isn't explicit in the Specification, but was uncovered during testing
+    // If the account is locked, we cannot deposit to (or withdraw from) it
+    if account.locked {
+        return Err(EngineError::AccountLocked {
+            client: transaction.client,
+        });
+    }
+
+    // Balances are worked out on a copy and the store written before the
+    // account, so a failure at either step leaves the account as it was.
+    // Engine::apply() has a batching store undo the stored tx as well
+    let mut updated = account;
+    adjust_balances(&mut updated, &transaction, amount, Decimal::ZERO)?;
+    transactions.insert(&transaction)?;
+    accounts.upsert(transaction.client, updated)?;
+
+    Ok(())
+}
+
+pub(crate) fn handle_withdrawal(
+    transaction: &TransactionRow,
+    accounts: &mut dyn AccountStore,
+) -> Result<(), EngineError> {
+    let amount = transaction
+        .amount
+        .filter(|a| a.scale() <= 4) // Reject > than 4 decimal places
+        .filter(|a| *a > Decimal::ZERO) // Don't allow zero withdrawal
+        .ok_or(EngineError::InvalidAmount {
+            tx: transaction.tx,
+            tx_type: transaction.tx_type,
+        })?;
+
+    accounts.update(transaction.client, &mut |account| {
+        // Apply the same logic in Deposit for a locked account
+        if account.locked {
+            return Err(EngineError::AccountLocked {
+                client: transaction.client,
+            });
+        }
+
+        if account.available < amount {
+            return Err(EngineError::InsufficientFunds {
+                requested: amount,
+                available: account.available,
+            });
+        }
+
+        adjust_balances(account, transaction, -amount, Decimal::ZERO)
+    })
+}
+
+pub(crate) fn handle_dispute(
+    transaction: &TransactionRow,
+    accounts: &mut dyn AccountStore,
+    transactions: &mut dyn TransactionStore,
+    redispute: RedisputePolicy,
+) -> Result<(), EngineError> {
+    let disputed_tx = transactions
+        .get(transaction.tx)?
+        .ok_or(EngineError::UnknownTx { tx: transaction.tx })?;
+
+    // Found while testing, cannot dispute the same transaction > 1 time
+    if disputed_tx.client != transaction.client {
+        return Err(EngineError::ClientMismatch {
+            tx: transaction.tx,
+            client: transaction.client,
+            owner: disputed_tx.client,
+        });
+    }
+
+    let next_state = disputed_tx.state.dispute(transaction.tx, redispute)?;
+
+    let amount = disputed_tx.amount;
+
+    let account = accounts
+        .get(transaction.client)?
+        .ok_or(EngineError::UnknownAccount {
+            client: transaction.client,
+        })?;
+
+    let mut updated = account;
+    if disputed_tx.tx_type == TransactionType::Withdrawal {
+        // The withdrawn funds are provisionally credited back, but held until
+        // the dispute is settled. Available is untouched, it was already debited
+        adjust_balances(&mut updated, transaction, Decimal::ZERO, amount)?;
+    } else {
+        // Per Specification, "clients available funds should decrease by amount disputed"
+        // Per Specification, "held funds should increase by the amount disputed"
+        adjust_balances(&mut updated, transaction, -amount, amount)?;
+    }
+    transactions.set_state(transaction.tx, next_state)?;
+    accounts.upsert(transaction.client, updated)?;
+
+    Ok(())
+}
+
+pub(crate) fn handle_resolve(
+    transaction: &TransactionRow,
+    accounts: &mut dyn AccountStore,
+    transactions: &mut dyn TransactionStore,
+) -> Result<(), EngineError> {
+    let resolved_tx = transactions
+        .get(transaction.tx)?
+        .ok_or(EngineError::UnknownTx { tx: transaction.tx })?;
+
+    // Verify transaction belongs to this client
+    if resolved_tx.client != transaction.client {
+        return Err(EngineError::ClientMismatch {
+            tx: transaction.tx,
+            client: transaction.client,
+            owner: resolved_tx.client,
+        });
+    }
+
+    // Check if transaction is under dispute
+    let next_state = resolved_tx.state.resolve(transaction.tx)?;
+
+    let amount = resolved_tx.amount;
+
+    let account = accounts
+        .get(transaction.client)?
+        .ok_or(EngineError::UnknownAccount {
+            client: transaction.client,
+        })?;
+
+    // Resolving a withdrawal dispute means the withdrawal stands, so the
+    // provisional credit is dropped rather than released to available
+    let mut updated = account;
+    if resolved_tx.tx_type == TransactionType::Withdrawal {
+        adjust_balances(&mut updated, transaction, Decimal::ZERO, -amount)?;
+    } else {
+        adjust_balances(&mut updated, transaction, amount, -amount)?;
+    }
+    transactions.set_state(transaction.tx, next_state)?;
+    accounts.upsert(transaction.client, updated)?;
+
+    Ok(())
+}
+
+pub(crate) fn handle_chargeback(
+    transaction: &TransactionRow,
+    accounts: &mut dyn AccountStore,
+    transactions: &mut dyn TransactionStore,
+) -> Result<(), EngineError> {
+    let chargeback_tx = transactions
+        .get(transaction.tx)?
+        .ok_or(EngineError::UnknownTx { tx: transaction.tx })?;
+
+    // Verify chargeback request belongs to this client
+    if chargeback_tx.client != transaction.client {
+        return Err(EngineError::ClientMismatch {
+            tx: transaction.tx,
+            client: transaction.client,
+            owner: chargeback_tx.client,
+        });
+    }
+
+    // Account must be under 'dispute' to initiate a chargeback
+    let next_state = chargeback_tx.state.chargeback(transaction.tx)?;
+
+    let amount = chargeback_tx.amount;
+
+    let account = accounts
+        .get(transaction.client)?
+        .ok_or(EngineError::UnknownAccount {
+            client: transaction.client,
+        })?;
+
+    // Charging back a withdrawal reverses it, returning the held funds to the client
+    let mut updated = account;
+    if chargeback_tx.tx_type == TransactionType::Withdrawal {
+        adjust_balances(&mut updated, transaction, amount, -amount)?;
+    } else {
+        adjust_balances(&mut updated, transaction, Decimal::ZERO, -amount)?;
+    }
+    updated.locked = true;
+    // Found while testing, a chargeback is no longer under dispute, and
+    // unlike a resolve it can never be disputed again
+    transactions.set_state(transaction.tx, next_state)?;
+    accounts.upsert(transaction.client, updated)?;
+
+    Ok(())
+}
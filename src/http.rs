@@ -0,0 +1,271 @@
+use std::error::Error;
+
+use log2::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use take_home::{
+    account_report, Engine, OutcomeKind, OutputRecord, RejectionRecord, SortBy, TransactionRecord,
+    TransactionRow, PARSE_ERROR,
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::cli::HttpArgs;
+use crate::serve::announce;
+use crate::{json_fields, new_engine, open_csv, Results, RunSummary};
+
+const DEFAULT_PER_PAGE: usize = 100;
+const MAX_PER_PAGE: usize = 1000;
+
+// What became of one posted transaction. `reason` is the code used in the
+// --rejections report, `message` the full error
+#[derive(Debug, Serialize)]
+struct TransactionResult {
+    tx: Option<u32>,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl TransactionResult {
+    fn rejected(tx: Option<u32>, reason: &'static str, message: String) -> Self {
+        TransactionResult {
+            tx,
+            result: "rejected",
+            reason: Some(reason),
+            message: Some(message),
+        }
+    }
+}
+
+struct Reply {
+    status: u16,
+    body: Value,
+}
+
+impl Reply {
+    fn ok(body: impl Serialize) -> Result<Self, Box<dyn Error>> {
+        Ok(Reply {
+            status: 200,
+            body: serde_json::to_value(body)?,
+        })
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Reply {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+// Answers requests one at a time on this thread, against a single Engine,
+// until killed. Posted transactions go through Engine::apply like csv rows,
+// and are in the WAL before they are answered. A storage failure stops the
+// server, as it stops a batch run
+pub fn serve_http(args: HttpArgs) -> Result<RunSummary, Box<dyn Error>> {
+    let (mut engine, wal) = new_engine(&args.engine)?;
+    let mut rejections_writer = open_csv(args.rows.rejections.as_deref())?;
+    let mut ledger_writer = open_csv(args.rows.ledger.as_deref())?;
+    let mut results = Results {
+        wal,
+        rejections: rejections_writer.iter_mut().collect(),
+        ledger: ledger_writer.as_mut(),
+        history: None,
+        summary: RunSummary::default(),
+    };
+
+    let server = Server::http(&args.listen).map_err(|err| err as Box<dyn Error>)?;
+    announce(&server.server_addr().to_string())?;
+
+    for mut request in server.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+        let (reply, fatal) = match route(&mut engine, &mut results, &mut request) {
+            Ok(reply) => (reply, None),
+            Err(err) => (Reply::error(500, &err), Some(err)),
+        };
+        let response = Response::from_string(reply.body.to_string())
+            .with_status_code(reply.status)
+            .with_header(
+                Header::from_bytes("Content-Type", "application/json").expect("valid header"),
+            );
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to send a response: {}", err);
+        }
+        if let Some(err) = fatal {
+            return Err(err);
+        }
+    }
+    Ok(results.summary)
+}
+
+fn route(
+    engine: &mut Engine,
+    results: &mut Results,
+    request: &mut Request,
+) -> Result<Reply, Box<dyn Error>> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["transactions"]) => {
+            let mut body = String::new();
+            if let Err(err) = request.as_reader().read_to_string(&mut body) {
+                return Ok(Reply::error(400, err));
+            }
+            let source = match request.remote_addr() {
+                Some(peer) => format!("http:{}", peer),
+                None => "http".to_string(),
+            };
+            post_transactions(engine, results, &source, &body)
+        }
+        (Method::Get, ["accounts"]) => list_accounts(engine, query),
+        (Method::Get, ["accounts", client]) => {
+            let Ok(client) = client.parse() else {
+                return Ok(Reply::error(400, format!("invalid client `{}`", client)));
+            };
+            match engine.account(client)? {
+                Some(account) => Reply::ok(OutputRecord::new(client, &account)),
+                None => Ok(Reply::error(
+                    404,
+                    format!("no account for client {}", client),
+                )),
+            }
+        }
+        (Method::Get, ["transactions", tx]) => {
+            let Ok(tx) = tx.parse() else {
+                return Ok(Reply::error(400, format!("invalid tx `{}`", tx)));
+            };
+            match engine.transaction(tx)? {
+                Some(transaction) => Reply::ok(TransactionRecord::new(&transaction)),
+                None => Ok(Reply::error(404, format!("no stored transaction {}", tx))),
+            }
+        }
+        (_, ["accounts"] | ["accounts", _] | ["transactions"] | ["transactions", _]) => {
+            Ok(Reply::error(405, "method not allowed"))
+        }
+        _ => Ok(Reply::error(404, "not found")),
+    }
+}
+
+// One transaction object, or an array of them applied in order. A bad
+// element of an array is rejected on its own, like a bad csv row
+fn post_transactions(
+    engine: &mut Engine,
+    results: &mut Results,
+    source: &str,
+    body: &str,
+) -> Result<Reply, Box<dyn Error>> {
+    let submitted: Value = match serde_json::from_str(body) {
+        Ok(submitted) => submitted,
+        Err(err) => return Ok(Reply::error(400, err)),
+    };
+
+    let reply = match submitted {
+        Value::Array(items) => {
+            let mut applied = Vec::with_capacity(items.len());
+            for item in items {
+                applied.push(apply(engine, results, source, item)?);
+            }
+            Reply::ok(applied)?
+        }
+        item => {
+            let result = apply(engine, results, source, item)?;
+            let status = match (result.result, result.reason) {
+                ("accepted", _) => 200,
+                (_, Some(PARSE_ERROR)) => 400,
+                _ => 422,
+            };
+            Reply {
+                status,
+                body: serde_json::to_value(result)?,
+            }
+        }
+    };
+    results.commit(engine)?;
+    Ok(reply)
+}
+
+// Rows are numbered across everything posted since the server started, for
+// the log and the --rejections report
+fn apply(
+    engine: &mut Engine,
+    results: &mut Results,
+    source: &str,
+    item: Value,
+) -> Result<TransactionResult, Box<dyn Error>> {
+    results.summary.rows += 1;
+    let line = results.summary.rows;
+
+    let transaction = match TransactionRow::deserialize(&item) {
+        Ok(transaction) => transaction,
+        Err(err) => {
+            warn!(
+                "{}:{} Transaction is being skipped, error: {}",
+                source, line, err
+            );
+            let fields = json_fields(&item);
+            let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+            results.reject(RejectionRecord::unparsed(source, line, &fields))?;
+            // An ID that doesn't fit a u32 is why the row failed, not one to echo
+            let tx = item
+                .get("tx")
+                .and_then(Value::as_u64)
+                .and_then(|tx| u32::try_from(tx).ok());
+            return Ok(TransactionResult::rejected(
+                tx,
+                PARSE_ERROR,
+                err.to_string(),
+            ));
+        }
+    };
+
+    debug!("Processing Transaction Row: {:?}", transaction);
+    let result = engine.apply(transaction.clone());
+    let response = match &result {
+        Ok(outcome) => TransactionResult {
+            tx: Some(transaction.tx),
+            result: "accepted",
+            reason: (outcome.kind == OutcomeKind::Replayed).then_some("replayed"),
+            message: None,
+        },
+        Err(e) => TransactionResult::rejected(Some(transaction.tx), e.code(), e.to_string()),
+    };
+    results.record(source, line, &transaction, result)?;
+    Ok(response)
+}
+
+// GET /accounts?page=1&per_page=100, by client ID
+fn list_accounts(engine: &Engine, query: &str) -> Result<Reply, Box<dyn Error>> {
+    let mut page = 1;
+    let mut per_page = DEFAULT_PER_PAGE;
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let target = match name {
+            "page" => &mut page,
+            "per_page" => &mut per_page,
+            _ => continue,
+        };
+        match value.parse() {
+            Ok(value) if value > 0 => *target = value,
+            _ => return Ok(Reply::error(400, format!("invalid {} `{}`", name, value))),
+        }
+    }
+    let per_page = per_page.min(MAX_PER_PAGE);
+
+    let accounts: Vec<OutputRecord> = account_report(engine, SortBy::Client)?;
+    let total = accounts.len();
+    let page_accounts: Vec<OutputRecord> = accounts
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+
+    Reply::ok(json!({
+        "accounts": page_accounts,
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+    }))
+}
@@ -0,0 +1,47 @@
+mod account_store;
+mod batch;
+mod config;
+mod disk_store;
+mod engine;
+mod error;
+mod export;
+mod fast_parse;
+mod handlers;
+mod lifecycle;
+mod model;
+mod parallel;
+mod report;
+mod snapshot;
+mod sqlite_store;
+mod store;
+mod wal;
+
+pub use account_store::{AccountStore, ShardedAccounts};
+pub use config::{EngineConfig, RedisputePolicy, TxIdPolicy};
+pub use disk_store::{DiskAccounts, DiskStore, DiskTxStore};
+pub use engine::{Engine, Outcome, OutcomeKind};
+pub use error::EngineError;
+pub use export::{
+    accounts_schema, history_schema, write_accounts_parquet, ExportError, HistoryRecord,
+    HistoryWriter, EXPORT_SCALE,
+};
+pub use fast_parse::{parse_amount, RowParseError, RowParser};
+pub use lifecycle::TxState;
+pub use model::{AccountRecord, OutputRecord, TransactionRow, TransactionType};
+pub use parallel::{Applied, ParallelEngine, ParallelError};
+pub use report::{
+    account_report, detailed_account_report, DetailedOutputRecord, LedgerRecord, RejectionRecord,
+    SortBy, TransactionRecord, PARSE_ERROR,
+};
+pub use snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION};
+pub use sqlite_store::{SqliteAccounts, SqliteStore, SqliteTransactions, SQLITE_SCHEMA_VERSION};
+pub use store::{StoredTx, TransactionStore, TxStore};
+pub use wal::{
+    replay_wal, replay_wal_into, Wal, WalError, WalReplay, DEFAULT_SYNC_EVERY, WAL_VERSION,
+};
+
+#[cfg(test)]
+use handlers::*;
+
+#[cfg(test)]
+mod tests;
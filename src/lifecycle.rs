@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::RedisputePolicy;
+use crate::error::EngineError;
+
+// Where a stored transaction is in the dispute process. A representment (the
+// merchant contesting a chargeback) would be the next state after ChargedBack,
+// the enum is non_exhaustive so it can be added without breaking embedders
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum TxState {
+    #[default]
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl TxState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxState::Processed => "processed",
+            TxState::Disputed => "disputed",
+            TxState::Resolved => "resolved",
+            TxState::ChargedBack => "charged_back",
+        }
+    }
+
+    // The only legal moves are below, the handlers ask for the next state
+    // before touching any balance and store it once the balances are updated
+    pub(crate) fn dispute(self, tx: u32, redispute: RedisputePolicy) -> Result<Self, EngineError> {
+        match self {
+            TxState::Processed => Ok(TxState::Disputed),
+            TxState::Resolved if redispute == RedisputePolicy::Allow => Ok(TxState::Disputed),
+            TxState::Resolved => Err(EngineError::RedisputeNotAllowed { tx }),
+            TxState::Disputed => Err(EngineError::AlreadyDisputed { tx }),
+            TxState::ChargedBack => Err(EngineError::ChargedBack { tx }),
+        }
+    }
+
+    pub(crate) fn resolve(self, tx: u32) -> Result<Self, EngineError> {
+        match self {
+            TxState::Disputed => Ok(TxState::Resolved),
+            TxState::ChargedBack => Err(EngineError::ChargedBack { tx }),
+            TxState::Processed | TxState::Resolved => Err(EngineError::NotDisputed { tx }),
+        }
+    }
+
+    // Specification says a 'chargeback is the final state of a dispute'
+    pub(crate) fn chargeback(self, tx: u32) -> Result<Self, EngineError> {
+        match self {
+            TxState::Disputed => Ok(TxState::ChargedBack),
+            TxState::ChargedBack => Err(EngineError::ChargedBack { tx }),
+            TxState::Processed | TxState::Resolved => Err(EngineError::NotDisputed { tx }),
+        }
+    }
+}
@@ -1,338 +1,782 @@
 use csv::ReaderBuilder;
+use std::collections::VecDeque;
+use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
-
-use rust_decimal::Decimal;
-use serde::Deserialize;
-
-use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
 
 use log2::*;
 
-use csv::Writer;
+use csv::{ByteRecord, StringRecord, Writer};
 use serde::Serialize;
 
-#[cfg(test)]
-mod tests;
-
-#[derive(Debug, Deserialize)]
-struct TransactionRow {
-    #[serde(rename = "type")] // 'type' is reserved, fix serde mapping 'type' from .csv
-    tx_type: TransactionType,
-    client: u16,
-    tx: u32,
-    amount: Option<Decimal>, // Handles 4 decimal precision and types like dispute
-    // that do not have an 'amount', per the Specification
-    #[serde(skip)] // 'disputed' is not in the source CSV
-    disputed: bool,
+use take_home::{
+    account_report, detailed_account_report, replay_wal, replay_wal_into, write_accounts_parquet,
+    DiskStore, Engine, EngineConfig, EngineError, HistoryRecord, HistoryWriter, LedgerRecord,
+    Outcome, OutcomeKind, OutputRecord, ParallelEngine, RejectionRecord, RowParseError, RowParser,
+    Snapshot, SqliteStore, TransactionRecord, TransactionRow, TxIdPolicy, Wal,
+};
+
+mod cli;
+mod compress;
+mod http;
+mod serve;
+
+use cli::{
+    AccountsArgs, Cli, Command, EngineArgs, ExportArgs, InputArgs, InputFormat, InspectArgs,
+    OutputFormat, ProcessArgs, ReplayWalArgs, ReportArgs,
+};
+use compress::{decompress, Output};
+
+// Exit codes, also listed in the README and `--help`. 2 is left to clap for usage errors
+const EXIT_FATAL: u8 = 1;
+const EXIT_PARTIAL: u8 = 3;
+
+// Where a report goes, in the chosen --format. Records are written as they
+// come, a json report is one array closed by finish(), which also completes
+// a compressed file
+enum ReportWriter {
+    Csv(Box<Writer<Output>>),
+    Json {
+        out: BufWriter<Output>,
+        records: u64,
+    },
+    Jsonl(BufWriter<Output>),
 }
 
-#[derive(Debug, Default)]
-struct AccountRecord {
-    available: Decimal,
-    held: Decimal,
-    locked: bool,
-}
+impl ReportWriter {
+    fn new(out: Output, format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Csv => ReportWriter::Csv(Box::new(Writer::from_writer(out))),
+            OutputFormat::Json => ReportWriter::Json {
+                out: BufWriter::new(out),
+                records: 0,
+            },
+            OutputFormat::Jsonl => ReportWriter::Jsonl(BufWriter::new(out)),
+        }
+    }
+
+    fn serialize<T: Serialize>(&mut self, record: T) -> Result<(), Box<dyn Error>> {
+        match self {
+            ReportWriter::Csv(writer) => writer.serialize(record)?,
+            ReportWriter::Json { out, records } => {
+                out.write_all(if *records == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *out, &record)?;
+                *records += 1;
+            }
+            ReportWriter::Jsonl(out) => {
+                serde_json::to_writer(&mut *out, &record)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ReportWriter::Csv(writer) => writer.flush(),
+            ReportWriter::Json { out, .. } | ReportWriter::Jsonl(out) => out.flush(),
+        }
+    }
 
-// These are the only transaction types currently supported
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+    // Once every record is written
+    fn finish(mut self) -> std::io::Result<()> {
+        if let ReportWriter::Json { out, records } = &mut self {
+            out.write_all(if *records == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        let mut out = match self {
+            ReportWriter::Csv(writer) => writer.into_inner().map_err(|err| err.into_error())?,
+            ReportWriter::Json { out, .. } | ReportWriter::Jsonl(out) => {
+                out.into_inner().map_err(|err| err.into_error())?
+            }
+        };
+        out.finish()
+    }
 }
 
-#[derive(Serialize)]
-struct OutputRecord {
-    client: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
+// Counts from one pass over an input, used to pick the exit code
+#[derive(Debug, Default)]
+struct RunSummary {
+    rows: u64,
+    rejected: u64,
 }
 
-fn handle_deposit(
-    transaction: TransactionRow,
-    accounts: &mut HashMap<u16, AccountRecord>,
-    transactions: &mut HashMap<u32, TransactionRow>,
-) -> Result<(), String> {
-    let amount = transaction
-        .amount
-        .filter(|a| a.scale() <= 4) // Reject > 4 decimal places
-        .filter(|a| *a > Decimal::ZERO) // Don't allow zero deposit
-        .ok_or_else(|| {
-            format!(
-                "Deposit transaction:{} must have a valid amount up to four decimals",
-                transaction.tx
-            )
-        })?;
-
-    if transactions.contains_key(&transaction.tx) {
-        return Err(format!("Duplicate transaction ID: {}", transaction.tx));
+fn main() -> ExitCode {
+    let command = Cli::parse_with_default().command;
+
+    let log = match &command {
+        Command::Process(args) | Command::Replay(args) => &args.report.input.log,
+        Command::Validate(args) => &args.input.log,
+        Command::Inspect(args) => &args.report.input.log,
+        Command::ReplayWal(args) => &args.log,
+        Command::Serve(args) => &args.log,
+        Command::Http(args) => &args.log,
+        Command::Export(args) => &args.input.log,
+    };
+    // Kept alive until main returns so the log is flushed
+    let _log2 = log2::open(&log.log_file).level(&log.log_level).start();
+
+    let result = match command {
+        Command::Process(args) => process(args),
+        Command::Replay(mut args) => {
+            // A replayed batch may overlap what was already applied
+            args.report.input.engine.tx_id_policy = TxIdPolicy::IdempotentReplay;
+            process(args)
+        }
+        Command::Validate(args) => validate(args),
+        Command::Inspect(args) => inspect(args),
+        Command::ReplayWal(args) => replay_wal_report(args),
+        Command::Serve(args) => serve::serve(args),
+        Command::Http(args) => http::serve_http(args),
+        Command::Export(args) => export(args),
+    };
+
+    match result {
+        Ok(summary) if summary.rejected == 0 => ExitCode::SUCCESS,
+        Ok(summary) => {
+            warn!(
+                "{} of {} rows were rejected",
+                summary.rejected, summary.rows
+            );
+            ExitCode::from(EXIT_PARTIAL)
+        }
+        Err(err) => {
+            error!("Fatal error: {}", err);
+            eprintln!("take_home: {}", err);
+            ExitCode::from(EXIT_FATAL)
+        }
     }
+}
+
+fn process(args: ProcessArgs) -> Result<RunSummary, Box<dyn Error>> {
+    let (mut engine, wal) = new_engine(&args.report.input.engine)?;
+    let (summary, wal) = run_input(&mut engine, wal, &args.report.input, None, None)?;
 
-    // Only create the account when there is a valid amount
-    // Only persist the account when there is a valid amount
-    let account = accounts.entry(transaction.client).or_default();
+    let output_writer = open_output(
+        args.report.output.output.as_deref(),
+        args.report.output.format,
+    )?;
+    write_account_report(&engine, &args.accounts, output_writer)?;
 
-    // This isn't explicit in the Specification, but was uncovered during testing
-    // If the account is locked, we cannot deposit to (or withdraw from) it
-    if account.locked {
-        return Err(format!("Account: {} is locked", transaction.client));
+    if let Some(path) = &args.save_state {
+        info!("Saving state to: {}", path);
+        let wal_seq = wal.map_or(0, |wal| wal.last_seq());
+        Snapshot::of(&engine)?.with_wal_seq(wal_seq).save(path)?;
     }
 
-    account.available += amount;
-    transactions.insert(transaction.tx, transaction);
+    Ok(summary)
+}
 
-    Ok(())
+// Same pass as process, but the rejections are the report
+fn validate(args: ReportArgs) -> Result<RunSummary, Box<dyn Error>> {
+    let mut engine = read_only_engine(&args.input.engine)?;
+    let mut output_writer = open_output(args.output.output.as_deref(), args.output.format)?;
+    let (summary, _) = run_input(
+        &mut engine,
+        None,
+        &args.input,
+        Some(&mut output_writer),
+        None,
+    )?;
+    output_writer.finish()?;
+
+    info!(
+        "Validated {} rows, {} rejected",
+        summary.rows, summary.rejected
+    );
+    Ok(summary)
 }
 
-fn handle_withdrawal(
-    transaction: &TransactionRow,
-    accounts: &mut HashMap<u16, AccountRecord>,
-) -> Result<(), String> {
-    let amount = transaction
-        .amount
-        .filter(|a| a.scale() <= 4) // Reject > than 4 decimal places
-        .filter(|a| *a > Decimal::ZERO) // Don't allow zero withdrawal
-        .ok_or_else(|| {
-            format!(
-                "Deposit transaction:{} must have a valid amount up to four decimals",
-                transaction.tx
-            )
-        })?;
-
-    let account = accounts.get_mut(&transaction.client).ok_or_else(|| {
-        format!(
-            "Account: {} does not exist for withdrawal",
-            transaction.client
-        )
-    })?;
-
-    // Apply the same logic in Deposit for a locked account
-    if account.locked {
-        return Err(format!("Account: {} is locked", transaction.client));
+fn inspect(args: InspectArgs) -> Result<RunSummary, Box<dyn Error>> {
+    let mut engine = read_only_engine(&args.report.input.engine)?;
+    let (summary, _) = run_input(&mut engine, None, &args.report.input, None, None)?;
+
+    // Nothing is written when the account or transaction doesn't exist
+    let mut output_writer = open_output(
+        args.report.output.output.as_deref(),
+        args.report.output.format,
+    )?;
+    if let Some(client) = args.target.client {
+        if let Some(account) = engine.account(client)? {
+            output_writer.serialize(OutputRecord::new(client, &account))?;
+        }
     }
-
-    if account.available < amount {
-        return Err(format!(
-            "Insufficient funds: tried to withdraw {} from available {}",
-            amount, account.available
-        ));
+    if let Some(tx) = args.target.tx {
+        if let Some(transaction) = engine.transaction(tx)? {
+            output_writer.serialize(TransactionRecord::new(&transaction))?;
+        }
     }
+    output_writer.finish()?;
 
-    account.available -= amount;
-
-    Ok(())
+    Ok(summary)
 }
 
-fn handle_dispute(
-    transaction: &TransactionRow,
-    accounts: &mut HashMap<u16, AccountRecord>,
-    transactions: &mut HashMap<u32, TransactionRow>,
-) -> Result<(), String> {
-    let disputed_tx = transactions.get_mut(&transaction.tx).ok_or_else(|| {
-        format!(
-            "Dispute references non-existent transaction: {}",
-            transaction.tx
-        )
-    })?;
-
-    // Found while testing, cannot dispute the same transaction > 1 time
-    if disputed_tx.client != transaction.client {
-        return Err(format!(
-            "Client: {} cannot dispute transaction belonging to client: {}",
-            transaction.client, disputed_tx.client
-        ));
+// Both files are written whole: the transaction log as rows are applied,
+// closed once the run is over, and the accounts at the end
+fn export(args: ExportArgs) -> Result<RunSummary, Box<dyn Error>> {
+    let (mut engine, wal) = new_engine(&args.input.engine)?;
+    let mut history = match &args.exports.transactions {
+        Some(path) => Some(HistoryWriter::new(File::create(path)?)?),
+        None => None,
+    };
+    let (summary, _) = run_input(&mut engine, wal, &args.input, None, history.as_mut())?;
+    if let Some(history) = history {
+        history.finish()?;
     }
 
-    if disputed_tx.disputed {
-        return Err(format!(
-            "Transaction: {} is already under dispute",
-            transaction.tx
-        ));
+    if let Some(path) = &args.exports.accounts {
+        info!("Exporting accounts to: {}", path);
+        write_accounts_parquet(&engine, File::create(path)?)?;
     }
+    Ok(summary)
+}
 
-    let amount = disputed_tx
-        .amount
-        .ok_or_else(|| format!("Transaction: {} has no amount", transaction.tx))?;
-
-    let account = accounts
-        .get_mut(&transaction.client)
-        .ok_or_else(|| format!("Account: {} does not exist", transaction.client))?;
-
-    // Per Specification, "clients available funds should decrease by amount disputed"
-    // Per Specification, "held funds should increase by the amount disputed"
-    account.available -= amount;
-    account.held += amount;
-    // We check later if a transaction is under dispute
-    disputed_tx.disputed = true;
-
-    Ok(())
+// The snapshot is loaded into the chosen stores, then the WAL's entries from
+// after it are replayed on top
+fn new_engine(args: &EngineArgs) -> Result<(Engine, Option<Wal>), Box<dyn Error>> {
+    let (mut engine, wal_seq) = open_engine(args, false)?;
+    let wal = match &args.wal {
+        Some(path) => {
+            let (wal, replay) = Wal::open_after(path, &mut engine, args.wal_sync_every, wal_seq)?;
+            info!("Replayed {} entries from WAL: {}", replay.entries, path);
+            if replay.torn_tail {
+                warn!("WAL: {} ended in a torn entry, it was dropped", path);
+            }
+            Some(wal)
+        }
+        None => None,
+    };
+    Ok((engine, wal))
 }
 
-fn handle_resolve(
-    transaction: &TransactionRow,
-    accounts: &mut HashMap<u16, AccountRecord>,
-    transactions: &mut HashMap<u32, TransactionRow>,
-) -> Result<(), String> {
-    let resolved_tx = transactions.get_mut(&transaction.tx).ok_or_else(|| {
-        format!(
-            "Resolve references non-existent transaction: {}",
-            transaction.tx
-        )
-    })?;
-
-    // Verify transaction belongs to this client
-    if resolved_tx.client != transaction.client {
-        return Err(format!(
-            "Client: {} cannot resolve transaction belonging to client: {}",
-            transaction.client, resolved_tx.client
-        ));
+// As new_engine(), for commands that only look at the state (validate,
+// inspect). The stores are opened read-only and the WAL is replayed but
+// never written, so whatever they hold is left as it was
+fn read_only_engine(args: &EngineArgs) -> Result<Engine, Box<dyn Error>> {
+    let (mut engine, wal_seq) = open_engine(args, true)?;
+    if let Some(path) = &args.wal {
+        let replay = replay_wal_into(path, &mut engine, wal_seq)?;
+        info!("Replayed {} entries from WAL: {}", replay.entries, path);
     }
+    Ok(engine)
+}
 
-    // Check if transaction is under dispute
-    if !resolved_tx.disputed {
-        return Err(format!(
-            "Transaction: {} is not under dispute",
-            transaction.tx
-        ));
+// The chosen stores with the snapshot loaded into them, and the WAL entry the
+// snapshot was taken at. Read-only, a store that doesn't exist yet is an
+// empty one kept in memory
+fn open_engine(args: &EngineArgs, read_only: bool) -> Result<(Engine, u64), Box<dyn Error>> {
+    let config = EngineConfig {
+        dispute_withdrawals: args.dispute_withdrawals,
+        tx_id_policy: args.tx_id_policy,
+        redispute: args.redispute,
+    };
+    let missing = |path: &String| read_only && !Path::new(path).exists();
+    let mut engine = match (&args.tx_store, &args.sqlite) {
+        (Some(path), _) | (None, Some(path)) if missing(path) => {
+            info!("No store at: {} yet, starting from an empty one", path);
+            Engine::with_config(config)
+        }
+        (Some(path), _) => {
+            info!("Using on-disk store: {}", path);
+            let store = if read_only {
+                DiskStore::open_read_only(path)?
+            } else {
+                DiskStore::open(path)?
+            };
+            Engine::with_stores(config, store.accounts(), store.transactions())
+        }
+        (None, Some(path)) => {
+            info!("Using SQLite database: {}", path);
+            let store = if read_only {
+                SqliteStore::open_read_only(path)?
+            } else {
+                SqliteStore::open(path)?
+            };
+            Engine::with_stores(config, store.accounts(), store.transactions())
+        }
+        (None, None) => Engine::with_config(config),
+    };
+    let mut wal_seq = 0;
+    if let Some(path) = &args.load_state {
+        info!("Loading state from: {}", path);
+        let snapshot = Snapshot::load(path)?;
+        wal_seq = snapshot.wal_seq();
+        snapshot.restore(&mut engine)?;
     }
+    Ok((engine, wal_seq))
+}
 
-    let amount = resolved_tx
-        .amount
-        .ok_or_else(|| format!("Transaction: {} has no amount", transaction.tx))?;
+// Rebuilds the accounts from a WAL alone. A torn final entry counts as a
+// rejected row, any other damage is fatal
+fn replay_wal_report(args: ReplayWalArgs) -> Result<RunSummary, Box<dyn Error>> {
+    info!("Replaying WAL: {}", args.wal);
+    let (engine, replay) = replay_wal(&args.wal)?;
 
-    let account = accounts
-        .get_mut(&transaction.client)
-        .ok_or_else(|| format!("Account: {} does not exist", transaction.client))?;
+    let output_writer = open_output(args.output.output.as_deref(), args.output.format)?;
+    write_account_report(&engine, &args.accounts, output_writer)?;
 
-    account.held -= amount;
-    account.available += amount;
-    resolved_tx.disputed = false;
+    let mut summary = RunSummary {
+        rows: replay.entries,
+        rejected: 0,
+    };
+    if replay.torn_tail {
+        warn!("WAL: {} ends in a torn entry", args.wal);
+        summary.rows += 1;
+        summary.rejected += 1;
+    }
+    Ok(summary)
+}
 
-    Ok(())
+// '-' reads stdin so the engine can sit at the end of a pipeline. gzip and
+// zstd inputs are decompressed on the way
+fn open_input(path: &str) -> std::io::Result<Box<dyn Read>> {
+    if path == "-" {
+        return decompress(Box::new(std::io::stdin().lock()));
+    }
+    decompress(Box::new(File::open(path)?))
+}
+
+fn open_output(path: Option<&str>, format: OutputFormat) -> std::io::Result<ReportWriter> {
+    let output = match path {
+        Some(path) => Output::for_path(path, Box::new(File::create(path)?))?,
+        None => Output::Plain(Box::new(std::io::stdout())),
+    };
+    Ok(ReportWriter::new(output, format))
 }
 
-fn handle_chargeback(
-    transaction: &TransactionRow,
-    accounts: &mut HashMap<u16, AccountRecord>,
-    transactions: &mut HashMap<u32, TransactionRow>,
-) -> Result<(), String> {
-    let chargeback_tx = transactions.get_mut(&transaction.tx).ok_or_else(|| {
-        format!(
-            "Chargeback references non-existent transaction: {}",
-            transaction.tx
-        )
-    })?;
-
-    // Verify chargeback request belongs to this client
-    if chargeback_tx.client != transaction.client {
-        return Err(format!(
-            "Client: {} cannot chargeback transaction belonging to client: {}",
-            transaction.client, chargeback_tx.client
-        ));
+// --rejections and --ledger are always csv
+fn open_csv(path: Option<&str>) -> std::io::Result<Option<ReportWriter>> {
+    match path {
+        Some(path) => Ok(Some(ReportWriter::new(
+            Output::for_path(path, Box::new(File::create(path)?))?,
+            OutputFormat::Csv,
+        ))),
+        None => Ok(None),
     }
+}
 
-    // Specification says a 'chargeback is the final state of a dispute'
-    // So account must be under 'dispute' to initiate a chargeback
-    if !chargeback_tx.disputed {
-        return Err(format!(
-            "Transaction: {} is not under dispute and cannot be charged back",
-            transaction.tx
-        ));
+fn write_account_report(
+    engine: &Engine,
+    args: &AccountsArgs,
+    mut writer: ReportWriter,
+) -> Result<(), Box<dyn Error>> {
+    if args.details {
+        for record in detailed_account_report(engine, args.sort_by)? {
+            writer.serialize(record)?;
+        }
+    } else {
+        for record in account_report(engine, args.sort_by)? {
+            writer.serialize(record)?;
+        }
     }
+    writer.finish()?;
+    Ok(())
+}
 
-    let amount = chargeback_tx
-        .amount
-        .ok_or_else(|| format!("Transaction: {} has no amount", transaction.tx))?;
+// Streams every input, in order, through the same Engine. Rejected rows go to
+// --rejections and `extra_rejections` when given, applied ones to the WAL,
+// and every row to `history` when given. The WAL is handed back synced
+fn run_input(
+    engine: &mut Engine,
+    wal: Option<Wal>,
+    args: &InputArgs,
+    extra_rejections: Option<&mut ReportWriter>,
+    history: Option<&mut HistoryWriter<File>>,
+) -> Result<(RunSummary, Option<Wal>), Box<dyn Error>> {
+    let mut rejections_writer = open_csv(args.rows.rejections.as_deref())?;
+    let mut ledger_writer = open_csv(args.rows.ledger.as_deref())?;
+    let mut results = Results {
+        wal,
+        rejections: rejections_writer
+            .iter_mut()
+            .chain(extra_rejections)
+            .collect(),
+        ledger: ledger_writer.as_mut(),
+        history,
+        summary: RunSummary::default(),
+    };
 
-    let account = accounts
-        .get_mut(&transaction.client)
-        .ok_or_else(|| format!("Account: {} does not exist", transaction.client))?;
+    if args.workers > 1 {
+        info!("Applying rows on {} workers", args.workers);
+        let mut parallel = ParallelEngine::start(std::mem::take(engine), args.workers)?;
+        for source in &args.inputs {
+            info!("Processing input: {}", source);
+            run_source(
+                &mut Applier::Parallel(&mut parallel),
+                source,
+                args,
+                &mut results,
+            )?;
+        }
+        *engine = parallel.finish()?;
+    } else {
+        for source in &args.inputs {
+            info!("Processing input: {}", source);
+            run_source(&mut Applier::Sequential(engine), source, args, &mut results)?;
+        }
+    }
 
-    account.held -= amount;
-    account.locked = true;
-    // Found while testing, a chargeback is no longer under dispute
-    chargeback_tx.disputed = false;
+    results.commit(engine)?;
+    let Results { summary, wal, .. } = results;
+    for writer in rejections_writer.into_iter().chain(ledger_writer) {
+        writer.finish()?;
+    }
+    Ok((summary, wal))
+}
 
-    Ok(())
+// Where parsed rows are applied: in the calling thread, or on --workers shards
+enum Applier<'a> {
+    Sequential(&'a mut Engine),
+    Parallel(&'a mut ParallelEngine),
 }
 
-fn main() {
-    let _log2 = log2::open("run_log.txt").start();
+// A row still with the --workers shards, or an unparsed one queued behind
+// them so rejections are written in input order either way
+enum Pending {
+    Row { line: u64 },
+    Unparsed(RejectionRecord),
+}
 
-    let transaction_csv = std::env::args().nth(1).expect("Transactions csv required");
+// Where every row's fate is recorded, in input order. Failed rows are always
+// logged, and also written to each of the rejection writers. Applied rows go
+// to the ledger, and every row to the history, when there is one
+struct Results<'a> {
+    wal: Option<Wal>,
+    rejections: Vec<&'a mut ReportWriter>,
+    ledger: Option<&'a mut ReportWriter>,
+    history: Option<&'a mut HistoryWriter<File>>,
+    summary: RunSummary,
+}
 
-    // maybe look to use ? | have main() return a result
-    let transaction_file = match File::open(transaction_csv) {
-        Ok(transaction_file) => transaction_file,
-        Err(err) => {
-            error!("Invalid transactions file: {}", err);
-            std::process::exit(1);
+impl Results<'_> {
+    // Makes everything recorded so far durable: the WAL, the engine's
+    // stores and the rejection writers
+    fn commit(&mut self, engine: &mut Engine) -> Result<(), Box<dyn Error>> {
+        if let Some(wal) = &mut self.wal {
+            wal.sync()?;
         }
-    };
+        engine.flush()?;
+        for writer in self.rejections.iter_mut().chain(self.ledger.as_mut()) {
+            writer.flush()?;
+        }
+        Ok(())
+    }
 
-    let file_reader = BufReader::new(transaction_file);
-    let mut transaction_csv_reader = ReaderBuilder::new()
-        .trim(csv::Trim::All) // Handle whitespace per Specification
-        .flexible(true) // Handle non-required fields per Specification
-        .from_reader(file_reader);
+    // A row that couldn't be parsed
+    fn reject(&mut self, rejection: RejectionRecord) -> Result<(), Box<dyn Error>> {
+        if let Some(history) = &mut self.history {
+            history.push(HistoryRecord::unparsed(&rejection))?;
+        }
+        self.write_rejection(rejection)
+    }
 
-    // These HashMaps will store all our Account & Transactions Entries by client ID
-    let mut all_accounts: HashMap<u16, AccountRecord> = HashMap::new();
-    let mut all_transactions: HashMap<u32, TransactionRow> = HashMap::new();
+    fn write_rejection(&mut self, rejection: RejectionRecord) -> Result<(), Box<dyn Error>> {
+        self.summary.rejected += 1;
+        for writer in self.rejections.iter_mut() {
+            writer.serialize(&rejection)?;
+        }
+        Ok(())
+    }
 
-    // Process each row at a time, minimizing memory consumption
-    for row in transaction_csv_reader.deserialize::<TransactionRow>() {
-        let transaction: TransactionRow = match row {
-            Ok(transaction) => transaction,
-            Err(err) => {
-                warn!("Row is being skipped, error: {}", err);
-                continue;
+    fn record(
+        &mut self,
+        source: &str,
+        line: u64,
+        transaction: &TransactionRow,
+        result: Result<Outcome, EngineError>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(history) = &mut self.history {
+            if !matches!(result, Err(EngineError::Storage { .. })) {
+                history.push(HistoryRecord::applied(source, line, transaction, &result))?;
             }
-        };
+        }
+        match result {
+            Ok(outcome) if outcome.kind == OutcomeKind::Replayed => {
+                info!(
+                    "{}:{} Transaction: {} is a replay and was skipped",
+                    source, line, outcome.tx
+                );
+            }
+            Ok(outcome) => {
+                if outcome.kind == OutcomeKind::AppliedReusedId {
+                    warn!(
+                        "{}:{} Transaction: {} reuses an existing tx ID",
+                        source, line, outcome.tx
+                    );
+                }
+                // Only rows that changed the state are logged
+                if let Some(wal) = &mut self.wal {
+                    wal.append(transaction)?;
+                }
+                if let Some(ledger) = &mut self.ledger {
+                    ledger.serialize(LedgerRecord::new(source, line, &outcome))?;
+                }
+            }
+            // The store failing says nothing about the row, so the run stops
+            Err(e @ EngineError::Storage { .. }) => return Err(e.into()),
+            Err(e) => {
+                error!(
+                    "{}:{} Transaction failed [{}]: {}",
+                    source,
+                    line,
+                    e.code(),
+                    e
+                );
+                self.write_rejection(RejectionRecord::rejected(source, line, transaction, &e))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Records every pending row whose result is in, or all of them with `wait`
+    fn collect(
+        &mut self,
+        parallel: &mut ParallelEngine,
+        pending: &mut VecDeque<Pending>,
+        source: &str,
+        wait: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            match pending.front() {
+                None => return Ok(()),
+                Some(Pending::Unparsed(_)) => {
+                    if let Some(Pending::Unparsed(rejection)) = pending.pop_front() {
+                        self.reject(rejection)?;
+                    }
+                }
+                Some(Pending::Row { line }) => {
+                    let line = *line;
+                    let applied = if wait {
+                        parallel.recv()
+                    } else {
+                        parallel.try_recv()
+                    };
+                    let Some(applied) = applied else {
+                        return Ok(());
+                    };
+                    pending.pop_front();
+                    self.record(source, line, &applied.transaction, applied.result)?;
+                }
+            }
+        }
+    }
+}
+
+// A source's rows: csv deserialized by serde or, with --fast-parse, read by
+// RowParser out of a reused ByteRecord, or JSON lines deserialized by serde
+enum RowReader<R: Read> {
+    Serde {
+        reader: csv::Reader<R>,
+        headers: StringRecord,
+        // Only finds a rejected row's fields
+        columns: RowParser,
+        record: StringRecord,
+    },
+    Fast {
+        reader: csv::Reader<R>,
+        parser: RowParser,
+        record: ByteRecord,
+    },
+    Jsonl {
+        input: BufReader<R>,
+        line: Vec<u8>,
+        number: u64,
+    },
+}
 
-        debug!("Processing Transaction Row: {:?}", transaction);
+// Whitespace is handled per Specification, by the reader or, on the fast
+// path, by RowParser as it parses each field
+fn csv_reader<R: Read>(input: R, fast_parse: bool) -> csv::Reader<R> {
+    ReaderBuilder::new()
+        .trim(if fast_parse {
+            csv::Trim::None
+        } else {
+            csv::Trim::All
+        })
+        .flexible(true) // Handle non-required fields per Specification
+        .from_reader(input)
+}
 
-        // Check the type of operation this single transaction is
-        let result = match transaction.tx_type {
-            TransactionType::Deposit => {
-                handle_deposit(transaction, &mut all_accounts, &mut all_transactions)
+impl<R: Read> RowReader<R> {
+    // Reads a csv's header row. Records are read raw first so a row that
+    // fails to deserialize still has its line number and columns for the
+    // rejections report
+    fn new(input: R, format: InputFormat, fast_parse: bool) -> csv::Result<Self> {
+        Ok(match format {
+            InputFormat::Jsonl => RowReader::Jsonl {
+                input: BufReader::new(input),
+                line: Vec::new(),
+                number: 0,
+            },
+            InputFormat::Csv if fast_parse => {
+                let mut reader = csv_reader(input, true);
+                RowReader::Fast {
+                    parser: RowParser::new(reader.byte_headers()?),
+                    reader,
+                    record: ByteRecord::new(),
+                }
+            }
+            InputFormat::Csv => {
+                let mut reader = csv_reader(input, false);
+                let headers = reader.headers()?.clone();
+                RowReader::Serde {
+                    columns: RowParser::new(headers.as_byte_record()),
+                    headers,
+                    reader,
+                    record: StringRecord::new(),
+                }
             }
-            TransactionType::Withdrawal => handle_withdrawal(&transaction, &mut all_accounts),
-            TransactionType::Dispute => {
-                handle_dispute(&transaction, &mut all_accounts, &mut all_transactions)
+        })
+    }
+
+    // The next row and its line, or why it can't be applied. None at the end
+    #[allow(clippy::type_complexity)]
+    fn next(
+        &mut self,
+        source: &str,
+    ) -> csv::Result<Option<(u64, Result<TransactionRow, RejectionRecord>)>> {
+        let read = match self {
+            RowReader::Serde { reader, record, .. } => reader.read_record(record),
+            RowReader::Fast { reader, record, .. } => reader.read_byte_record(record),
+            RowReader::Jsonl {
+                input,
+                line,
+                number,
+            } => return Ok(next_json_line(input, line, number, source)?),
+        };
+        match read {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            // An I/O error will just repeat, so it ends the run
+            Err(err) if matches!(err.kind(), csv::ErrorKind::Io(_)) => return Err(err),
+            Err(err) => {
+                let line = err.position().map_or(0, |p| p.line());
+                warn!("{}:{} Row is being skipped, error: {}", source, line, err);
+                return Ok(Some((
+                    line,
+                    Err(RejectionRecord::unparsed(source, line, &[])),
+                )));
             }
-            TransactionType::Resolve => {
-                handle_resolve(&transaction, &mut all_accounts, &mut all_transactions)
+        }
+
+        let row = match self {
+            RowReader::Serde {
+                headers,
+                columns,
+                record,
+                ..
+            } => {
+                let line = record.position().map_or(0, |p| p.line());
+                let transaction = record.deserialize(Some(headers)).map_err(|err| {
+                    warn!("{}:{} Row is being skipped, error: {}", source, line, err);
+                    let fields = columns.fields(record.as_byte_record());
+                    RejectionRecord::unparsed(source, line, &fields)
+                });
+                (line, transaction)
             }
-            TransactionType::Chargeback => {
-                handle_chargeback(&transaction, &mut all_accounts, &mut all_transactions)
+            RowReader::Fast { parser, record, .. } => {
+                let line = record.position().map_or(0, |p| p.line());
+                let transaction = parser.parse(record).map_err(|err| {
+                    warn!("{}:{} Row is being skipped, error: {}", source, line, err);
+                    // Like serde, a row that isn't UTF-8 has no fields to show
+                    let fields = match err {
+                        RowParseError::Utf8 => [""; 4],
+                        _ => parser.fields(record),
+                    };
+                    RejectionRecord::unparsed(source, line, &fields)
+                });
+                (line, transaction)
             }
+            RowReader::Jsonl { .. } => unreachable!("read above"),
         };
+        Ok(Some(row))
+    }
+}
 
-        if let Err(e) = result {
-            error!("Transaction failed: {}", e);
+// One object per line, through the same serde model as a csv row. Blank
+// lines are skipped like blank csv lines, and `number` counts every line
+#[allow(clippy::type_complexity)]
+fn next_json_line<R: Read>(
+    input: &mut BufReader<R>,
+    line: &mut Vec<u8>,
+    number: &mut u64,
+    source: &str,
+) -> std::io::Result<Option<(u64, Result<TransactionRow, RejectionRecord>)>> {
+    loop {
+        line.clear();
+        if input.read_until(b'\n', line)? == 0 {
+            return Ok(None);
+        }
+        *number += 1;
+        if !line.trim_ascii().is_empty() {
+            break;
         }
     }
 
-    let mut output_writer = Writer::from_writer(std::io::stdout());
-    for (client_id, account) in &all_accounts {
-        if let Err(e) = output_writer.serialize(OutputRecord {
-            client: *client_id, // OutputRecord does not want a reference
-            available: account.available,
-            held: account.held,
-            total: account.available + account.held,
-            locked: account.locked,
-        }) {
-            error!("Failed to serialize output: {}", e);
+    let transaction = serde_json::from_slice(line).map_err(|err| {
+        warn!("{}:{} Row is being skipped, error: {}", source, number, err);
+        // Whatever can still be made of the line goes in the report
+        let fields = serde_json::from_slice(line)
+            .map(|object| json_fields(&object))
+            .unwrap_or_default();
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        RejectionRecord::unparsed(source, *number, &fields)
+    });
+    Ok(Some((*number, transaction)))
+}
+
+// The raw type, client, tx and amount of a JSON transaction that didn't
+// deserialize, for its rejection record
+fn json_fields(object: &serde_json::Value) -> [String; 4] {
+    ["type", "client", "tx", "amount"].map(|name| match object.get(name) {
+        Some(serde_json::Value::String(text)) => text.clone(),
+        Some(serde_json::Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    })
+}
+
+// One input file (or stdin). Every source gets its own reader with the same
+// settings, so each has its own header row and line numbers start over
+fn run_source(
+    applier: &mut Applier,
+    source: &str,
+    args: &InputArgs,
+    results: &mut Results,
+) -> Result<(), Box<dyn Error>> {
+    let format = args
+        .input_format
+        .unwrap_or_else(|| InputFormat::of_path(source));
+    let mut rows = RowReader::new(open_input(source)?, format, args.parse.fast_parse)?;
+    let mut pending = VecDeque::new();
+
+    // Process each row at a time, minimizing memory consumption
+    while let Some((line, transaction)) = rows.next(source)? {
+        results.summary.rows += 1;
+
+        match (transaction, &mut *applier) {
+            (Err(rejection), Applier::Sequential(_)) => results.reject(rejection)?,
+            (Err(rejection), Applier::Parallel(_)) => {
+                pending.push_back(Pending::Unparsed(rejection))
+            }
+            (Ok(transaction), Applier::Sequential(engine)) => {
+                debug!("Processing Transaction Row: {:?}", transaction);
+                let result = engine.apply(transaction.clone());
+                results.record(source, line, &transaction, result)?;
+            }
+            (Ok(transaction), Applier::Parallel(parallel)) => {
+                parallel.submit(transaction)?;
+                pending.push_back(Pending::Row { line });
+            }
+        }
+
+        if let Applier::Parallel(parallel) = applier {
+            results.collect(parallel, &mut pending, source, false)?;
         }
     }
-    if let Err(e) = output_writer.flush() {
-        error!("Failed to flush output: {}", e);
+
+    if let Applier::Parallel(parallel) = applier {
+        results.collect(parallel, &mut pending, source, true)?;
     }
+    Ok(())
 }
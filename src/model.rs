@@ -0,0 +1,99 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::lifecycle::TxState;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransactionRow {
+    #[serde(rename = "type")] // 'type' is reserved, fix serde mapping 'type' from .csv
+    pub tx_type: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    // Parsed from the raw text, serde would otherwise route it through f64.
+    // In JSON that means a string ("1.5"), and it may be left out
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    pub amount: Option<Decimal>, // Handles 4 decimal precision and types like dispute
+    // that do not have an 'amount', per the Specification
+    #[serde(skip)] // 'state' is not in the source CSV
+    pub(crate) state: TxState,
+}
+
+impl TransactionRow {
+    // Embedders build rows directly instead of going through the CSV reader
+    pub fn new(tx_type: TransactionType, client: u16, tx: u32, amount: Option<Decimal>) -> Self {
+        TransactionRow {
+            tx_type,
+            client,
+            tx,
+            amount,
+            state: TxState::Processed,
+        }
+    }
+
+    pub fn state(&self) -> TxState {
+        self.state
+    }
+
+    pub fn disputed(&self) -> bool {
+        self.state == TxState::Disputed
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AccountRecord {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+}
+
+impl AccountRecord {
+    // The handlers refuse any change whose total would not fit in a Decimal
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+}
+
+// These are the only transaction types currently supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl TransactionType {
+    // Same spelling as the 'type' column of the input csv
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputRecord {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl OutputRecord {
+    pub fn new(client: u16, account: &AccountRecord) -> Self {
+        OutputRecord {
+            client,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.locked,
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::account_store::shard_of;
+use crate::config::EngineConfig;
+use crate::engine::{Engine, Outcome};
+use crate::error::EngineError;
+use crate::lifecycle::TxState;
+use crate::model::{AccountRecord, TransactionRow};
+use crate::store::{StoredTx, TransactionStore};
+
+// Rows each worker may have queued before submit() waits for it
+const QUEUE_DEPTH: usize = 1024;
+
+#[derive(Debug)]
+pub enum ParallelError {
+    // A worker thread couldn't be started
+    Spawn(std::io::Error),
+    // The Engine's accounts are kept on disk, where the workers can't share them
+    DurableAccounts,
+    // The Engine's stores couldn't be read or written
+    Store(EngineError),
+}
+
+impl fmt::Display for ParallelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParallelError::Spawn(err) => write!(f, "Worker thread couldn't start: {}", err),
+            ParallelError::DurableAccounts => {
+                write!(f, "Workers can't apply rows to accounts kept on disk")
+            }
+            ParallelError::Store(err) => write!(f, "Workers couldn't use the engine: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ParallelError {}
+
+impl From<EngineError> for ParallelError {
+    fn from(err: EngineError) -> Self {
+        ParallelError::Store(err)
+    }
+}
+
+// A row for a worker, with whatever was stored under its tx ID
+struct Apply {
+    seq: u64,
+    transaction: TransactionRow,
+    stored: Option<StoredTx>,
+}
+
+// A row once a worker has applied it. `stored` is what its tx ID is left
+// holding, when the row changed that
+struct Done {
+    seq: u64,
+    transaction: TransactionRow,
+    result: Result<Outcome, EngineError>,
+    stored: Result<Option<Option<StoredTx>>, EngineError>,
+}
+
+// A worker thread, which hands back its clients' accounts once it stops
+type Worker = JoinHandle<Result<Vec<(u16, AccountRecord)>, EngineError>>;
+
+// A row once a worker has applied it, handed back in submission order
+#[derive(Debug)]
+pub struct Applied {
+    pub seq: u64,
+    pub transaction: TransactionRow,
+    pub result: Result<Outcome, EngineError>,
+}
+
+// Applies rows on `workers` threads, each with its own Engine holding the
+// clients for its shard, so a client's rows are applied in order by one
+// thread. Every business rule is scoped to one client except tx IDs, which
+// are global, so the transactions stay in the Engine's own store on the
+// submitting thread. Each row is sent with its tx ID's state and the worker
+// sends back what the row left there, and a row waits for the one before it
+// with the same tx ID to come back. Results are therefore exactly those of
+// applying the same rows to one Engine in order
+pub struct ParallelEngine {
+    engine: Engine,
+    senders: Vec<SyncSender<Apply>>,
+    workers: Vec<Worker>,
+    results: Receiver<Done>,
+    // Tx IDs of the rows not yet written back, at most one row each
+    in_flight: HashSet<u32>,
+    // Rows that came back ahead of an earlier one, not yet written back
+    arrived: BTreeMap<u64, Done>,
+    // Rows written back, waiting to be handed out
+    ready: BTreeMap<u64, Applied>,
+    next_seq: u64,
+    next_written: u64,
+    next_out: u64,
+}
+
+impl ParallelEngine {
+    // Carries on from `engine`'s state: each worker starts with a copy of its
+    // clients' accounts, the transactions stay where they are. The Engine
+    // can't be used by anything else meanwhile, finish() hands it back with
+    // the workers' accounts. Accounts on disk are refused, since the workers
+    // would only keep them in memory until then
+    pub fn start(engine: Engine, workers: usize) -> Result<Self, ParallelError> {
+        if engine.accounts.is_durable() {
+            return Err(ParallelError::DurableAccounts);
+        }
+        let workers = workers.max(1);
+        let config = engine.config;
+        let mut parts: Vec<HashMap<u16, AccountRecord>> = vec![HashMap::new(); workers];
+        engine.accounts.for_each(&mut |client, account| {
+            parts[shard_of(client, workers)].insert(client, account);
+        })?;
+
+        let (results_sender, results) = mpsc::channel();
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for (shard, accounts) in parts.into_iter().enumerate() {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+            let results = results_sender.clone();
+            let handle = thread::Builder::new()
+                .name(format!("shard-{}", shard))
+                .spawn(move || run_shard(config, accounts, receiver, results))
+                .map_err(ParallelError::Spawn)?;
+            senders.push(sender);
+            handles.push(handle);
+        }
+
+        Ok(ParallelEngine {
+            engine,
+            senders,
+            workers: handles,
+            results,
+            in_flight: HashSet::new(),
+            arrived: BTreeMap::new(),
+            ready: BTreeMap::new(),
+            next_seq: 0,
+            next_written: 0,
+            next_out: 0,
+        })
+    }
+
+    // Queues the row on its client's shard and returns its sequence number.
+    // Only waits when the shard's queue is full, or a row with the same tx ID
+    // hasn't come back yet
+    pub fn submit(&mut self, transaction: TransactionRow) -> Result<u64, EngineError> {
+        while self.in_flight.contains(&transaction.tx) {
+            let done = self.results.recv().expect("shard worker stopped");
+            self.arrived(done);
+        }
+        let stored = self.engine.transactions.get(transaction.tx)?;
+        self.in_flight.insert(transaction.tx);
+
+        let shard = shard_of(transaction.client, self.senders.len());
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.senders[shard]
+            .send(Apply {
+                seq,
+                transaction,
+                stored,
+            })
+            .expect("shard worker stopped");
+        Ok(seq)
+    }
+
+    // Writes back what each row left under its tx ID, in submission order as
+    // one Engine would, so the store sees tx IDs in the order they were used.
+    // When that fails, so does the row
+    fn arrived(&mut self, done: Done) {
+        self.arrived.insert(done.seq, done);
+        while let Some(done) = self.arrived.remove(&self.next_written) {
+            let tx = done.transaction.tx;
+            self.in_flight.remove(&tx);
+            let written = done.stored.and_then(|stored| match stored {
+                Some(Some(stored)) => self.engine.transactions.insert(&stored.row(tx)),
+                Some(None) => self.engine.transactions.remove(tx).map(|_| ()),
+                None => Ok(()),
+            });
+            let result = written.and(done.result);
+            self.ready.insert(
+                done.seq,
+                Applied {
+                    seq: done.seq,
+                    transaction: done.transaction,
+                    result,
+                },
+            );
+            self.next_written += 1;
+        }
+    }
+
+    // The next row's result in submission order, if it is done already
+    pub fn try_recv(&mut self) -> Option<Applied> {
+        while let Ok(done) = self.results.try_recv() {
+            self.arrived(done);
+        }
+        self.pop_ready()
+    }
+
+    // The next row's result in submission order, waiting for it if needed.
+    // None once every submitted row has been handed back
+    pub fn recv(&mut self) -> Option<Applied> {
+        while self.next_out < self.next_seq {
+            if let Some(applied) = self.try_recv() {
+                return Some(applied);
+            }
+            let done = self.results.recv().expect("shard worker stopped");
+            self.arrived(done);
+        }
+        None
+    }
+
+    fn pop_ready(&mut self) -> Option<Applied> {
+        let applied = self.ready.remove(&self.next_out)?;
+        self.next_out += 1;
+        Some(applied)
+    }
+
+    // Waits for every queued row and hands back the Engine, with the
+    // workers' accounts written into its own store. Results not yet taken
+    // with recv() are dropped
+    pub fn finish(mut self) -> Result<Engine, ParallelError> {
+        self.senders.clear();
+        let mut accounts = Vec::new();
+        for worker in std::mem::take(&mut self.workers) {
+            accounts.push(worker.join().expect("shard worker panicked")?);
+        }
+        // Every worker has stopped, so every result is in
+        while let Ok(done) = self.results.try_recv() {
+            self.arrived(done);
+        }
+        for (client, account) in accounts.into_iter().flatten() {
+            self.engine.accounts.upsert(client, account)?;
+        }
+        Ok(self.engine)
+    }
+}
+
+fn run_shard(
+    config: EngineConfig,
+    accounts: HashMap<u16, AccountRecord>,
+    receiver: Receiver<Apply>,
+    results: Sender<Done>,
+) -> Result<Vec<(u16, AccountRecord)>, EngineError> {
+    let mut engine = Engine::with_stores(config, accounts, RowTx::default());
+
+    for Apply {
+        seq,
+        transaction,
+        stored,
+    } in receiver
+    {
+        let tx = transaction.tx;
+        let result = engine
+            .put_tx(tx, stored)
+            .and_then(|()| engine.apply(transaction.clone()));
+        let left = engine
+            .take_tx(tx)
+            .map(|left| (left != stored).then_some(left));
+        // The receiver only goes away once nobody wants results
+        let _ = results.send(Done {
+            seq,
+            transaction,
+            result,
+            stored: left,
+        });
+    }
+
+    engine.accounts()
+}
+
+// A worker's TransactionStore. It only ever holds the tx ID of the row being
+// applied, for as long as that takes
+#[derive(Debug, Default)]
+struct RowTx {
+    slot: Option<(u32, StoredTx)>,
+}
+
+impl TransactionStore for RowTx {
+    fn get(&self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        Ok(self
+            .slot
+            .filter(|(held, _)| *held == tx)
+            .map(|(_, stored)| stored))
+    }
+
+    fn insert(&mut self, row: &TransactionRow) -> Result<(), EngineError> {
+        self.slot = Some((row.tx, StoredTx::of(row)?));
+        Ok(())
+    }
+
+    fn set_state(&mut self, tx: u32, state: TxState) -> Result<(), EngineError> {
+        match &mut self.slot {
+            Some((held, stored)) if *held == tx => {
+                stored.state = state;
+                Ok(())
+            }
+            _ => Err(EngineError::UnknownTx { tx }),
+        }
+    }
+
+    fn remove(&mut self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        let stored = self.get(tx)?;
+        if stored.is_some() {
+            self.slot = None;
+        }
+        Ok(stored)
+    }
+
+    fn len(&self) -> usize {
+        self.slot.iter().len()
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(u32, StoredTx)) -> Result<(), EngineError> {
+        if let Some((tx, stored)) = self.slot {
+            f(tx, stored);
+        }
+        Ok(())
+    }
+}
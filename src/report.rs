@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::engine::{Engine, Outcome};
+use crate::error::EngineError;
+use crate::model::{OutputRecord, TransactionRow};
+
+// Column the account report is ordered by. Every order is ascending, and
+// accounts with equal values fall back to client id so runs are repeatable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortBy {
+    #[default]
+    Client,
+    Available,
+    Held,
+    Total,
+}
+
+impl std::str::FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortBy::Client),
+            "available" => Ok(SortBy::Available),
+            "held" => Ok(SortBy::Held),
+            "total" => Ok(SortBy::Total),
+            other => Err(format!(
+                "Unknown sort column: {} (expected client|available|held|total)",
+                other
+            )),
+        }
+    }
+}
+
+// No AccountStore promises an order, so the order is fixed here at emit time
+pub fn account_report(engine: &Engine, sort_by: SortBy) -> Result<Vec<OutputRecord>, EngineError> {
+    let mut records: Vec<OutputRecord> = engine
+        .accounts()?
+        .into_iter()
+        .map(|(client, account)| OutputRecord::new(client, &account))
+        .collect();
+
+    records.sort_by(|a, b| {
+        let by_column = match sort_by {
+            SortBy::Client => Ordering::Equal,
+            SortBy::Available => a.available.cmp(&b.available),
+            SortBy::Held => a.held.cmp(&b.held),
+            SortBy::Total => a.total.cmp(&b.total),
+        };
+        by_column.then(a.client.cmp(&b.client))
+    });
+
+    Ok(records)
+}
+
+// An account report row with what else is known about the account: `status`
+// is locked, disputed (while any of its transactions are) or active
+#[derive(Debug, Serialize)]
+pub struct DetailedOutputRecord {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    pub status: &'static str,
+    pub open_disputes: u32,
+}
+
+// The account report, in the same order, with each account's details
+pub fn detailed_account_report(
+    engine: &Engine,
+    sort_by: SortBy,
+) -> Result<Vec<DetailedOutputRecord>, EngineError> {
+    let open_disputes = engine.open_disputes()?;
+    let records = account_report(engine, sort_by)?
+        .into_iter()
+        .map(|record| {
+            let open_disputes = open_disputes.get(&record.client).copied().unwrap_or(0);
+            let status = match (record.locked, open_disputes) {
+                (true, _) => "locked",
+                (false, 0) => "active",
+                (false, _) => "disputed",
+            };
+            DetailedOutputRecord {
+                client: record.client,
+                available: record.available,
+                held: record.held,
+                total: record.total,
+                locked: record.locked,
+                status,
+                open_disputes,
+            }
+        })
+        .collect();
+    Ok(records)
+}
+
+// A stored transaction as shown by `inspect`
+#[derive(Debug, Serialize)]
+pub struct TransactionRecord {
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Option<Decimal>,
+    pub state: &'static str,
+}
+
+impl TransactionRecord {
+    pub fn new(transaction: &TransactionRow) -> Self {
+        TransactionRecord {
+            tx_type: transaction.tx_type.as_str(),
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount.map(|a| a.normalize()),
+            state: transaction.state().as_str(),
+        }
+    }
+}
+
+// One row of the audit ledger: an applied transaction, what it changed and
+// the client's balances right after it. `source` and `line` are as in the
+// rejections report
+#[derive(Debug, Serialize)]
+pub struct LedgerRecord {
+    pub source: String,
+    pub line: u64,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub available_delta: Decimal,
+    pub held_delta: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl LedgerRecord {
+    pub fn new(source: &str, line: u64, outcome: &Outcome) -> Self {
+        let (before, after) = (&outcome.before, &outcome.account);
+        LedgerRecord {
+            source: source.to_string(),
+            line,
+            client: outcome.client,
+            tx: outcome.tx,
+            tx_type: outcome.tx_type.as_str(),
+            available_delta: (after.available - before.available).normalize(),
+            held_delta: (after.held - before.held).normalize(),
+            available: after.available,
+            held: after.held,
+            total: after.total(),
+            locked: after.locked,
+        }
+    }
+}
+
+// Reason code for rows that never reached the Engine because they didn't parse
+pub const PARSE_ERROR: &str = "parse_error";
+
+// One row of the rejections report. `source` is the input the row came from
+// ('-' for stdin) and `line` is counted within that input. Rows that failed to
+// parse only have whatever raw text was in their columns, so those are optional
+#[derive(Debug, Serialize)]
+pub struct RejectionRecord {
+    pub source: String,
+    pub line: u64,
+    #[serde(rename = "type")]
+    pub tx_type: Option<String>,
+    pub client: Option<String>,
+    pub tx: Option<String>,
+    pub amount: Option<String>,
+    pub reason: &'static str,
+}
+
+impl RejectionRecord {
+    pub fn rejected(
+        source: &str,
+        line: u64,
+        transaction: &TransactionRow,
+        error: &EngineError,
+    ) -> Self {
+        RejectionRecord {
+            source: source.to_string(),
+            line,
+            tx_type: Some(transaction.tx_type.as_str().to_string()),
+            client: Some(transaction.client.to_string()),
+            tx: Some(transaction.tx.to_string()),
+            amount: transaction.amount.map(|a| a.to_string()),
+            reason: error.code(),
+        }
+    }
+
+    // `fields` are the row's type, client, tx and amount, in that order
+    // whatever order the input had them in, as far as there are any
+    pub fn unparsed(source: &str, line: u64, fields: &[&str]) -> Self {
+        let field = |i: usize| {
+            fields
+                .get(i)
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+        RejectionRecord {
+            source: source.to_string(),
+            line,
+            tx_type: field(0),
+            client: field(1),
+            tx: field(2),
+            amount: field(3),
+            reason: PARSE_ERROR,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use csv::Writer;
+use log2::*;
+use serde::Serialize;
+use take_home::{EngineError, Outcome, OutcomeKind, RejectionRecord, TransactionRow};
+
+use crate::cli::{InputFormat, ServeArgs};
+use crate::{new_engine, open_csv, Results, RowReader, RunSummary};
+
+// Most rows applied before the WAL is synced and their answers sent
+const MAX_BATCH: usize = 1024;
+
+// A row from one of the connections, for the engine thread
+enum Request {
+    Row {
+        source: Arc<str>,
+        line: u64,
+        transaction: TransactionRow,
+        reply: Sender<RowResponse>,
+    },
+    Unparsed {
+        rejection: RejectionRecord,
+        reply: Sender<RowResponse>,
+    },
+}
+
+// What a connection is told about each of its rows, one csv line per row.
+// `reason` is the rejection reason, as in the --rejections report
+#[derive(Debug, Serialize)]
+struct RowResponse {
+    line: u64,
+    tx: Option<u32>,
+    result: &'static str,
+    reason: Option<&'static str>,
+}
+
+impl RowResponse {
+    fn of(line: u64, tx: u32, result: &Result<Outcome, EngineError>) -> Self {
+        let (result, reason) = match result {
+            Ok(outcome) if outcome.kind == OutcomeKind::Replayed => ("accepted", Some("replayed")),
+            Ok(_) => ("accepted", None),
+            Err(e) => ("rejected", Some(e.code())),
+        };
+        RowResponse {
+            line,
+            tx: Some(tx),
+            result,
+            reason,
+        }
+    }
+
+    fn unparsed(rejection: &RejectionRecord) -> Self {
+        RowResponse {
+            line: rejection.line,
+            tx: rejection.tx.as_ref().and_then(|tx| tx.parse().ok()),
+            result: "rejected",
+            reason: Some(rejection.reason),
+        }
+    }
+}
+
+// Listens until killed. Each connection streams a csv (header row first) and
+// gets a line back per row. Connections are read on their own threads, and
+// every row is applied on this one, to a single Engine, in the order rows
+// arrive. Rows are answered once they are in the WAL, which is synced after
+// every batch of rows waiting together
+pub fn serve(args: ServeArgs) -> Result<RunSummary, Box<dyn Error>> {
+    let (mut engine, wal) = new_engine(&args.engine)?;
+    let mut rejections_writer = open_csv(args.rows.rejections.as_deref())?;
+    let mut ledger_writer = open_csv(args.rows.ledger.as_deref())?;
+    let mut results = Results {
+        wal,
+        rejections: rejections_writer.iter_mut().collect(),
+        ledger: ledger_writer.as_mut(),
+        history: None,
+        summary: RunSummary::default(),
+    };
+
+    let (requests, received) = mpsc::channel();
+    listen(&args, requests)?;
+
+    while let Ok(first) = received.recv() {
+        let mut replies = Vec::new();
+        for request in std::iter::once(first).chain(received.try_iter().take(MAX_BATCH - 1)) {
+            results.summary.rows += 1;
+            match request {
+                Request::Row {
+                    source,
+                    line,
+                    transaction,
+                    reply,
+                } => {
+                    debug!("Processing Transaction Row: {:?}", transaction);
+                    let result = engine.apply(transaction.clone());
+                    replies.push((reply, RowResponse::of(line, transaction.tx, &result)));
+                    results.record(&source, line, &transaction, result)?;
+                }
+                Request::Unparsed { rejection, reply } => {
+                    replies.push((reply, RowResponse::unparsed(&rejection)));
+                    results.reject(rejection)?;
+                }
+            }
+        }
+
+        results.commit(&mut engine)?;
+        debug!("Committed {} rows", replies.len());
+        // A connection that has gone away no longer wants its answers
+        for (reply, response) in replies {
+            let _ = reply.send(response);
+        }
+    }
+    Ok(results.summary)
+}
+
+// Binds the address and accepts connections on a thread of its own
+fn listen(args: &ServeArgs, requests: Sender<Request>) -> Result<(), Box<dyn Error>> {
+    let fast_parse = args.parse.fast_parse;
+
+    #[cfg(unix)]
+    if let Some(path) = &args.address.unix {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        // A socket left behind by an earlier run would stop the bind
+        if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        announce(path)?;
+        let mut next_id = 0u64;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                next_id += 1;
+                let peer = format!("unix:{}", next_id);
+                match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+                    Ok((input, output)) => connect(peer, input, output, fast_parse, &requests),
+                    Err(err) => warn!("Failed to accept a connection: {}", err),
+                }
+            }
+        });
+        return Ok(());
+    }
+
+    let address = args.address.listen.as_deref().unwrap_or_default();
+    let listener = TcpListener::bind(address)?;
+    announce(&listener.local_addr()?.to_string())?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let accepted = stream.and_then(|stream| {
+                let peer = stream.peer_addr()?.to_string();
+                Ok((peer, stream.try_clone()?, stream))
+            });
+            match accepted {
+                Ok((peer, input, output)) => connect(peer, input, output, fast_parse, &requests),
+                Err(err) => warn!("Failed to accept a connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+// The address goes to stdout as well, so whoever started the server can
+// find a port picked with :0
+pub(crate) fn announce(address: &str) -> std::io::Result<()> {
+    info!("Listening on: {}", address);
+    let mut stdout = std::io::stdout();
+    writeln!(stdout, "listening on {}", address)?;
+    stdout.flush()
+}
+
+fn connect<R, W>(peer: String, input: R, output: W, fast_parse: bool, requests: &Sender<Request>)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    info!("{} connected", peer);
+    let requests = requests.clone();
+    let spawned =
+        thread::Builder::new().name(format!("conn-{}", peer)).spawn(
+            move || match handle_connection(&peer, input, output, fast_parse, requests) {
+                Ok(rows) => info!("{} disconnected after {} rows", peer, rows),
+                Err(err) => warn!("{} dropped: {}", peer, err),
+            },
+        );
+    if let Err(err) = spawned {
+        warn!("Failed to start a connection thread: {}", err);
+    }
+}
+
+// Reads the connection's rows like an input file, the peer standing in for
+// the file name, and answers each in turn. Rows are read ahead of their
+// answers, up to MAX_BATCH of them, so one connection's rows can share a
+// batch. The answers are written on a thread of their own, in the order the
+// rows were read, since the engine thread takes them in that order. Returns
+// the number of rows
+fn handle_connection<R: Read, W: Write + Send>(
+    peer: &str,
+    input: R,
+    output: W,
+    fast_parse: bool,
+    requests: Sender<Request>,
+) -> Result<u64, Box<dyn Error>> {
+    let source: Arc<str> = Arc::from(peer);
+    let mut rows = RowReader::new(input, InputFormat::Csv, fast_parse)?;
+    let (reply, replies) = mpsc::channel();
+    // A slot per row read but not yet answered
+    let (unanswered, answered) = mpsc::sync_channel(MAX_BATCH);
+
+    thread::scope(|scope| {
+        let answers = scope.spawn(move || -> Result<(), csv::Error> {
+            let mut writer = Writer::from_writer(output);
+            loop {
+                // Flushed once no other answer is waiting to go with it
+                let response = match replies.try_recv() {
+                    Ok(response) => response,
+                    Err(TryRecvError::Empty) => {
+                        writer.flush()?;
+                        match replies.recv() {
+                            Ok(response) => response,
+                            Err(_) => break,
+                        }
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                };
+                writer.serialize(response)?;
+                let _ = answered.recv();
+            }
+            writer.flush()?;
+            Ok(())
+        });
+
+        let mut count = 0;
+        while let Some((line, transaction)) = rows.next(peer)? {
+            let request = match transaction {
+                Ok(transaction) => Request::Row {
+                    source: source.clone(),
+                    line,
+                    transaction,
+                    reply: reply.clone(),
+                },
+                Err(rejection) => Request::Unparsed {
+                    rejection,
+                    reply: reply.clone(),
+                },
+            };
+            // Fails once the answers can't be written any more
+            unanswered.send(()).map_err(|_| "connection closed")?;
+            requests.send(request).map_err(|_| "server stopped")?;
+            count += 1;
+        }
+        // The answers end once the last row's reply has been sent
+        drop(reply);
+        answers.join().expect("answer writer panicked")?;
+        Ok(count)
+    })
+}
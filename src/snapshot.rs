@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::config::EngineConfig;
+use crate::engine::Engine;
+use crate::error::EngineError;
+use crate::lifecycle::TxState;
+use crate::model::{AccountRecord, TransactionRow, TransactionType};
+
+// Bumped whenever the layout below changes. Older versions are refused rather
+// than guessed at, since a wrong guess would silently corrupt balances
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    UnsupportedVersion(u32),
+    // The Engine's transaction store couldn't be read or written
+    Store(EngineError),
+    // The snapshot left these ("accounts" or "transactions") in the on-disk
+    // store it was saved from, and the Engine isn't using one
+    NotInStore(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "Snapshot I/O error: {}", err),
+            SnapshotError::Format(err) => write!(f, "Snapshot is malformed: {}", err),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "Snapshot version: {} is not supported (expected {})",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Store(err) => write!(f, "Snapshot transaction store: {}", err),
+            SnapshotError::NotInStore(what) => write!(
+                f,
+                "Snapshot's {} are kept in an on-disk store, load it into an engine using that store",
+                what
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<EngineError> for SnapshotError {
+    fn from(err: EngineError) -> Self {
+        SnapshotError::Store(err)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        SnapshotError::Format(err)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotAccount {
+    client: u16,
+    #[serde(with = "rust_decimal::serde::str")]
+    available: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    held: Decimal,
+    locked: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotTransaction {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "is_processed")]
+    state: TxState,
+}
+
+fn is_processed(state: &TxState) -> bool {
+    *state == TxState::Processed
+}
+
+fn is_false(flag: &bool) -> bool {
+    !flag
+}
+
+fn is_zero(seq: &u64) -> bool {
+    *seq == 0
+}
+
+// Everything the Engine needs to carry on where a previous run stopped. The
+// config is not included, it belongs to the deployment rather than the data.
+// Neither is what an on-disk store already keeps: only the fact that it is
+// there, so the snapshot stays small however much history the store holds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    accounts: Vec<SnapshotAccount>,
+    transactions: Vec<SnapshotTransaction>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    accounts_in_store: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    transactions_in_store: bool,
+    // The last WAL entry the state includes, when the run kept one
+    #[serde(default, skip_serializing_if = "is_zero")]
+    wal_seq: u64,
+}
+
+impl Snapshot {
+    // Rows are sorted so the same state always produces the same file. The
+    // Engine should be flushed first when it uses an on-disk store
+    pub fn of(engine: &Engine) -> Result<Self, SnapshotError> {
+        let accounts_in_store = engine.accounts.is_durable();
+        let transactions_in_store = engine.transactions.is_durable();
+
+        let mut accounts: Vec<SnapshotAccount> = Vec::new();
+        if !accounts_in_store {
+            engine.accounts.for_each(&mut |client, account| {
+                accounts.push(SnapshotAccount {
+                    client,
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked,
+                })
+            })?;
+            accounts.sort_by_key(|account| account.client);
+        }
+
+        let mut transactions: Vec<SnapshotTransaction> = Vec::new();
+        if !transactions_in_store {
+            engine.transactions.for_each(&mut |tx, stored| {
+                transactions.push(SnapshotTransaction {
+                    tx_type: stored.tx_type,
+                    client: stored.client,
+                    tx,
+                    amount: Some(stored.amount),
+                    state: stored.state,
+                })
+            })?;
+            transactions.sort_by_key(|transaction| transaction.tx);
+        }
+
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            accounts,
+            transactions,
+            accounts_in_store,
+            transactions_in_store,
+            wal_seq: 0,
+        })
+    }
+
+    // Records that the state includes the WAL up to entry `seq`, so reopening
+    // that WAL on top of this snapshot doesn't apply those entries twice
+    pub fn with_wal_seq(mut self, seq: u64) -> Self {
+        self.wal_seq = seq;
+        self
+    }
+
+    pub fn wal_seq(&self) -> u64 {
+        self.wal_seq
+    }
+
+    pub fn into_engine(self, config: EngineConfig) -> Result<Engine, SnapshotError> {
+        let mut engine = Engine::with_config(config);
+        self.restore(&mut engine)?;
+        Ok(engine)
+    }
+
+    // Loads the snapshot into an Engine that was built with the stores it
+    // should use. Accounts and transactions already in them are overwritten
+    pub fn restore(self, engine: &mut Engine) -> Result<(), SnapshotError> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(self.version));
+        }
+        if self.accounts_in_store && !engine.accounts.is_durable() {
+            return Err(SnapshotError::NotInStore("accounts"));
+        }
+        if self.transactions_in_store && !engine.transactions.is_durable() {
+            return Err(SnapshotError::NotInStore("transactions"));
+        }
+
+        for account in self.accounts {
+            engine.accounts.upsert(
+                account.client,
+                AccountRecord {
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked,
+                },
+            )?;
+        }
+        for transaction in self.transactions {
+            let mut row = TransactionRow::new(
+                transaction.tx_type,
+                transaction.client,
+                transaction.tx,
+                transaction.amount,
+            );
+            row.state = transaction.state;
+            engine.transactions.insert(&row)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    // Written next to the target and renamed over it, so a crash part way
+    // through never leaves a truncated snapshot behind
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,498 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use rust_decimal::Decimal;
+
+use crate::account_store::AccountStore;
+use crate::batch::{Backend, Batch};
+use crate::disk_store::storage_error;
+use crate::error::EngineError;
+use crate::lifecycle::TxState;
+use crate::model::{AccountRecord, OutputRecord, TransactionRow, TransactionType};
+use crate::store::{StoredTx, TransactionStore};
+
+// Applied in order, PRAGMA user_version records how many have run. Only ever
+// append to this list, a database that was migrated before must stay valid
+const MIGRATIONS: &[&str] = &[
+    // 1: amounts are TEXT so no precision is lost, accounts hold the same
+    // values the account report prints
+    "CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL
+    );
+    CREATE TABLE transactions (
+        tx INTEGER PRIMARY KEY,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        state TEXT NOT NULL
+    );
+    CREATE TABLE dispute_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        tx INTEGER NOT NULL,
+        client INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        amount TEXT NOT NULL
+    );
+    CREATE INDEX dispute_events_by_tx ON dispute_events (tx);",
+];
+
+// Schema version a database is left at once opened
+pub const SQLITE_SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+fn migrate(conn: &mut Connection) -> Result<(), EngineError> {
+    let version: u32 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(storage_error)?;
+    if version > SQLITE_SCHEMA_VERSION {
+        return Err(storage_error(format!(
+            "database schema version: {} is newer than this build ({})",
+            version, SQLITE_SCHEMA_VERSION
+        )));
+    }
+
+    // A savepoint, so a read-only store can migrate inside the transaction
+    // it never commits
+    let migration = conn.savepoint().map_err(storage_error)?;
+    for (applied, sql) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration.execute_batch(sql).map_err(storage_error)?;
+        migration
+            .pragma_update(None, "user_version", applied as u32 + 1)
+            .map_err(storage_error)?;
+    }
+    migration.commit().map_err(storage_error)
+}
+
+fn decimal(row: &Row, index: usize) -> Result<Decimal, EngineError> {
+    let text: String = row.get(index).map_err(storage_error)?;
+    text.parse()
+        .map_err(|_| storage_error(format!("stored amount: {} is not a decimal", text)))
+}
+
+fn tx_type(text: &str) -> Option<TransactionType> {
+    match text {
+        "deposit" => Some(TransactionType::Deposit),
+        "withdrawal" => Some(TransactionType::Withdrawal),
+        "dispute" => Some(TransactionType::Dispute),
+        "resolve" => Some(TransactionType::Resolve),
+        "chargeback" => Some(TransactionType::Chargeback),
+        _ => None,
+    }
+}
+
+fn tx_state(text: &str) -> Option<TxState> {
+    match text {
+        "processed" => Some(TxState::Processed),
+        "disputed" => Some(TxState::Disputed),
+        "resolved" => Some(TxState::Resolved),
+        "charged_back" => Some(TxState::ChargedBack),
+        _ => None,
+    }
+}
+
+// The dispute_events row a state change is recorded as
+fn event(state: TxState) -> Option<&'static str> {
+    match state {
+        TxState::Disputed => Some("dispute"),
+        TxState::Resolved => Some("resolve"),
+        TxState::ChargedBack => Some("chargeback"),
+        TxState::Processed => None,
+    }
+}
+
+fn account_from(row: &Row) -> Result<(u16, AccountRecord), EngineError> {
+    let client = row.get(0).map_err(storage_error)?;
+    let account = AccountRecord {
+        available: decimal(row, 1)?,
+        held: decimal(row, 2)?,
+        locked: row.get(3).map_err(storage_error)?,
+    };
+    Ok((client, account))
+}
+
+fn stored_from(row: &Row) -> Result<(u32, StoredTx), EngineError> {
+    let tx = row.get(0).map_err(storage_error)?;
+    let corrupt = || storage_error(format!("stored transaction: {} is corrupt", tx));
+    let type_text: String = row.get(1).map_err(storage_error)?;
+    let state_text: String = row.get(4).map_err(storage_error)?;
+    let stored = StoredTx {
+        tx_type: tx_type(&type_text).ok_or_else(corrupt)?,
+        client: row.get(2).map_err(storage_error)?,
+        amount: decimal(row, 3)?,
+        state: tx_state(&state_text).ok_or_else(corrupt)?,
+    };
+    Ok((tx, stored))
+}
+
+// The connection both halves write through. Rows are kept apart with a
+// savepoint each, inside the one transaction that is committed per batch
+struct Sqlite {
+    conn: Connection,
+    transactions: usize,
+    // As it stood when the open row began
+    row_transactions: usize,
+    // False for open_in_memory()
+    durable: bool,
+}
+
+impl Sqlite {
+    fn begin(&mut self) -> Result<(), EngineError> {
+        if self.conn.is_autocommit() {
+            self.conn.execute_batch("BEGIN").map_err(storage_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for Sqlite {
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.begin()?;
+        self.row_transactions = self.transactions;
+        self.conn
+            .execute_batch("SAVEPOINT row")
+            .map_err(storage_error)
+    }
+
+    fn keep_row(&mut self) -> Result<(), EngineError> {
+        self.conn
+            .execute_batch("RELEASE row")
+            .map_err(storage_error)
+    }
+
+    fn undo_row(&mut self) -> Result<(), EngineError> {
+        self.conn
+            .execute_batch("ROLLBACK TO row; RELEASE row")
+            .map_err(storage_error)?;
+        self.transactions = self.row_transactions;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), EngineError> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("COMMIT").map_err(storage_error)?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        if !self.conn.is_autocommit() {
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+// A SQLite database (a local file, no server) holding accounts, stored
+// transactions and every dispute, resolve and chargeback applied to them,
+// for querying engine state with SQL. accounts() and transactions() hand out
+// the two stores the Engine takes, both writing to this one database in
+// batches, as with DiskStore
+#[derive(Clone)]
+pub struct SqliteStore {
+    shared: Rc<RefCell<Batch<Sqlite>>>,
+}
+
+impl fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shared = self.shared.borrow();
+        f.debug_struct("SqliteStore")
+            .field("pending", &shared.pending())
+            .field("transactions", &shared.backend.transactions)
+            .finish()
+    }
+}
+
+impl SqliteStore {
+    // Opens the database at `path`, creating it or bringing its schema up to
+    // date first. A schema newer than this build is refused
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        let conn = Connection::open(path).map_err(storage_error)?;
+        SqliteStore::with_connection(conn, true, false)
+    }
+
+    // Opens an existing database for a run that must leave it as it was.
+    // It takes writes like any other, migrations included, but they are
+    // never committed
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        let flags = OpenFlags::default().difference(OpenFlags::SQLITE_OPEN_CREATE);
+        let conn = Connection::open_with_flags(path, flags).map_err(storage_error)?;
+        conn.execute_batch("BEGIN").map_err(storage_error)?;
+        SqliteStore::with_connection(conn, true, true)
+    }
+
+    // Nothing is kept once the store is dropped
+    pub fn open_in_memory() -> Result<Self, EngineError> {
+        let conn = Connection::open_in_memory().map_err(storage_error)?;
+        SqliteStore::with_connection(conn, false, false)
+    }
+
+    fn with_connection(
+        mut conn: Connection,
+        durable: bool,
+        read_only: bool,
+    ) -> Result<Self, EngineError> {
+        migrate(&mut conn)?;
+        let transactions: u64 = conn
+            .query_row("SELECT COUNT(*) FROM transactions", [], |row| row.get(0))
+            .map_err(storage_error)?;
+        Ok(SqliteStore {
+            shared: Rc::new(RefCell::new(Batch::new(
+                Sqlite {
+                    conn,
+                    transactions: transactions as usize,
+                    row_transactions: 0,
+                    durable,
+                },
+                read_only,
+            ))),
+        })
+    }
+
+    pub fn accounts(&self) -> SqliteAccounts {
+        SqliteAccounts {
+            store: self.clone(),
+        }
+    }
+
+    pub fn transactions(&self) -> SqliteTransactions {
+        SqliteTransactions {
+            store: self.clone(),
+        }
+    }
+
+    pub fn schema_version(&self) -> Result<u32, EngineError> {
+        self.shared
+            .borrow()
+            .backend
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(storage_error)
+    }
+
+    // Commits everything written so far
+    pub fn flush(&self) -> Result<(), EngineError> {
+        self.shared.borrow_mut().flush()
+    }
+}
+
+// The accounts table as an AccountStore
+#[derive(Debug, Clone)]
+pub struct SqliteAccounts {
+    store: SqliteStore,
+}
+
+impl AccountStore for SqliteAccounts {
+    fn get(&self, client: u16) -> Result<Option<AccountRecord>, EngineError> {
+        let shared = self.store.shared.borrow();
+        let mut statement = shared
+            .backend
+            .conn
+            .prepare_cached(
+                "SELECT client, available, held, locked FROM accounts WHERE client = ?1",
+            )
+            .map_err(storage_error)?;
+        let mut rows = statement.query([client]).map_err(storage_error)?;
+        match rows.next().map_err(storage_error)? {
+            Some(row) => Ok(Some(account_from(row)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    fn upsert(&mut self, client: u16, account: AccountRecord) -> Result<(), EngineError> {
+        let record = OutputRecord::new(client, &account);
+        self.store.shared.borrow_mut().write(|db| {
+            db.begin()?;
+            db.conn
+                .prepare_cached(
+                    "INSERT INTO accounts (client, available, held, total, locked)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (client) DO UPDATE SET available = excluded.available,
+                        held = excluded.held, total = excluded.total, locked = excluded.locked",
+                )
+                .map_err(storage_error)?
+                .execute(params![
+                    client,
+                    record.available.to_string(),
+                    record.held.to_string(),
+                    record.total.to_string(),
+                    record.locked,
+                ])
+                .map_err(storage_error)?;
+            Ok(())
+        })
+    }
+
+    // In client order
+    fn for_each(&self, f: &mut dyn FnMut(u16, AccountRecord)) -> Result<(), EngineError> {
+        let shared = self.store.shared.borrow();
+        let mut statement = shared
+            .backend
+            .conn
+            .prepare_cached("SELECT client, available, held, locked FROM accounts ORDER BY client")
+            .map_err(storage_error)?;
+        let mut rows = statement.query([]).map_err(storage_error)?;
+        while let Some(row) = rows.next().map_err(storage_error)? {
+            let (client, account) = account_from(row)?;
+            f(client, account);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        self.store.flush()
+    }
+
+    fn is_durable(&self) -> bool {
+        self.store.shared.borrow().backend.durable
+    }
+
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().begin_row()
+    }
+
+    fn end_row(&mut self, applied: bool) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().end_row(applied)
+    }
+}
+
+// The transactions table as a TransactionStore. Every state change is also
+// appended to dispute_events
+#[derive(Debug, Clone)]
+pub struct SqliteTransactions {
+    store: SqliteStore,
+}
+
+impl TransactionStore for SqliteTransactions {
+    fn get(&self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        let shared = self.store.shared.borrow();
+        let mut statement = shared
+            .backend
+            .conn
+            .prepare_cached(
+                "SELECT tx, type, client, amount, state FROM transactions WHERE tx = ?1",
+            )
+            .map_err(storage_error)?;
+        let mut rows = statement.query([tx]).map_err(storage_error)?;
+        match rows.next().map_err(storage_error)? {
+            Some(row) => Ok(Some(stored_from(row)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, row: &TransactionRow) -> Result<(), EngineError> {
+        let stored = StoredTx::of(row)?;
+        self.store.shared.borrow_mut().write(|db| {
+            db.begin()?;
+            let replaced = db
+                .conn
+                .prepare_cached("SELECT 1 FROM transactions WHERE tx = ?1")
+                .map_err(storage_error)?
+                .query_row([row.tx], |_| Ok(()))
+                .optional()
+                .map_err(storage_error)?
+                .is_some();
+            db.conn
+                .prepare_cached(
+                    "INSERT INTO transactions (tx, type, client, amount, state)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (tx) DO UPDATE SET type = excluded.type,
+                        client = excluded.client, amount = excluded.amount, state = excluded.state",
+                )
+                .map_err(storage_error)?
+                .execute(params![
+                    row.tx,
+                    stored.tx_type.as_str(),
+                    stored.client,
+                    stored.amount.to_string(),
+                    stored.state.as_str(),
+                ])
+                .map_err(storage_error)?;
+            if !replaced {
+                db.transactions += 1;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_state(&mut self, tx: u32, state: TxState) -> Result<(), EngineError> {
+        let stored = self.get(tx)?.ok_or(EngineError::UnknownTx { tx })?;
+        self.store.shared.borrow_mut().write(|db| {
+            db.begin()?;
+            db.conn
+                .prepare_cached("UPDATE transactions SET state = ?2 WHERE tx = ?1")
+                .map_err(storage_error)?
+                .execute(params![tx, state.as_str()])
+                .map_err(storage_error)?;
+            if let Some(event) = event(state) {
+                db.conn
+                    .prepare_cached(
+                        "INSERT INTO dispute_events (tx, client, event, amount)
+                         VALUES (?1, ?2, ?3, ?4)",
+                    )
+                    .map_err(storage_error)?
+                    .execute(params![tx, stored.client, event, stored.amount.to_string()])
+                    .map_err(storage_error)?;
+            }
+            Ok(())
+        })
+    }
+
+    // The transaction's dispute_events are history and stay
+    fn remove(&mut self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        let Some(stored) = self.get(tx)? else {
+            return Ok(None);
+        };
+        self.store.shared.borrow_mut().write(|db| {
+            db.begin()?;
+            db.conn
+                .prepare_cached("DELETE FROM transactions WHERE tx = ?1")
+                .map_err(storage_error)?
+                .execute([tx])
+                .map_err(storage_error)?;
+            db.transactions -= 1;
+            Ok(())
+        })?;
+        Ok(Some(stored))
+    }
+
+    fn len(&self) -> usize {
+        self.store.shared.borrow().backend.transactions
+    }
+
+    // In tx order
+    fn for_each(&self, f: &mut dyn FnMut(u32, StoredTx)) -> Result<(), EngineError> {
+        let shared = self.store.shared.borrow();
+        let mut statement = shared
+            .backend
+            .conn
+            .prepare_cached("SELECT tx, type, client, amount, state FROM transactions ORDER BY tx")
+            .map_err(storage_error)?;
+        let mut rows = statement.query([]).map_err(storage_error)?;
+        while let Some(row) = rows.next().map_err(storage_error)? {
+            let (tx, stored) = stored_from(row)?;
+            f(tx, stored);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        self.store.flush()
+    }
+
+    fn is_durable(&self) -> bool {
+        self.store.shared.borrow().backend.durable
+    }
+
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().begin_row()
+    }
+
+    fn end_row(&mut self, applied: bool) -> Result<(), EngineError> {
+        self.store.shared.borrow_mut().end_row(applied)
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::error::EngineError;
+use crate::lifecycle::TxState;
+use crate::model::{TransactionRow, TransactionType};
+
+// The dense store may grow to this many slots however few are filled. Past
+// that, at least half of them must be, or the ids are judged too sparse for it
+const DENSE_SLACK: usize = 1 << 16;
+
+// Where the Engine keeps every transaction it may still be asked to dispute.
+// Lookups hand back copies and changes are written back explicitly, so an
+// implementation doesn't have to keep anything resident
+pub trait TransactionStore: fmt::Debug {
+    fn get(&self, tx: u32) -> Result<Option<StoredTx>, EngineError>;
+
+    // Replaces whatever was stored under the row's tx ID. Only rows with an
+    // amount can be disputed, so only those can be stored
+    fn insert(&mut self, row: &TransactionRow) -> Result<(), EngineError>;
+
+    // Fails with UnknownTx when nothing is stored under `tx`
+    fn set_state(&mut self, tx: u32, state: TxState) -> Result<(), EngineError>;
+
+    // Hands back whatever was stored under `tx`
+    fn remove(&mut self, tx: u32) -> Result<Option<StoredTx>, EngineError>;
+
+    fn len(&self) -> usize;
+
+    // In tx order where the store keeps one
+    fn for_each(&self, f: &mut dyn FnMut(u32, StoredTx)) -> Result<(), EngineError>;
+
+    // Makes everything written so far durable, for stores that batch writes
+    fn flush(&mut self) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    // True for stores kept on disk, which a Snapshot refers to rather than copies
+    fn is_durable(&self) -> bool {
+        false
+    }
+
+    // As with AccountStore, around each row's writes
+    fn begin_row(&mut self) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    fn end_row(&mut self, _applied: bool) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    fn contains(&self, tx: u32) -> Result<bool, EngineError> {
+        Ok(self.get(tx)?.is_some())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// What is kept of a deposit (or a disputable withdrawal) once it has been
+// applied. 20 bytes, where a whole TransactionRow is 28 before the map's key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoredTx {
+    pub(crate) amount: Decimal,
+    pub(crate) client: u16,
+    pub(crate) tx_type: TransactionType,
+    pub(crate) state: TxState,
+}
+
+impl StoredTx {
+    pub fn of(row: &TransactionRow) -> Result<Self, EngineError> {
+        Ok(StoredTx {
+            amount: row
+                .amount
+                .ok_or(EngineError::MissingAmount { tx: row.tx })?,
+            client: row.client,
+            tx_type: row.tx_type,
+            state: row.state,
+        })
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx_type(&self) -> TransactionType {
+        self.tx_type
+    }
+
+    pub fn state(&self) -> TxState {
+        self.state
+    }
+
+    // Back to the row it was stored from, for callers outside the Engine
+    pub fn row(&self, tx: u32) -> TransactionRow {
+        let mut row = TransactionRow::new(self.tx_type, self.client, tx, Some(self.amount));
+        row.state = self.state;
+        row
+    }
+}
+
+#[derive(Debug)]
+enum Slots {
+    // Indexed by tx - base. Option<StoredTx> is no bigger than StoredTx, the
+    // enums inside it have room for the None
+    Dense {
+        base: u32,
+        slots: Vec<Option<StoredTx>>,
+    },
+    // Once ids turn out not to be (near) sequential, for good
+    Sparse(HashMap<u32, StoredTx>),
+}
+
+// The in-memory TransactionStore. Ids are usually handed out in order, so they start out in a vec with no
+// per entry key or hashing overhead, and move to a map if a gap shows up
+#[derive(Debug)]
+pub struct TxStore {
+    slots: Slots,
+    len: usize,
+}
+
+impl Default for TxStore {
+    fn default() -> Self {
+        TxStore {
+            slots: Slots::Dense {
+                base: 0,
+                slots: Vec::new(),
+            },
+            len: 0,
+        }
+    }
+}
+
+impl TxStore {
+    pub fn is_dense(&self) -> bool {
+        matches!(self.slots, Slots::Dense { .. })
+    }
+
+    fn get_ref(&self, tx: u32) -> Option<&StoredTx> {
+        match &self.slots {
+            Slots::Dense { base, slots } => {
+                let index = tx.checked_sub(*base)? as usize;
+                slots.get(index)?.as_ref()
+            }
+            Slots::Sparse(map) => map.get(&tx),
+        }
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<&mut StoredTx> {
+        match &mut self.slots {
+            Slots::Dense { base, slots } => {
+                let index = tx.checked_sub(*base)? as usize;
+                slots.get_mut(index)?.as_mut()
+            }
+            Slots::Sparse(map) => map.get_mut(&tx),
+        }
+    }
+
+    fn insert_sparse(&mut self, tx: u32, stored: StoredTx) {
+        if let Slots::Sparse(map) = &mut self.slots {
+            if map.insert(tx, stored).is_none() {
+                self.len += 1;
+            }
+        }
+    }
+
+    fn make_sparse(&mut self) {
+        if let Slots::Dense { base, slots } = &mut self.slots {
+            let mut map = HashMap::with_capacity(self.len);
+            for (index, stored) in slots.drain(..).enumerate() {
+                if let Some(stored) = stored {
+                    map.insert(*base + index as u32, stored);
+                }
+            }
+            self.slots = Slots::Sparse(map);
+        }
+    }
+}
+
+impl TransactionStore for TxStore {
+    fn get(&self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        Ok(self.get_ref(tx).copied())
+    }
+
+    fn insert(&mut self, row: &TransactionRow) -> Result<(), EngineError> {
+        let stored = StoredTx::of(row)?;
+
+        let Slots::Dense { base, slots } = &mut self.slots else {
+            self.insert_sparse(row.tx, stored);
+            return Ok(());
+        };
+        if slots.is_empty() {
+            *base = row.tx;
+        }
+        let filled = self.len + 1;
+        let index = match row.tx.checked_sub(*base) {
+            Some(index)
+                if (index as usize) < slots.len().max(DENSE_SLACK)
+                    || filled * 2 > index as usize =>
+            {
+                index as usize
+            }
+            _ => {
+                self.make_sparse();
+                self.insert_sparse(row.tx, stored);
+                return Ok(());
+            }
+        };
+        if index >= slots.len() {
+            slots.resize(index + 1, None);
+        }
+        if slots[index].replace(stored).is_none() {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, tx: u32, state: TxState) -> Result<(), EngineError> {
+        let stored = self.get_mut(tx).ok_or(EngineError::UnknownTx { tx })?;
+        stored.state = state;
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: u32) -> Result<Option<StoredTx>, EngineError> {
+        let removed = match &mut self.slots {
+            Slots::Dense { base, slots } => tx
+                .checked_sub(*base)
+                .and_then(|index| slots.get_mut(index as usize))
+                .and_then(Option::take),
+            Slots::Sparse(map) => map.remove(&tx),
+        };
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        Ok(removed)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    // In tx order while dense, in no particular order once sparse
+    fn for_each(&self, f: &mut dyn FnMut(u32, StoredTx)) -> Result<(), EngineError> {
+        match &self.slots {
+            Slots::Dense { base, slots } => {
+                for (index, stored) in slots.iter().enumerate() {
+                    if let Some(stored) = stored {
+                        f(*base + index as u32, *stored);
+                    }
+                }
+            }
+            Slots::Sparse(map) => map.iter().for_each(|(tx, stored)| f(*tx, *stored)),
+        }
+        Ok(())
+    }
+}
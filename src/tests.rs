@@ -2,9 +2,12 @@
 // With some tweaking by a human
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::*;
+    use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
+    use std::collections::HashMap;
 
     // Helper to create a deposit transaction
     fn make_deposit(client: u16, tx: u32, amount: Decimal) -> TransactionRow {
@@ -13,7 +16,7 @@ mod tests {
             client,
             tx,
             amount: Some(amount),
-            disputed: false,
+            state: TxState::Processed,
         }
     }
 
@@ -24,7 +27,7 @@ mod tests {
             client,
             tx,
             amount: Some(amount),
-            disputed: false,
+            state: TxState::Processed,
         }
     }
 
@@ -35,7 +38,7 @@ mod tests {
             client,
             tx,
             amount: None,
-            disputed: false,
+            state: TxState::Processed,
         }
     }
 
@@ -46,7 +49,7 @@ mod tests {
             client,
             tx,
             amount: None,
-            disputed: false,
+            state: TxState::Processed,
         }
     }
 
@@ -57,18 +60,23 @@ mod tests {
             client,
             tx,
             amount: None,
-            disputed: false,
+            state: TxState::Processed,
         }
     }
 
+    // Accounts as the Engine holds them by default
+    fn new_accounts() -> Box<dyn AccountStore> {
+        Box::new(HashMap::<u16, AccountRecord>::new())
+    }
+
     // =========================================================================
     // Deposit Tests
     // =========================================================================
 
     #[test]
     fn deposit_creates_account_and_adds_funds() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         let result = handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -77,14 +85,14 @@ mod tests {
         );
 
         assert!(result.is_ok());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100));
-        assert!(transactions.contains_key(&1));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100));
+        assert!(transactions.contains(1).unwrap());
     }
 
     #[test]
     fn deposit_adds_to_existing_account() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -99,13 +107,13 @@ mod tests {
         )
         .unwrap();
 
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(150));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(150));
     }
 
     #[test]
     fn deposit_rejects_duplicate_tx_id() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -120,13 +128,13 @@ mod tests {
         );
 
         assert!(result.is_err());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100)); // unchanged
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100)); // unchanged
     }
 
     #[test]
     fn deposit_rejects_zero_amount() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         let result = handle_deposit(
             make_deposit(1, 1, dec!(0)),
@@ -135,13 +143,13 @@ mod tests {
         );
 
         assert!(result.is_err());
-        assert!(!accounts.contains_key(&1));
+        assert!(accounts.get(1).unwrap().is_none());
     }
 
     #[test]
     fn deposit_rejects_negative_amount() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         let result = handle_deposit(
             make_deposit(1, 1, dec!(-50)),
@@ -150,21 +158,23 @@ mod tests {
         );
 
         assert!(result.is_err());
-        assert!(!accounts.contains_key(&1));
+        assert!(accounts.get(1).unwrap().is_none());
     }
 
     #[test]
     fn deposit_rejects_locked_account() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: true,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: true,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
 
         let result = handle_deposit(
             make_deposit(1, 1, dec!(50)),
@@ -173,7 +183,7 @@ mod tests {
         );
 
         assert!(result.is_err());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100)); // unchanged
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100)); // unchanged
     }
 
     // =========================================================================
@@ -182,45 +192,49 @@ mod tests {
 
     #[test]
     fn withdrawal_subtracts_funds() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
 
         let tx = make_withdrawal(1, 1, dec!(30));
         let result = handle_withdrawal(&tx, &mut accounts);
 
         assert!(result.is_ok());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(70));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(70));
     }
 
     #[test]
     fn withdrawal_rejects_insufficient_funds() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(50),
-                held: dec!(0),
-                locked: false,
-            },
-        );
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(50),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
 
         let tx = make_withdrawal(1, 1, dec!(100));
         let result = handle_withdrawal(&tx, &mut accounts);
 
         assert!(result.is_err());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(50)); // unchanged
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(50)); // unchanged
     }
 
     #[test]
     fn withdrawal_rejects_nonexistent_account() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
+        let mut accounts = new_accounts();
 
         let tx = make_withdrawal(1, 1, dec!(50));
         let result = handle_withdrawal(&tx, &mut accounts);
@@ -230,34 +244,38 @@ mod tests {
 
     #[test]
     fn withdrawal_rejects_locked_account() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: true,
-            },
-        );
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: true,
+                },
+            )
+            .unwrap();
 
         let tx = make_withdrawal(1, 1, dec!(30));
         let result = handle_withdrawal(&tx, &mut accounts);
 
         assert!(result.is_err());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100)); // unchanged
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100)); // unchanged
     }
 
     #[test]
     fn withdrawal_rejects_zero_amount() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
 
         let tx = make_withdrawal(1, 1, dec!(0));
         let result = handle_withdrawal(&tx, &mut accounts);
@@ -271,77 +289,106 @@ mod tests {
 
     #[test]
     fn dispute_moves_funds_to_held() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
-        transactions.insert(1, make_deposit(1, 1, dec!(100)));
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
+        transactions.insert(&make_deposit(1, 1, dec!(100))).unwrap();
 
         let tx = make_dispute(1, 1);
-        let result = handle_dispute(&tx, &mut accounts, &mut transactions);
+        let result = handle_dispute(
+            &tx,
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        );
 
         assert!(result.is_ok());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(0));
-        assert_eq!(accounts.get(&1).unwrap().held, dec!(100));
-        assert!(transactions.get(&1).unwrap().disputed);
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(0));
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, dec!(100));
+        assert_eq!(
+            transactions.get(1).unwrap().unwrap().state,
+            TxState::Disputed
+        );
     }
 
     #[test]
     fn dispute_rejects_nonexistent_transaction() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         let tx = make_dispute(1, 999);
-        let result = handle_dispute(&tx, &mut accounts, &mut transactions);
+        let result = handle_dispute(
+            &tx,
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        );
 
         assert!(result.is_err());
     }
 
     #[test]
     fn dispute_rejects_wrong_client() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
-        transactions.insert(1, make_deposit(1, 1, dec!(100)));
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
+        transactions.insert(&make_deposit(1, 1, dec!(100))).unwrap();
 
         // Client 2 trying to dispute client 1's transaction
         let tx = make_dispute(2, 1);
-        let result = handle_dispute(&tx, &mut accounts, &mut transactions);
+        let result = handle_dispute(
+            &tx,
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        );
 
         assert!(result.is_err());
     }
 
     #[test]
     fn dispute_rejects_already_disputed() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
         let mut deposit = make_deposit(1, 1, dec!(100));
-        deposit.disputed = true;
-        transactions.insert(1, deposit);
+        deposit.state = TxState::Disputed;
+        transactions.insert(&deposit).unwrap();
 
         let tx = make_dispute(1, 1);
-        let result = handle_dispute(&tx, &mut accounts, &mut transactions);
+        let result = handle_dispute(
+            &tx,
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        );
 
         assert!(result.is_err());
     }
@@ -352,42 +399,49 @@ mod tests {
 
     #[test]
     fn resolve_moves_funds_back_to_available() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(0),
-                held: dec!(100),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(0),
+                    held: dec!(100),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
         let mut deposit = make_deposit(1, 1, dec!(100));
-        deposit.disputed = true;
-        transactions.insert(1, deposit);
+        deposit.state = TxState::Disputed;
+        transactions.insert(&deposit).unwrap();
 
         let tx = make_resolve(1, 1);
         let result = handle_resolve(&tx, &mut accounts, &mut transactions);
 
         assert!(result.is_ok());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100));
-        assert_eq!(accounts.get(&1).unwrap().held, dec!(0));
-        assert!(!transactions.get(&1).unwrap().disputed);
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100));
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, dec!(0));
+        assert_eq!(
+            transactions.get(1).unwrap().unwrap().state,
+            TxState::Resolved
+        );
     }
 
     #[test]
     fn resolve_rejects_not_disputed() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
-        transactions.insert(1, make_deposit(1, 1, dec!(100)));
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
+        transactions.insert(&make_deposit(1, 1, dec!(100))).unwrap();
 
         let tx = make_resolve(1, 1);
         let result = handle_resolve(&tx, &mut accounts, &mut transactions);
@@ -397,19 +451,21 @@ mod tests {
 
     #[test]
     fn resolve_rejects_wrong_client() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(0),
-                held: dec!(100),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(0),
+                    held: dec!(100),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
         let mut deposit = make_deposit(1, 1, dec!(100));
-        deposit.disputed = true;
-        transactions.insert(1, deposit);
+        deposit.state = TxState::Disputed;
+        transactions.insert(&deposit).unwrap();
 
         // Client 2 trying to resolve client 1's dispute
         let tx = make_resolve(2, 1);
@@ -424,66 +480,75 @@ mod tests {
 
     #[test]
     fn chargeback_removes_held_and_locks_account() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(50),
-                held: dec!(100),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(50),
+                    held: dec!(100),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
         let mut deposit = make_deposit(1, 1, dec!(100));
-        deposit.disputed = true;
-        transactions.insert(1, deposit);
+        deposit.state = TxState::Disputed;
+        transactions.insert(&deposit).unwrap();
 
         let tx = make_chargeback(1, 1);
         let result = handle_chargeback(&tx, &mut accounts, &mut transactions);
 
         assert!(result.is_ok());
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(50)); // unchanged
-        assert_eq!(accounts.get(&1).unwrap().held, dec!(0));
-        assert!(accounts.get(&1).unwrap().locked);
-        assert!(!transactions.get(&1).unwrap().disputed);
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(50)); // unchanged
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, dec!(0));
+        assert!(accounts.get(1).unwrap().unwrap().locked);
+        assert_eq!(
+            transactions.get(1).unwrap().unwrap().state,
+            TxState::ChargedBack
+        );
     }
 
     #[test]
     fn chargeback_rejects_not_disputed() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
-        transactions.insert(1, make_deposit(1, 1, dec!(100)));
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
+        transactions.insert(&make_deposit(1, 1, dec!(100))).unwrap();
 
         let tx = make_chargeback(1, 1);
         let result = handle_chargeback(&tx, &mut accounts, &mut transactions);
 
         assert!(result.is_err());
-        assert!(!accounts.get(&1).unwrap().locked);
+        assert!(!accounts.get(1).unwrap().unwrap().locked);
     }
 
     #[test]
     fn chargeback_rejects_wrong_client() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(0),
-                held: dec!(100),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(0),
+                    held: dec!(100),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
         let mut deposit = make_deposit(1, 1, dec!(100));
-        deposit.disputed = true;
-        transactions.insert(1, deposit);
+        deposit.state = TxState::Disputed;
+        transactions.insert(&deposit).unwrap();
 
         // Client 2 trying to chargeback client 1's transaction
         let tx = make_chargeback(2, 1);
@@ -498,8 +563,8 @@ mod tests {
 
     #[test]
     fn dispute_then_resolve_restores_original_state() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -508,23 +573,35 @@ mod tests {
         )
         .unwrap();
 
-        let original_available = accounts.get(&1).unwrap().available;
-        let original_held = accounts.get(&1).unwrap().held;
+        let original_available = accounts.get(1).unwrap().unwrap().available;
+        let original_held = accounts.get(1).unwrap().unwrap().held;
 
         // Dispute then resolve
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
         handle_resolve(&make_resolve(1, 1), &mut accounts, &mut transactions).unwrap();
 
         // Should be back to original
-        assert_eq!(accounts.get(&1).unwrap().available, original_available);
-        assert_eq!(accounts.get(&1).unwrap().held, original_held);
-        assert!(!transactions.get(&1).unwrap().disputed);
+        assert_eq!(
+            accounts.get(1).unwrap().unwrap().available,
+            original_available
+        );
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, original_held);
+        assert_eq!(
+            transactions.get(1).unwrap().unwrap().state,
+            TxState::Resolved
+        );
     }
 
     #[test]
     fn total_remains_constant_through_dispute() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -533,11 +610,19 @@ mod tests {
         )
         .unwrap();
 
-        let total_before = accounts.get(&1).unwrap().available + accounts.get(&1).unwrap().held;
+        let total_before =
+            accounts.get(1).unwrap().unwrap().available + accounts.get(1).unwrap().unwrap().held;
 
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
 
-        let total_after = accounts.get(&1).unwrap().available + accounts.get(&1).unwrap().held;
+        let total_after =
+            accounts.get(1).unwrap().unwrap().available + accounts.get(1).unwrap().unwrap().held;
 
         assert_eq!(
             total_before, total_after,
@@ -547,8 +632,8 @@ mod tests {
 
     #[test]
     fn total_remains_constant_through_resolve() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -556,13 +641,21 @@ mod tests {
             &mut transactions,
         )
         .unwrap();
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
 
-        let total_before = accounts.get(&1).unwrap().available + accounts.get(&1).unwrap().held;
+        let total_before =
+            accounts.get(1).unwrap().unwrap().available + accounts.get(1).unwrap().unwrap().held;
 
         handle_resolve(&make_resolve(1, 1), &mut accounts, &mut transactions).unwrap();
 
-        let total_after = accounts.get(&1).unwrap().available + accounts.get(&1).unwrap().held;
+        let total_after =
+            accounts.get(1).unwrap().unwrap().available + accounts.get(1).unwrap().unwrap().held;
 
         assert_eq!(
             total_before, total_after,
@@ -572,8 +665,8 @@ mod tests {
 
     #[test]
     fn chargeback_reduces_total_by_disputed_amount() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -588,13 +681,21 @@ mod tests {
         )
         .unwrap();
 
-        let total_before = accounts.get(&1).unwrap().available + accounts.get(&1).unwrap().held;
+        let total_before =
+            accounts.get(1).unwrap().unwrap().available + accounts.get(1).unwrap().unwrap().held;
         assert_eq!(total_before, dec!(150));
 
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
         handle_chargeback(&make_chargeback(1, 1), &mut accounts, &mut transactions).unwrap();
 
-        let total_after = accounts.get(&1).unwrap().available + accounts.get(&1).unwrap().held;
+        let total_after =
+            accounts.get(1).unwrap().unwrap().available + accounts.get(1).unwrap().unwrap().held;
 
         assert_eq!(
             total_after,
@@ -605,8 +706,8 @@ mod tests {
 
     #[test]
     fn multiple_deposits_withdrawals_balance_correctly() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -630,13 +731,13 @@ mod tests {
         handle_withdrawal(&make_withdrawal(1, 5, dec!(40)), &mut accounts).unwrap();
 
         // 100 + 50 - 30 + 20 - 40 = 100
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100));
     }
 
     #[test]
     fn dispute_resolve_cycle_can_repeat() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -646,22 +747,34 @@ mod tests {
         .unwrap();
 
         // First cycle
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
-        assert_eq!(accounts.get(&1).unwrap().held, dec!(100));
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, dec!(100));
         handle_resolve(&make_resolve(1, 1), &mut accounts, &mut transactions).unwrap();
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100));
 
         // Second cycle - should work again
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
-        assert_eq!(accounts.get(&1).unwrap().held, dec!(100));
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, dec!(100));
         handle_resolve(&make_resolve(1, 1), &mut accounts, &mut transactions).unwrap();
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100));
     }
 
     #[test]
     fn failed_operations_dont_change_state() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -670,8 +783,8 @@ mod tests {
         )
         .unwrap();
 
-        let available_before = accounts.get(&1).unwrap().available;
-        let held_before = accounts.get(&1).unwrap().held;
+        let available_before = accounts.get(1).unwrap().unwrap().available;
+        let held_before = accounts.get(1).unwrap().unwrap().held;
 
         // These should all fail
         let _ = handle_withdrawal(&make_withdrawal(1, 2, dec!(200)), &mut accounts); // insufficient
@@ -679,14 +792,17 @@ mod tests {
         let _ = handle_chargeback(&make_chargeback(1, 1), &mut accounts, &mut transactions); // not disputed
 
         // State should be unchanged
-        assert_eq!(accounts.get(&1).unwrap().available, available_before);
-        assert_eq!(accounts.get(&1).unwrap().held, held_before);
+        assert_eq!(
+            accounts.get(1).unwrap().unwrap().available,
+            available_before
+        );
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, held_before);
     }
 
     #[test]
     fn locked_account_blocks_all_deposits_and_withdrawals() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100)),
@@ -694,11 +810,17 @@ mod tests {
             &mut transactions,
         )
         .unwrap();
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
         handle_chargeback(&make_chargeback(1, 1), &mut accounts, &mut transactions).unwrap();
 
-        assert!(accounts.get(&1).unwrap().locked);
-        let available_after_lock = accounts.get(&1).unwrap().available;
+        assert!(accounts.get(1).unwrap().unwrap().locked);
+        let available_after_lock = accounts.get(1).unwrap().unwrap().available;
 
         // Both should fail
         let deposit_result = handle_deposit(
@@ -710,7 +832,10 @@ mod tests {
 
         assert!(deposit_result.is_err());
         assert!(withdrawal_result.is_err());
-        assert_eq!(accounts.get(&1).unwrap().available, available_after_lock);
+        assert_eq!(
+            accounts.get(1).unwrap().unwrap().available,
+            available_after_lock
+        );
     }
 
     // =========================================================================
@@ -719,8 +844,8 @@ mod tests {
 
     #[test]
     fn deposit_preserves_four_decimal_places() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(100.1234)),
@@ -729,13 +854,13 @@ mod tests {
         )
         .unwrap();
 
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100.1234));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100.1234));
     }
 
     #[test]
     fn multiple_deposits_preserve_precision() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         handle_deposit(
             make_deposit(1, 1, dec!(0.0001)),
@@ -756,45 +881,57 @@ mod tests {
         )
         .unwrap();
 
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(0.0003));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(0.0003));
     }
 
     #[test]
     fn withdrawal_preserves_precision() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(100.5678),
-                held: dec!(0),
-                locked: false,
-            },
-        );
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(100.5678),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
 
         let tx = make_withdrawal(1, 1, dec!(0.0008));
         handle_withdrawal(&tx, &mut accounts).unwrap();
 
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(100.567));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(100.567));
     }
 
     #[test]
     fn dispute_preserves_precision() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        accounts.insert(
-            1,
-            AccountRecord {
-                available: dec!(50.1234),
-                held: dec!(0),
-                locked: false,
-            },
-        );
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
-        transactions.insert(1, make_deposit(1, 1, dec!(50.1234)));
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(50.1234),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+        let mut transactions = TxStore::default();
+        transactions
+            .insert(&make_deposit(1, 1, dec!(50.1234)))
+            .unwrap();
 
-        handle_dispute(&make_dispute(1, 1), &mut accounts, &mut transactions).unwrap();
+        handle_dispute(
+            &make_dispute(1, 1),
+            &mut accounts,
+            &mut transactions,
+            RedisputePolicy::Allow,
+        )
+        .unwrap();
 
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(0));
-        assert_eq!(accounts.get(&1).unwrap().held, dec!(50.1234));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(0));
+        assert_eq!(accounts.get(1).unwrap().unwrap().held, dec!(50.1234));
     }
 
     #[test]
@@ -810,8 +947,8 @@ mod tests {
 
     #[test]
     fn many_small_deposits_no_floating_point_error() {
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
         // This would fail with f64 due to floating point errors
         for i in 1..=10000 {
@@ -823,25 +960,1912 @@ mod tests {
             .unwrap();
         }
 
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(1.0000));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(1.0000));
     }
 
     #[test]
     fn input_with_more_than_four_decimals_truncated_or_rejected() {
         // This tests what happens if input has more precision than expected
         // rust_decimal will preserve it, but spec says input is "up to four places"
-        let mut accounts: HashMap<u16, AccountRecord> = HashMap::new();
-        let mut transactions: HashMap<u32, TransactionRow> = HashMap::new();
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
 
-        // dec!(0.00001) has 5 decimal places - Decimal will store it exactly
-        handle_deposit(
+        // dec!(0.00001) has 5 decimal places - handle_deposit validates the scale
+        let result = handle_deposit(
             make_deposit(1, 1, dec!(0.00001)),
             &mut accounts,
             &mut transactions,
+        );
+
+        // Rejected rather than truncated, and no account is created
+        assert!(result.is_err());
+        assert!(accounts.get(1).unwrap().is_none());
+    }
+
+    // =========================================================================
+    // Engine Tests
+    // =========================================================================
+
+    #[test]
+    fn engine_apply_returns_updated_account() {
+        let mut engine = Engine::new();
+
+        let outcome = engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+
+        assert_eq!(outcome.client, 1);
+        assert_eq!(outcome.tx, 1);
+        assert_eq!(outcome.tx_type, TransactionType::Deposit);
+        assert_eq!(outcome.account.available, dec!(100));
+    }
+
+    #[test]
+    fn engine_apply_rejects_and_leaves_state() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+
+        let result = engine.apply(make_withdrawal(1, 2, dec!(200)));
+
+        assert!(result.is_err());
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(100));
+    }
+
+    #[test]
+    fn engine_accounts_lists_every_client() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(make_deposit(2, 2, dec!(20))).unwrap();
+        engine.apply(make_dispute(2, 2)).unwrap();
+
+        let mut clients: Vec<u16> = engine
+            .accounts()
+            .unwrap()
+            .into_iter()
+            .map(|(client, _)| client)
+            .collect();
+        clients.sort();
+
+        assert_eq!(clients, vec![1, 2]);
+        assert_eq!(engine.account(2).unwrap().unwrap().held, dec!(20));
+        assert!(engine.account(3).unwrap().is_none());
+    }
+
+    // =========================================================================
+    // Typed Error Tests
+    // =========================================================================
+
+    #[test]
+    fn withdrawal_reports_requested_and_available() {
+        let mut accounts = new_accounts();
+        accounts
+            .upsert(
+                1,
+                AccountRecord {
+                    available: dec!(50),
+                    held: dec!(0),
+                    locked: false,
+                },
+            )
+            .unwrap();
+
+        let result = handle_withdrawal(&make_withdrawal(1, 1, dec!(100)), &mut accounts);
+
+        assert_eq!(
+            result,
+            Err(EngineError::InsufficientFunds {
+                requested: dec!(100),
+                available: dec!(50),
+            })
+        );
+        assert_eq!(result.unwrap_err().code(), "insufficient_funds");
+    }
+
+    #[test]
+    fn dispute_errors_are_typed() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+
+        assert_eq!(
+            engine.apply(make_deposit(1, 1, dec!(5))),
+            Err(EngineError::DuplicateTx { tx: 1 })
+        );
+        assert_eq!(
+            engine.apply(make_dispute(1, 9)),
+            Err(EngineError::UnknownTx { tx: 9 })
+        );
+        assert_eq!(
+            engine.apply(make_dispute(2, 1)),
+            Err(EngineError::ClientMismatch {
+                tx: 1,
+                client: 2,
+                owner: 1
+            })
+        );
+        assert_eq!(
+            engine.apply(make_resolve(1, 1)),
+            Err(EngineError::NotDisputed { tx: 1 })
+        );
+        engine.apply(make_dispute(1, 1)).unwrap();
+        assert_eq!(
+            engine.apply(make_dispute(1, 1)),
+            Err(EngineError::AlreadyDisputed { tx: 1 })
+        );
+        engine.apply(make_chargeback(1, 1)).unwrap();
+        assert_eq!(
+            engine.apply(make_deposit(1, 2, dec!(5))),
+            Err(EngineError::AccountLocked { client: 1 })
+        );
+    }
+
+    #[test]
+    fn invalid_amount_code_is_stable() {
+        let mut engine = Engine::new();
+
+        let err = engine.apply(make_deposit(1, 1, dec!(0))).unwrap_err();
+
+        assert_eq!(err.code(), "invalid_amount");
+        assert_eq!(
+            err.to_string(),
+            "Deposit transaction:1 must have a valid amount up to four decimals"
+        );
+    }
+
+    // =========================================================================
+    // Withdrawal Dispute Tests
+    // =========================================================================
+
+    fn withdrawal_dispute_engine() -> Engine {
+        let mut engine = Engine::with_config(EngineConfig {
+            dispute_withdrawals: true,
+            ..EngineConfig::default()
+        });
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine.apply(make_withdrawal(1, 2, dec!(40))).unwrap();
+        engine
+    }
+
+    #[test]
+    fn withdrawal_dispute_holds_provisional_credit() {
+        let mut engine = withdrawal_dispute_engine();
+
+        engine.apply(make_dispute(1, 2)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(60));
+        assert_eq!(account.held, dec!(40));
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolve_keeps_withdrawal() {
+        let mut engine = withdrawal_dispute_engine();
+
+        engine.apply(make_dispute(1, 2)).unwrap();
+        engine.apply(make_resolve(1, 2)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(60));
+        assert_eq!(account.held, dec!(0));
+    }
+
+    #[test]
+    fn withdrawal_chargeback_credits_client_and_locks() {
+        let mut engine = withdrawal_dispute_engine();
+
+        engine.apply(make_dispute(1, 2)).unwrap();
+        engine.apply(make_chargeback(1, 2)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(100));
+        assert_eq!(account.held, dec!(0));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn withdrawal_dispute_disabled_by_default() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine.apply(make_withdrawal(1, 2, dec!(40))).unwrap();
+
+        assert_eq!(
+            engine.apply(make_dispute(1, 2)),
+            Err(EngineError::UnknownTx { tx: 2 })
+        );
+    }
+
+    // =========================================================================
+    // Transaction ID Policy Tests
+    // =========================================================================
+
+    fn engine_with_policy(tx_id_policy: TxIdPolicy) -> Engine {
+        let mut engine = Engine::with_config(EngineConfig {
+            tx_id_policy,
+            ..EngineConfig::default()
+        });
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine
+    }
+
+    #[test]
+    fn withdrawal_reusing_deposit_id_is_rejected() {
+        let mut engine = engine_with_policy(TxIdPolicy::Reject);
+
+        let result = engine.apply(make_withdrawal(1, 1, dec!(30)));
+
+        assert_eq!(result, Err(EngineError::DuplicateTx { tx: 1 }));
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(100));
+    }
+
+    #[test]
+    fn warn_policy_applies_reused_withdrawal_id() {
+        let mut engine = engine_with_policy(TxIdPolicy::Warn);
+
+        let outcome = engine.apply(make_withdrawal(1, 1, dec!(30))).unwrap();
+
+        assert_eq!(outcome.kind, OutcomeKind::AppliedReusedId);
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(70));
+        // A second deposit would overwrite the stored one, so it is still refused
+        assert!(engine.apply(make_deposit(1, 1, dec!(5))).is_err());
+    }
+
+    #[test]
+    fn withdrawal_holds_its_id_without_being_disputable() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine.apply(make_withdrawal(1, 2, dec!(40))).unwrap();
+
+        assert_eq!(
+            engine.apply(make_deposit(1, 2, dec!(5))),
+            Err(EngineError::DuplicateTx { tx: 2 })
+        );
+        assert!(engine.transaction(2).unwrap().is_none());
+        for row in [make_resolve(1, 2), make_chargeback(1, 2)] {
+            assert_eq!(engine.apply(row), Err(EngineError::UnknownTx { tx: 2 }));
+        }
+    }
+
+    #[test]
+    fn warn_policy_deposit_takes_over_a_withdrawals_id() {
+        let mut engine = engine_with_policy(TxIdPolicy::Warn);
+        engine.apply(make_withdrawal(1, 2, dec!(40))).unwrap();
+
+        let outcome = engine.apply(make_deposit(1, 2, dec!(5))).unwrap();
+        engine.apply(make_dispute(1, 2)).unwrap();
+
+        assert_eq!(outcome.kind, OutcomeKind::AppliedReusedId);
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(60));
+        assert_eq!(account.held, dec!(5));
+    }
+
+    #[test]
+    fn replay_policy_skips_identical_rows() {
+        let mut engine = engine_with_policy(TxIdPolicy::IdempotentReplay);
+        engine.apply(make_withdrawal(1, 2, dec!(10))).unwrap();
+
+        let deposit = engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        let withdrawal = engine.apply(make_withdrawal(1, 2, dec!(10))).unwrap();
+
+        assert_eq!(deposit.kind, OutcomeKind::Replayed);
+        assert_eq!(withdrawal.kind, OutcomeKind::Replayed);
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(90));
+    }
+
+    #[test]
+    fn replay_policy_rejects_rows_that_differ() {
+        let mut engine = engine_with_policy(TxIdPolicy::IdempotentReplay);
+
+        // Same amount but a different scale is not byte-identical
+        assert!(engine.apply(make_deposit(1, 1, dec!(100.0))).is_err());
+        assert!(engine.apply(make_deposit(2, 1, dec!(100))).is_err());
+        assert!(engine.apply(make_withdrawal(1, 1, dec!(100))).is_err());
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(100));
+    }
+
+    #[test]
+    fn rejected_row_does_not_claim_its_tx_id() {
+        let mut engine = engine_with_policy(TxIdPolicy::Reject);
+
+        assert!(engine.apply(make_withdrawal(1, 2, dec!(500))).is_err());
+        assert!(engine.apply(make_withdrawal(1, 2, dec!(50))).is_ok());
+    }
+
+    // =========================================================================
+    // Overflow Tests
+    // =========================================================================
+
+    #[test]
+    fn deposit_overflow_is_rejected_not_panicking() {
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
+
+        handle_deposit(
+            make_deposit(1, 1, Decimal::MAX),
+            &mut accounts,
+            &mut transactions,
+        )
+        .unwrap();
+        let result = handle_deposit(
+            make_deposit(1, 2, dec!(1)),
+            &mut accounts,
+            &mut transactions,
+        );
+
+        assert_eq!(
+            result,
+            Err(EngineError::BalanceOverflow { tx: 2, client: 1 })
+        );
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, Decimal::MAX); // unchanged
+        assert!(!transactions.contains(2).unwrap());
+    }
+
+    #[test]
+    fn deposit_that_would_round_is_rejected() {
+        let mut accounts = new_accounts();
+        let mut transactions = TxStore::default();
+
+        handle_deposit(
+            make_deposit(1, 1, Decimal::MAX),
+            &mut accounts,
+            &mut transactions,
         )
         .unwrap();
 
-        // Decimal preserves it - if you need to reject/truncate, add validation
-        assert_eq!(accounts.get(&1).unwrap().available, dec!(0.00001));
+        // Decimal::MAX + 0.0001 silently rounds back to Decimal::MAX
+        let result = handle_deposit(
+            make_deposit(1, 2, dec!(0.0001)),
+            &mut accounts,
+            &mut transactions,
+        );
+
+        assert!(matches!(result, Err(EngineError::BalanceOverflow { .. })));
+    }
+
+    #[test]
+    fn withdrawal_dispute_overflow_leaves_transaction_undisputed() {
+        let mut engine = Engine::with_config(EngineConfig {
+            dispute_withdrawals: true,
+            ..EngineConfig::default()
+        });
+        engine.apply(make_deposit(1, 1, Decimal::MAX)).unwrap();
+        engine.apply(make_withdrawal(1, 2, dec!(1))).unwrap();
+        engine.apply(make_deposit(1, 3, dec!(1))).unwrap();
+
+        // Crediting the withdrawal back into held would push the total past MAX
+        let result = engine.apply(make_dispute(1, 2));
+
+        assert!(matches!(result, Err(EngineError::BalanceOverflow { .. })));
+        assert_eq!(engine.account(1).unwrap().unwrap().held, dec!(0));
+        assert_eq!(
+            engine.apply(make_resolve(1, 2)),
+            Err(EngineError::NotDisputed { tx: 2 })
+        );
+    }
+
+    // =========================================================================
+    // Transaction Lifecycle Tests
+    // =========================================================================
+
+    #[test]
+    fn transaction_state_follows_the_dispute_lifecycle() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().state(),
+            TxState::Processed
+        );
+
+        engine.apply(make_dispute(1, 1)).unwrap();
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().state(),
+            TxState::Disputed
+        );
+
+        engine.apply(make_resolve(1, 1)).unwrap();
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().state(),
+            TxState::Resolved
+        );
+
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_chargeback(1, 1)).unwrap();
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().state(),
+            TxState::ChargedBack
+        );
+    }
+
+    #[test]
+    fn charged_back_transaction_is_final() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_chargeback(1, 1)).unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+
+        for row in [
+            make_dispute(1, 1),
+            make_resolve(1, 1),
+            make_chargeback(1, 1),
+        ] {
+            assert_eq!(
+                engine.apply(row).unwrap_err(),
+                EngineError::ChargedBack { tx: 1 }
+            );
+        }
+        assert_eq!(engine.account(1).unwrap(), Some(account));
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().state(),
+            TxState::ChargedBack
+        );
+    }
+
+    #[test]
+    fn redispute_deny_keeps_resolve_final() {
+        let mut engine = Engine::with_config(EngineConfig {
+            redispute: RedisputePolicy::Deny,
+            ..EngineConfig::default()
+        });
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_resolve(1, 1)).unwrap();
+
+        let result = engine.apply(make_dispute(1, 1));
+
+        assert_eq!(result, Err(EngineError::RedisputeNotAllowed { tx: 1 }));
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(10));
+        assert_eq!(
+            engine.transaction(1).unwrap().unwrap().state(),
+            TxState::Resolved
+        );
+    }
+
+    #[test]
+    fn resolve_of_resolved_transaction_is_not_disputed() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_resolve(1, 1)).unwrap();
+
+        let result = engine.apply(make_resolve(1, 1));
+
+        assert_eq!(result, Err(EngineError::NotDisputed { tx: 1 }));
+    }
+
+    #[test]
+    fn redispute_policy_parses_cli_names() {
+        assert_eq!("allow".parse(), Ok(RedisputePolicy::Allow));
+        assert_eq!("deny".parse(), Ok(RedisputePolicy::Deny));
+        assert!("maybe".parse::<RedisputePolicy>().is_err());
+    }
+
+    // Files a test writes go in a directory of its own, removed with
+    // everything in it when the test drops the TempDir
+    fn temp_dir() -> tempfile::TempDir {
+        tempfile::Builder::new()
+            .prefix("take_home_")
+            .tempdir()
+            .unwrap()
+    }
+
+    // =========================================================================
+    // Account Store Suite
+    // =========================================================================
+
+    // What every AccountStore has to get right, run once per backend. The
+    // business rules above only need the one store
+    fn account_store_suite<S: AccountStore + 'static>(make: impl Fn() -> S) {
+        let mut accounts = make();
+        assert!(accounts.get(1).unwrap().is_none());
+        assert_eq!(
+            accounts.update(1, &mut |_| Ok(())),
+            Err(EngineError::UnknownAccount { client: 1 })
+        );
+
+        for client in 1..=3 {
+            accounts
+                .upsert(
+                    client,
+                    AccountRecord {
+                        available: dec!(1.5) * Decimal::from(client),
+                        held: dec!(0.0001),
+                        locked: false,
+                    },
+                )
+                .unwrap();
+        }
+        accounts
+            .update(2, &mut |account| {
+                account.locked = true;
+                Ok(())
+            })
+            .unwrap();
+        let result = accounts.update(3, &mut |account| {
+            account.available = dec!(0);
+            Err(EngineError::AccountLocked { client: 3 })
+        });
+        accounts.flush().unwrap();
+
+        assert_eq!(result, Err(EngineError::AccountLocked { client: 3 }));
+        let account = accounts.get(2).unwrap().unwrap();
+        assert_eq!(account.available, dec!(3.0));
+        assert_eq!(account.held, dec!(0.0001));
+        assert!(account.locked);
+        assert_eq!(accounts.get(3).unwrap().unwrap().available, dec!(4.5));
+        let mut clients = Vec::new();
+        accounts
+            .for_each(&mut |client, _| clients.push(client))
+            .unwrap();
+        clients.sort();
+        assert_eq!(clients, vec![1, 2, 3]);
+
+        // And the whole lifecycle, through an Engine
+        let mut engine = Engine::with_stores(EngineConfig::default(), make(), TxStore::default());
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine.apply(make_withdrawal(1, 2, dec!(30))).unwrap();
+        engine.apply(make_deposit(1, 3, dec!(20))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_resolve(1, 1)).unwrap();
+        engine.apply(make_dispute(1, 3)).unwrap();
+        engine.apply(make_chargeback(1, 3)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(70));
+        assert_eq!(account.held, dec!(0));
+        assert!(account.locked);
+        assert_eq!(
+            engine.apply(make_deposit(1, 4, dec!(5))),
+            Err(EngineError::AccountLocked { client: 1 })
+        );
+        assert_eq!(
+            engine.apply(make_withdrawal(2, 5, dec!(5))),
+            Err(EngineError::UnknownAccount { client: 2 })
+        );
+    }
+
+    #[test]
+    fn hash_map_accounts_pass_the_account_store_suite() {
+        account_store_suite(HashMap::<u16, AccountRecord>::new);
+    }
+
+    #[test]
+    fn sharded_accounts_pass_the_account_store_suite() {
+        account_store_suite(|| ShardedAccounts::new(4));
+    }
+
+    #[test]
+    fn disk_accounts_pass_the_account_store_suite() {
+        let dir = temp_dir();
+        let opened = std::cell::Cell::new(0);
+        account_store_suite(|| {
+            opened.set(opened.get() + 1);
+            let path = dir.path().join(format!("accounts_{}.redb", opened.get()));
+            DiskStore::open(path).unwrap().accounts()
+        });
+    }
+
+    #[test]
+    fn sqlite_accounts_pass_the_account_store_suite() {
+        account_store_suite(|| SqliteStore::open_in_memory().unwrap().accounts());
+    }
+
+    #[test]
+    fn sharded_accounts_keep_each_client_in_one_shard() {
+        let mut accounts = ShardedAccounts::new(4);
+        for client in 0..20 {
+            accounts
+                .upsert(
+                    client,
+                    AccountRecord {
+                        available: Decimal::from(client),
+                        held: dec!(0),
+                        locked: false,
+                    },
+                )
+                .unwrap();
+        }
+
+        assert_eq!(accounts.shard_of(5), accounts.shard_of(9));
+        assert_ne!(accounts.shard_of(5), accounts.shard_of(6));
+        assert_eq!(accounts.get(7).unwrap().unwrap().available, dec!(7));
+
+        let mut clients = Vec::new();
+        accounts
+            .for_each(&mut |client, _| clients.push(client))
+            .unwrap();
+        clients.sort();
+        assert_eq!(clients, (0..20).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn account_update_that_fails_writes_nothing() {
+        let mut accounts: Box<dyn AccountStore> = Box::new(ShardedAccounts::new(2));
+        accounts.upsert(1, AccountRecord::default()).unwrap();
+
+        let result = accounts.update(1, &mut |account| {
+            account.available = dec!(5);
+            Err(EngineError::AccountLocked { client: 1 })
+        });
+
+        assert_eq!(result, Err(EngineError::AccountLocked { client: 1 }));
+        assert_eq!(accounts.get(1).unwrap().unwrap().available, dec!(0));
+        assert_eq!(
+            accounts.update(2, &mut |_| Ok(())),
+            Err(EngineError::UnknownAccount { client: 2 })
+        );
+    }
+
+    // =========================================================================
+    // Report Ordering Tests
+    // =========================================================================
+
+    fn report_clients(engine: &Engine, sort_by: SortBy) -> Vec<u16> {
+        account_report(engine, sort_by)
+            .unwrap()
+            .iter()
+            .map(|record| record.client)
+            .collect()
+    }
+
+    #[test]
+    fn report_orders_by_client_by_default() {
+        let mut engine = Engine::new();
+        for client in [5, 3, 9, 1] {
+            engine
+                .apply(make_deposit(client, client as u32, dec!(10)))
+                .unwrap();
+        }
+
+        assert_eq!(report_clients(&engine, SortBy::default()), vec![1, 3, 5, 9]);
+    }
+
+    #[test]
+    fn report_sorts_by_column_then_client() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(3, 1, dec!(30))).unwrap();
+        engine.apply(make_deposit(2, 2, dec!(10))).unwrap();
+        engine.apply(make_deposit(1, 3, dec!(30))).unwrap();
+        engine.apply(make_deposit(4, 4, dec!(20))).unwrap();
+        engine.apply(make_dispute(4, 4)).unwrap();
+
+        assert_eq!(report_clients(&engine, SortBy::Available), vec![4, 2, 1, 3]);
+        assert_eq!(report_clients(&engine, SortBy::Held), vec![1, 2, 3, 4]);
+        assert_eq!(report_clients(&engine, SortBy::Total), vec![2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn sort_by_parses_cli_names() {
+        assert_eq!("total".parse::<SortBy>(), Ok(SortBy::Total));
+        assert!("balance".parse::<SortBy>().is_err());
+    }
+
+    // =========================================================================
+    // Rejection Record Tests
+    // =========================================================================
+
+    #[test]
+    fn rejection_record_carries_row_and_code() {
+        let row = make_withdrawal(7, 42, dec!(1.5));
+        let error = EngineError::UnknownAccount { client: 7 };
+
+        let rejection = RejectionRecord::rejected("day1.csv", 3, &row, &error);
+
+        assert_eq!(rejection.source, "day1.csv");
+        assert_eq!(rejection.line, 3);
+        assert_eq!(rejection.tx_type.as_deref(), Some("withdrawal"));
+        assert_eq!(rejection.client.as_deref(), Some("7"));
+        assert_eq!(rejection.tx.as_deref(), Some("42"));
+        assert_eq!(rejection.amount.as_deref(), Some("1.5"));
+        assert_eq!(rejection.reason, "unknown_account");
+    }
+
+    #[test]
+    fn unparsed_rejection_keeps_raw_columns() {
+        let rejection = RejectionRecord::unparsed("-", 9, &["deposit", "1", "x", ""]);
+
+        assert_eq!(rejection.tx.as_deref(), Some("x"));
+        assert_eq!(rejection.amount, None);
+        assert_eq!(rejection.reason, PARSE_ERROR);
+    }
+
+    // =========================================================================
+    // Snapshot Tests
+    // =========================================================================
+
+    #[test]
+    fn snapshot_round_trip_keeps_disputes_and_tx_ids() {
+        let config = EngineConfig {
+            tx_id_policy: TxIdPolicy::IdempotentReplay,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::with_config(config);
+        engine.apply(make_deposit(1, 1, dec!(10.5))).unwrap();
+        engine.apply(make_deposit(1, 2, dec!(4))).unwrap();
+        engine.apply(make_dispute(1, 2)).unwrap();
+        engine.apply(make_withdrawal(1, 3, dec!(0.5))).unwrap();
+
+        let json = serde_json::to_string(&Snapshot::of(&engine).unwrap()).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        let mut restored = snapshot.into_engine(config).unwrap();
+
+        assert_eq!(restored.account(1), engine.account(1));
+        assert!(restored.transaction(2).unwrap().unwrap().disputed());
+
+        // Every tx ID in use came along, so a replayed row is still recognised
+        for row in [
+            make_deposit(1, 1, dec!(10.5)),
+            make_withdrawal(1, 3, dec!(0.5)),
+        ] {
+            assert_eq!(restored.apply(row).unwrap().kind, OutcomeKind::Replayed);
+        }
+        restored.apply(make_resolve(1, 2)).unwrap();
+        assert_eq!(restored.account(1).unwrap().unwrap().available, dec!(14));
+    }
+
+    #[test]
+    fn snapshot_leaves_an_on_disk_store_where_it_is() {
+        let dir = temp_dir();
+        let store = DiskStore::open(dir.path().join("snapshot.redb")).unwrap();
+        let mut engine = Engine::with_stores(
+            EngineConfig::default(),
+            store.accounts(),
+            store.transactions(),
+        );
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.flush().unwrap();
+
+        let json = serde_json::to_string(&Snapshot::of(&engine).unwrap()).unwrap();
+
+        assert!(!json.contains("\"client\""));
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        snapshot.restore(&mut engine).unwrap();
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(10));
+
+        // Without the store there is nothing to carry on from
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        let result = snapshot.into_engine(EngineConfig::default());
+        assert!(matches!(result, Err(SnapshotError::NotInStore("accounts"))));
+    }
+
+    #[test]
+    fn snapshot_with_other_version_is_refused() {
+        let json = r#"{"version":99,"accounts":[],"transactions":[]}"#;
+        let snapshot: Snapshot = serde_json::from_str(json).unwrap();
+
+        let result = snapshot.into_engine(EngineConfig::default());
+
+        assert!(matches!(result, Err(SnapshotError::UnsupportedVersion(99))));
+    }
+
+    // =========================================================================
+    // WAL Tests
+    // =========================================================================
+
+    // Applies each row and logs the ones that were accepted, as the CLI does
+    fn apply_logged(engine: &mut Engine, wal: &mut Wal, rows: Vec<TransactionRow>) {
+        for row in rows {
+            if engine.apply(row.clone()).is_ok() {
+                wal.append(&row).unwrap();
+            }
+        }
+        wal.sync().unwrap();
+    }
+
+    #[test]
+    fn wal_replay_rebuilds_accounts_and_transactions() {
+        let dir = temp_dir();
+        let path = dir.path().join("rebuild.wal");
+        let mut engine = Engine::new();
+        let (mut wal, replay) = Wal::open(&path, &mut engine, 2).unwrap();
+        assert_eq!(replay.entries, 0);
+        apply_logged(
+            &mut engine,
+            &mut wal,
+            vec![
+                make_deposit(1, 1, dec!(10)),
+                make_withdrawal(1, 2, dec!(50)), // refused, not logged
+                make_deposit(2, 3, dec!(2.5)),
+                make_dispute(1, 1),
+            ],
+        );
+
+        let (rebuilt, replay) = replay_wal(&path).unwrap();
+
+        assert_eq!(replay.entries, 3);
+        assert!(!replay.torn_tail);
+        assert_eq!(rebuilt.account(1), engine.account(1));
+        assert_eq!(rebuilt.account(2), engine.account(2));
+        assert!(rebuilt.transaction(1).unwrap().unwrap().disputed());
+    }
+
+    #[test]
+    fn wal_reopen_replays_then_appends() {
+        let dir = temp_dir();
+        let path = dir.path().join("reopen.wal");
+        let mut engine = Engine::new();
+        let (mut wal, _) = Wal::open(&path, &mut engine, 1).unwrap();
+        apply_logged(&mut engine, &mut wal, vec![make_deposit(1, 1, dec!(10))]);
+        drop(wal);
+
+        let mut resumed = Engine::new();
+        let (mut wal, replay) = Wal::open(&path, &mut resumed, 1).unwrap();
+        assert_eq!(replay.entries, 1);
+        apply_logged(&mut resumed, &mut wal, vec![make_deposit(1, 2, dec!(5))]);
+
+        let (rebuilt, replay) = replay_wal(&path).unwrap();
+        assert_eq!(replay.entries, 2);
+        assert_eq!(rebuilt.account(1).unwrap().unwrap().available, dec!(15));
+    }
+
+    #[test]
+    fn wal_torn_tail_is_dropped_on_open() {
+        let dir = temp_dir();
+        let path = dir.path().join("torn.wal");
+        let mut engine = Engine::new();
+        let (mut wal, _) = Wal::open(&path, &mut engine, 1).unwrap();
+        apply_logged(&mut engine, &mut wal, vec![make_deposit(1, 1, dec!(10))]);
+        drop(wal);
+        // A crash part way through writing the next entry
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"0badc0de {\"seq\":2,\"ty").unwrap();
+
+        assert!(replay_wal(&path).unwrap().1.torn_tail);
+
+        let mut resumed = Engine::new();
+        let (mut wal, replay) = Wal::open(&path, &mut resumed, 1).unwrap();
+        assert_eq!(replay.entries, 1);
+        assert!(replay.torn_tail);
+        apply_logged(&mut resumed, &mut wal, vec![make_deposit(1, 2, dec!(5))]);
+
+        let (rebuilt, replay) = replay_wal(&path).unwrap();
+        assert_eq!(replay.entries, 2);
+        assert!(!replay.torn_tail);
+        assert_eq!(rebuilt.account(1).unwrap().unwrap().available, dec!(15));
+    }
+
+    #[test]
+    fn wal_damage_before_the_last_entry_is_corrupt() {
+        let dir = temp_dir();
+        let path = dir.path().join("corrupt.wal");
+        let mut engine = Engine::new();
+        let (mut wal, _) = Wal::open(&path, &mut engine, 1).unwrap();
+        apply_logged(
+            &mut engine,
+            &mut wal,
+            vec![make_deposit(1, 1, dec!(10)), make_deposit(1, 2, dec!(5))],
+        );
+        drop(wal);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replacen("\"10\"", "\"90\"", 1)).unwrap();
+
+        let result = replay_wal(&path);
+
+        assert!(matches!(result, Err(WalError::Corrupt { line: 2 })));
+    }
+
+    #[test]
+    fn wal_written_with_other_settings_is_refused() {
+        let dir = temp_dir();
+        let path = dir.path().join("config.wal");
+        let mut engine = Engine::new();
+        drop(Wal::open(&path, &mut engine, 1).unwrap());
+
+        let mut other = Engine::with_config(EngineConfig {
+            dispute_withdrawals: true,
+            ..EngineConfig::default()
+        });
+        let result = Wal::open(&path, &mut other, 1);
+
+        assert!(matches!(result, Err(WalError::ConfigMismatch)));
+
+        // Neither policy logs a reused tx ID, so they replay alike
+        let mut replaying = Engine::with_config(EngineConfig {
+            tx_id_policy: TxIdPolicy::IdempotentReplay,
+            ..EngineConfig::default()
+        });
+        assert!(Wal::open(&path, &mut replaying, 1).is_ok());
+    }
+
+    #[test]
+    fn wal_reopened_after_a_snapshot_skips_what_it_holds() {
+        let dir = temp_dir();
+        let path = dir.path().join("snapshot.wal");
+        let mut engine = Engine::new();
+        let (mut wal, _) = Wal::open(&path, &mut engine, 1).unwrap();
+        apply_logged(&mut engine, &mut wal, vec![make_deposit(1, 1, dec!(10))]);
+        let snapshot = Snapshot::of(&engine).unwrap().with_wal_seq(wal.last_seq());
+        apply_logged(&mut engine, &mut wal, vec![make_deposit(1, 2, dec!(5))]);
+        drop(wal);
+
+        let mut resumed = snapshot.into_engine(EngineConfig::default()).unwrap();
+        let (wal, replay) = Wal::open_after(&path, &mut resumed, 1, 1).unwrap();
+
+        assert_eq!(replay.entries, 2);
+        assert_eq!(wal.last_seq(), 2);
+        assert_eq!(resumed.account(1).unwrap().unwrap().available, dec!(15));
+        drop(wal);
+        let mut ahead = Engine::new();
+        assert!(matches!(
+            Wal::open_after(&path, &mut ahead, 1, 3),
+            Err(WalError::Behind {
+                entries: 2,
+                applied: 3
+            })
+        ));
+    }
+
+    // =========================================================================
+    // Transaction Store Tests
+    // =========================================================================
+
+    #[test]
+    fn stored_tx_is_twenty_bytes() {
+        assert_eq!(std::mem::size_of::<StoredTx>(), 20);
+        assert_eq!(std::mem::size_of::<Option<StoredTx>>(), 20);
+    }
+
+    #[test]
+    fn sequential_ids_stay_dense() {
+        let mut store = TxStore::default();
+        for tx in 1000..2000 {
+            store.insert(&make_deposit(1, tx, dec!(1))).unwrap();
+        }
+
+        assert!(store.is_dense());
+        assert_eq!(store.len(), 1000);
+        assert_eq!(store.get(1500).unwrap().unwrap().amount(), dec!(1));
+        assert!(store.get(999).unwrap().is_none());
+        assert!(store.get(2000).unwrap().is_none());
+    }
+
+    #[test]
+    fn ids_doubling_apart_move_to_a_map() {
+        let mut store = TxStore::default();
+        // 0, 65536, 131074, 262150, ... each just over double the last
+        let ids =
+            std::iter::successors(Some(0u32), |tx| Some((tx.checked_mul(2)? + 2).max(1 << 16)));
+        for tx in ids.take(17) {
+            store.insert(&make_deposit(1, tx, dec!(1))).unwrap();
+        }
+
+        assert!(!store.is_dense());
+        assert_eq!(store.len(), 17);
+    }
+
+    #[test]
+    fn sparse_ids_move_to_a_map_and_keep_entries() {
+        let mut store = TxStore::default();
+        store.insert(&make_deposit(1, 1, dec!(1))).unwrap();
+        store.insert(&make_deposit(2, 2, dec!(2))).unwrap();
+        store.insert(&make_deposit(3, u32::MAX, dec!(3))).unwrap();
+        store.insert(&make_deposit(4, 0, dec!(4))).unwrap();
+
+        assert!(!store.is_dense());
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(2).unwrap().unwrap().client(), 2);
+        assert_eq!(store.get(u32::MAX).unwrap().unwrap().client(), 3);
+        assert_eq!(store.get(0).unwrap().unwrap().client(), 4);
+    }
+
+    #[test]
+    fn store_replaces_an_existing_id() {
+        let mut store = TxStore::default();
+        store.insert(&make_deposit(1, 5, dec!(1))).unwrap();
+        let mut disputed = make_deposit(1, 5, dec!(1));
+        disputed.state = TxState::Disputed;
+        store.insert(&disputed).unwrap();
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(5).unwrap().unwrap().state(), TxState::Disputed);
+    }
+
+    #[test]
+    fn store_refuses_a_row_without_amount() {
+        let mut store = TxStore::default();
+
+        let result = store.insert(&make_dispute(1, 1));
+
+        assert_eq!(result, Err(EngineError::MissingAmount { tx: 1 }));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn every_store_removes_a_transaction() {
+        let mut memory = TxStore::default();
+        let dir = temp_dir();
+        let mut disk = DiskTxStore::open(dir.path().join("remove.redb")).unwrap();
+        let mut sqlite = SqliteStore::open_in_memory().unwrap().transactions();
+
+        for store in [
+            &mut memory as &mut dyn TransactionStore,
+            &mut disk,
+            &mut sqlite,
+        ] {
+            store.insert(&make_deposit(1, 4, dec!(2))).unwrap();
+            store.insert(&make_deposit(1, 5, dec!(3))).unwrap();
+
+            assert_eq!(store.remove(4).unwrap().unwrap().amount(), dec!(2));
+            assert!(store.remove(4).unwrap().is_none());
+            assert!(store.get(4).unwrap().is_none());
+            assert_eq!(store.len(), 1);
+        }
+    }
+
+    #[test]
+    fn stored_tx_converts_back_to_its_row() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(7, 3, dec!(2.5))).unwrap();
+        engine.apply(make_dispute(7, 3)).unwrap();
+
+        let row = engine.transaction(3).unwrap().unwrap();
+
+        assert_eq!(row.tx_type, TransactionType::Deposit);
+        assert_eq!(row.client, 7);
+        assert_eq!(row.tx, 3);
+        assert_eq!(row.amount, Some(dec!(2.5)));
+        assert!(row.disputed());
+    }
+
+    // =========================================================================
+    // Disk Transaction Store Tests
+    // =========================================================================
+
+    #[test]
+    fn disk_store_runs_the_dispute_lifecycle() {
+        let dir = temp_dir();
+        let store = DiskTxStore::open(dir.path().join("lifecycle.redb")).unwrap();
+        let mut engine = Engine::with_transaction_store(EngineConfig::default(), store);
+
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine.apply(make_deposit(1, 2, dec!(20))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_resolve(1, 1)).unwrap();
+        engine.apply(make_dispute(1, 2)).unwrap();
+        engine.apply(make_chargeback(1, 2)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(100));
+        assert_eq!(account.held, dec!(0));
+        assert!(account.locked);
+        assert_eq!(
+            engine.apply(make_deposit(2, 1, dec!(5))),
+            Err(EngineError::DuplicateTx { tx: 1 })
+        );
+        assert_eq!(
+            engine.transaction(2).unwrap().unwrap().state(),
+            TxState::ChargedBack
+        );
+    }
+
+    #[test]
+    fn disk_store_keeps_transactions_between_opens() {
+        let dir = temp_dir();
+        let path = dir.path().join("reopen.redb");
+        {
+            let mut store = DiskTxStore::open(&path).unwrap();
+            store.insert(&make_deposit(3, 7, dec!(1.5))).unwrap();
+            store.set_state(7, TxState::Disputed).unwrap();
+            // Dropped without a flush, the open batch is committed on the way out
+        }
+
+        let store = DiskTxStore::open(&path).unwrap();
+
+        assert_eq!(store.len(), 1);
+        let stored = store.get(7).unwrap().unwrap();
+        assert_eq!(stored.client(), 3);
+        assert_eq!(stored.amount(), dec!(1.5));
+        assert_eq!(stored.state(), TxState::Disputed);
+    }
+
+    #[test]
+    fn disk_store_keeps_accounts_between_opens() {
+        let dir = temp_dir();
+        let path = dir.path().join("accounts.redb");
+        {
+            let store = DiskStore::open(&path).unwrap();
+            let mut engine = Engine::with_stores(
+                EngineConfig::default(),
+                store.accounts(),
+                store.transactions(),
+            );
+            engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        }
+
+        let store = DiskStore::open(&path).unwrap();
+        let mut engine = Engine::with_stores(
+            EngineConfig::default(),
+            store.accounts(),
+            store.transactions(),
+        );
+        engine.apply(make_dispute(1, 1)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(0));
+        assert_eq!(account.held, dec!(100));
+    }
+
+    #[test]
+    fn disk_store_commits_whole_rows_only() {
+        let dir = temp_dir();
+        let path = dir.path().join("rows.redb");
+        let store = DiskStore::open(&path).unwrap();
+        let mut engine = Engine::with_stores(
+            EngineConfig::default(),
+            store.accounts(),
+            store.transactions(),
+        );
+        // A write outside any row, as restoring a snapshot makes, so that a
+        // batch counted in writes would fill up between a deposit's two
+        store
+            .accounts()
+            .upsert(2, AccountRecord::default())
+            .unwrap();
+        for tx in 1..=crate::batch::BATCH_SIZE as u32 {
+            engine.apply(make_deposit(1, tx, dec!(1))).unwrap();
+        }
+
+        // What a crash now would leave behind is what has been committed
+        let crashed = dir.path().join("crashed.redb");
+        std::fs::copy(&path, &crashed).unwrap();
+        let store = DiskStore::open(&crashed).unwrap();
+
+        let stored = store.transactions().len();
+        let account = store.accounts().get(1).unwrap().unwrap();
+        assert!(stored > 0);
+        assert_eq!(account.available, Decimal::from(stored));
+    }
+
+    #[test]
+    fn disk_store_visits_in_tx_order() {
+        let dir = temp_dir();
+        let mut store = DiskTxStore::open(dir.path().join("order.redb")).unwrap();
+        for tx in [30, 10, 20] {
+            store.insert(&make_deposit(1, tx, dec!(1))).unwrap();
+        }
+        store.flush().unwrap();
+        store.insert(&make_deposit(1, 5, dec!(1))).unwrap();
+
+        let mut seen = Vec::new();
+        store.for_each(&mut |tx, _| seen.push(tx)).unwrap();
+
+        assert_eq!(seen, vec![5, 10, 20, 30]);
+    }
+
+    #[test]
+    fn set_state_of_unknown_tx_fails() {
+        let mut memory = TxStore::default();
+        let dir = temp_dir();
+        let mut disk = DiskTxStore::open(dir.path().join("unknown.redb")).unwrap();
+
+        for store in [&mut memory as &mut dyn TransactionStore, &mut disk] {
+            assert_eq!(
+                store.set_state(9, TxState::Disputed),
+                Err(EngineError::UnknownTx { tx: 9 })
+            );
+        }
+    }
+
+    // =========================================================================
+    // SQLite Store Tests
+    // =========================================================================
+
+    #[test]
+    fn sqlite_store_runs_the_dispute_lifecycle() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = Engine::with_stores(
+            EngineConfig::default(),
+            store.accounts(),
+            store.transactions(),
+        );
+
+        engine.apply(make_deposit(1, 1, dec!(100))).unwrap();
+        engine.apply(make_deposit(1, 2, dec!(20))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_resolve(1, 1)).unwrap();
+        engine.apply(make_dispute(1, 2)).unwrap();
+        engine.apply(make_chargeback(1, 2)).unwrap();
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, dec!(100));
+        assert!(account.locked);
+        assert_eq!(
+            engine.transaction(2).unwrap().unwrap().state(),
+            TxState::ChargedBack
+        );
+        assert_eq!(
+            engine.apply(make_deposit(2, 1, dec!(5))),
+            Err(EngineError::DuplicateTx { tx: 1 })
+        );
+    }
+
+    #[test]
+    fn sqlite_store_commits_whole_rows_only() {
+        let dir = temp_dir();
+        let path = dir.path().join("rows.db");
+        let store = SqliteStore::open(&path).unwrap();
+        let mut engine = Engine::with_stores(
+            EngineConfig::default(),
+            store.accounts(),
+            store.transactions(),
+        );
+        // As with the redb store, a write outside any row first
+        store
+            .accounts()
+            .upsert(2, AccountRecord::default())
+            .unwrap();
+        for tx in 1..=crate::batch::BATCH_SIZE as u32 {
+            engine.apply(make_deposit(1, tx, dec!(1))).unwrap();
+        }
+
+        // A crash now leaves the journal behind, which rolls back whatever
+        // wasn't committed once the copy is opened
+        let crashed = dir.path().join("crashed.db");
+        std::fs::copy(&path, &crashed).unwrap();
+        let journal = dir.path().join("rows.db-journal");
+        if journal.exists() {
+            std::fs::copy(&journal, dir.path().join("crashed.db-journal")).unwrap();
+        }
+        let store = SqliteStore::open(&crashed).unwrap();
+
+        let stored = store.transactions().len();
+        let account = store.accounts().get(1).unwrap().unwrap();
+        assert!(stored > 0);
+        assert_eq!(account.available, Decimal::from(stored));
+    }
+
+    #[test]
+    fn sqlite_store_keeps_state_between_opens() {
+        let dir = temp_dir();
+        let path = dir.path().join("reopen.db");
+        {
+            let store = SqliteStore::open(&path).unwrap();
+            let mut engine = Engine::with_stores(
+                EngineConfig::default(),
+                store.accounts(),
+                store.transactions(),
+            );
+            engine.apply(make_deposit(3, 7, dec!(1.5))).unwrap();
+            engine.apply(make_dispute(3, 7)).unwrap();
+            // Dropped without a flush, the open batch is committed on the way out
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+
+        assert_eq!(store.schema_version().unwrap(), SQLITE_SCHEMA_VERSION);
+        let transactions = store.transactions();
+        assert_eq!(transactions.len(), 1);
+        let stored = transactions.get(7).unwrap().unwrap();
+        assert_eq!(stored.amount(), dec!(1.5));
+        assert_eq!(stored.state(), TxState::Disputed);
+        let account = store.accounts().get(3).unwrap().unwrap();
+        assert_eq!(account.held, dec!(1.5));
+        assert_eq!(account.available, dec!(0));
+    }
+
+    #[test]
+    fn sqlite_store_refuses_a_newer_schema() {
+        let dir = temp_dir();
+        let path = dir.path().join("newer.db");
+        SqliteStore::open(&path).unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", SQLITE_SCHEMA_VERSION + 1)
+            .unwrap();
+
+        let result = SqliteStore::open(&path);
+
+        assert!(matches!(result, Err(EngineError::Storage { .. })));
+    }
+
+    #[test]
+    fn sqlite_set_state_of_unknown_tx_fails() {
+        let mut store = SqliteStore::open_in_memory().unwrap().transactions();
+
+        assert_eq!(
+            store.set_state(9, TxState::Disputed),
+            Err(EngineError::UnknownTx { tx: 9 })
+        );
+    }
+
+    // =========================================================================
+    // Parallel Engine Tests
+    // =========================================================================
+
+    // Rows that hop between clients sharing tx IDs, so most of them need a tx
+    // ID's state moved between shards
+    fn cross_client_rows() -> Vec<TransactionRow> {
+        let mut rows = Vec::new();
+        for round in 0..4u32 {
+            for client in 1..=6u16 {
+                let tx = round * 3 + client as u32 % 3;
+                rows.push(make_deposit(client, tx, dec!(10)));
+                rows.push(make_withdrawal(client, tx + 100, dec!(3)));
+                rows.push(make_dispute(client, tx));
+                rows.push(make_dispute(client % 6 + 1, tx));
+                rows.push(make_withdrawal(client % 6 + 1, tx + 100, dec!(3)));
+                if client % 2 == 0 {
+                    rows.push(make_resolve(client, tx));
+                    rows.push(make_dispute(client, tx));
+                } else {
+                    rows.push(make_chargeback(client, tx));
+                }
+            }
+        }
+        rows
+    }
+
+    fn report_rows(engine: &Engine) -> Vec<(u16, Decimal, Decimal, bool)> {
+        account_report(engine, SortBy::Client)
+            .unwrap()
+            .iter()
+            .map(|record| (record.client, record.available, record.held, record.locked))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_engine_matches_sequential_results() {
+        for tx_id_policy in [
+            TxIdPolicy::Reject,
+            TxIdPolicy::Warn,
+            TxIdPolicy::IdempotentReplay,
+        ] {
+            for dispute_withdrawals in [false, true] {
+                let config = EngineConfig {
+                    dispute_withdrawals,
+                    tx_id_policy,
+                    ..EngineConfig::default()
+                };
+                let mut sequential = Engine::with_config(config);
+                let mut parallel = ParallelEngine::start(Engine::with_config(config), 3).unwrap();
+
+                for row in cross_client_rows() {
+                    let expected = sequential.apply(row.clone());
+                    parallel.submit(row).unwrap();
+                    let applied = parallel.recv().unwrap();
+                    assert_eq!(applied.result, expected, "{:?}", config);
+                }
+                let parallel = parallel.finish().unwrap();
+
+                assert_eq!(report_rows(&parallel), report_rows(&sequential));
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_engine_hands_results_back_in_order() {
+        let mut parallel = ParallelEngine::start(Engine::new(), 4).unwrap();
+        let rows = cross_client_rows();
+        for row in rows.clone() {
+            parallel.submit(row).unwrap();
+        }
+
+        let mut seen = Vec::new();
+        while let Some(applied) = parallel.recv() {
+            seen.push((
+                applied.seq,
+                applied.transaction.client,
+                applied.transaction.tx,
+            ));
+        }
+
+        let expected: Vec<_> = rows
+            .iter()
+            .enumerate()
+            .map(|(seq, row)| (seq as u64, row.client, row.tx))
+            .collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn parallel_engine_carries_on_from_existing_state() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(make_deposit(2, 2, dec!(20))).unwrap();
+        engine.apply(make_dispute(2, 2)).unwrap();
+
+        let mut parallel = ParallelEngine::start(engine, 2).unwrap();
+        parallel.submit(make_resolve(2, 2)).unwrap();
+        parallel.submit(make_deposit(3, 1, dec!(5))).unwrap();
+        parallel.submit(make_withdrawal(1, 3, dec!(4))).unwrap();
+
+        assert!(parallel.recv().unwrap().result.is_ok());
+        assert_eq!(
+            parallel.recv().unwrap().result,
+            Err(EngineError::DuplicateTx { tx: 1 })
+        );
+        assert!(parallel.recv().unwrap().result.is_ok());
+        let engine = parallel.finish().unwrap();
+
+        assert_eq!(engine.account(1).unwrap().unwrap().available, dec!(6));
+        assert_eq!(engine.account(2).unwrap().unwrap().available, dec!(20));
+        assert!(engine.account(3).unwrap().is_none());
+        assert_eq!(
+            engine.transaction(2).unwrap().unwrap().state(),
+            TxState::Resolved
+        );
+    }
+
+    #[test]
+    fn parallel_engine_keeps_the_engines_transaction_store() {
+        let dir = temp_dir();
+        let path = dir.path().join("parallel.redb");
+        let store = DiskTxStore::open(&path).unwrap();
+        let mut engine = Engine::with_transaction_store(EngineConfig::default(), store);
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+
+        let mut parallel = ParallelEngine::start(engine, 2).unwrap();
+        parallel.submit(make_dispute(1, 1)).unwrap();
+        parallel.submit(make_deposit(2, 2, dec!(5))).unwrap();
+        let mut engine = parallel.finish().unwrap();
+        engine.flush().unwrap();
+        drop(engine);
+
+        // Both rows went into the store the Engine started with
+        let store = DiskTxStore::open(&path).unwrap();
+        assert_eq!(store.get(1).unwrap().unwrap().state(), TxState::Disputed);
+        assert_eq!(store.get(2).unwrap().unwrap().amount(), dec!(5));
+    }
+
+    #[test]
+    fn parallel_engine_refuses_accounts_on_disk() {
+        let dir = temp_dir();
+        let store = DiskStore::open(dir.path().join("accounts.redb")).unwrap();
+        let engine = Engine::with_stores(
+            EngineConfig::default(),
+            store.accounts(),
+            store.transactions(),
+        );
+
+        let result = ParallelEngine::start(engine, 2);
+
+        assert!(matches!(result, Err(ParallelError::DurableAccounts)));
+    }
+
+    // A parsed row's fields, amount scale included
+    type RowFields = (TransactionType, u16, u32, Option<(Decimal, u32)>);
+
+    fn row_fields(row: TransactionRow) -> RowFields {
+        (
+            row.tx_type,
+            row.client,
+            row.tx,
+            row.amount.map(|amount| (amount, amount.scale())),
+        )
+    }
+
+    // What the serde path makes of `csv`, row by row
+    fn serde_rows(csv: &[u8]) -> Vec<Option<RowFields>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(csv);
+        let headers = reader.headers().unwrap().clone();
+        reader
+            .records()
+            .map(|record| match record {
+                Ok(record) => record.deserialize(Some(&headers)).ok().map(row_fields),
+                Err(_) => None,
+            })
+            .collect()
+    }
+
+    fn fast_rows(csv: &[u8]) -> Vec<Option<RowFields>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::None)
+            .flexible(true)
+            .from_reader(csv);
+        let parser = RowParser::new(reader.byte_headers().unwrap());
+        reader
+            .byte_records()
+            .map(|record| parser.parse(&record.unwrap()).ok().map(row_fields))
+            .collect()
+    }
+
+    #[test]
+    fn parse_amount_matches_decimal_parsing() {
+        for text in [
+            "0",
+            "1",
+            "10",
+            "1.5",
+            "0.0001",
+            "12.3400",
+            "0012.50",
+            ".5",
+            "5.",
+            "+5",
+            "-5",
+            "-0.0",
+            "1e2",
+            "1.5E-3",
+            "1_000",
+            "",
+            ".",
+            "abc",
+            "1.2.3",
+            " 1",
+            "1 ",
+            "0x10",
+            "12345678901234567890.12345678",
+            "123456789012345678901234567890",
+            "0.12345678901234567890123456789",
+        ] {
+            // As rust_decimal's serde str_option parses it
+            let expected = text
+                .parse::<Decimal>()
+                .or_else(|_| Decimal::from_scientific(text))
+                .ok();
+            assert_eq!(parse_amount(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn parse_amount_keeps_scale() {
+        assert_eq!(parse_amount("1.5000").unwrap().scale(), 4);
+        assert_eq!(parse_amount("1.5000").unwrap().to_string(), "1.5000");
+        assert_eq!(parse_amount("2").unwrap().scale(), 0);
+    }
+
+    #[test]
+    fn row_parser_matches_serde() {
+        let csv = b"type, client, tx, amount
+deposit, 1, 1, 1.0
+ withdrawal ,\t2,\t2,\t0.5000
+dispute, 1, 1,
+resolve, 1, 1
+chargeback, 0x1, 0x1,
+deposit, +3, 3, +2.5
+deposit, 3, 4, 1e2
+deposit, 3, 5, .25
+deposit, 3, 6, 1_000
+deposit, 3, 7, 1.2.3
+Deposit, 3, 8, 1
+deposit, 70000, 9, 1
+deposit, -1, 10, 1
+deposit, 3, 4294967296, 1
+deposit, 3, 11, 1, extra
+deposit, 3
+deposit, 3, 12, 1\xff
+\xff, 3, 13, 1
+deposit, 3, 14, \xc3\xa91
+";
+        let serde = serde_rows(csv);
+
+        assert_eq!(fast_rows(csv), serde);
+        assert_eq!(serde.len(), 19);
+        assert!(serde.iter().flatten().count() >= 10);
+    }
+
+    #[test]
+    fn row_parser_finds_columns_by_name() {
+        let csv = b"amount,tx,type,client,note
+1.5,7,deposit,2,first
+,7,dispute,2,
+";
+
+        assert_eq!(fast_rows(csv), serde_rows(csv));
+        assert_eq!(
+            fast_rows(csv)[0],
+            Some((TransactionType::Deposit, 2, 7, Some((dec!(1.5), 1))))
+        );
+    }
+
+    #[test]
+    fn row_parser_reports_what_is_wrong() {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(
+            &b"type,client,tx,amount\nrefund,1,1,1\ndeposit,1\ndeposit,1,1,x\ndeposit,1,1,\xff\n"[..],
+        );
+        let parser = RowParser::new(reader.byte_headers().unwrap());
+        let errors: Vec<_> = reader
+            .byte_records()
+            .map(|record| parser.parse(&record.unwrap()).unwrap_err())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                RowParseError::Invalid("type"),
+                RowParseError::Missing("tx"),
+                RowParseError::Invalid("amount"),
+                RowParseError::Utf8,
+            ]
+        );
+    }
+
+    #[test]
+    fn transaction_row_deserializes_from_json() {
+        let deposit: TransactionRow =
+            serde_json::from_str(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.5000"}"#)
+                .unwrap();
+        let dispute: TransactionRow =
+            serde_json::from_str(r#"{"type":"dispute","client":1,"tx":2}"#).unwrap();
+        let resolve: TransactionRow =
+            serde_json::from_str(r#"{"type":"resolve","client":1,"tx":2,"amount":null}"#).unwrap();
+
+        assert_eq!(
+            row_fields(deposit),
+            (TransactionType::Deposit, 1, 2, Some((dec!(1.5), 4)))
+        );
+        assert_eq!(dispute.amount, None);
+        assert_eq!(resolve.amount, None);
+        assert_eq!(dispute.state(), TxState::Processed);
+    }
+
+    #[test]
+    fn transaction_row_wants_json_amounts_as_strings() {
+        // A JSON number would already have been through a float
+        assert!(serde_json::from_str::<TransactionRow>(
+            r#"{"type":"deposit","client":1,"tx":2,"amount":1.5}"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<TransactionRow>(
+            r#"{"type":"deposit","client":1,"amount":"1"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn detailed_report_counts_open_disputes() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        engine.apply(make_deposit(1, 2, dec!(5))).unwrap();
+        engine.apply(make_deposit(1, 3, dec!(1))).unwrap();
+        engine.apply(make_deposit(2, 4, dec!(7))).unwrap();
+        engine.apply(make_deposit(3, 5, dec!(2))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        engine.apply(make_dispute(1, 2)).unwrap();
+        engine.apply(make_dispute(1, 3)).unwrap();
+        engine.apply(make_resolve(1, 3)).unwrap();
+        engine.apply(make_dispute(2, 4)).unwrap();
+        engine.apply(make_chargeback(2, 4)).unwrap();
+
+        assert_eq!(engine.open_disputes().unwrap(), HashMap::from([(1, 2)]));
+
+        let report = detailed_account_report(&engine, SortBy::Client).unwrap();
+        let details: Vec<_> = report
+            .iter()
+            .map(|record| {
+                (
+                    record.client,
+                    record.status,
+                    record.open_disputes,
+                    record.held,
+                )
+            })
+            .collect();
+        assert_eq!(
+            details,
+            vec![
+                (1, "disputed", 2, dec!(15)),
+                (2, "locked", 0, dec!(0)),
+                (3, "active", 0, dec!(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn detailed_report_keeps_the_report_order() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(30))).unwrap();
+        engine.apply(make_deposit(2, 2, dec!(10))).unwrap();
+        engine.apply(make_deposit(3, 3, dec!(20))).unwrap();
+
+        let detailed: Vec<u16> = detailed_account_report(&engine, SortBy::Total)
+            .unwrap()
+            .iter()
+            .map(|record| record.client)
+            .collect();
+        let plain: Vec<u16> = account_report(&engine, SortBy::Total)
+            .unwrap()
+            .iter()
+            .map(|record| record.client)
+            .collect();
+
+        assert_eq!(detailed, vec![2, 3, 1]);
+        assert_eq!(detailed, plain);
+    }
+
+    // =========================================================================
+    // Parquet Export Tests
+    // =========================================================================
+
+    // Every test file is small enough to be read back as one batch
+    fn read_parquet(path: &std::path::Path) -> arrow_array::RecordBatch {
+        let file = std::fs::File::open(path).unwrap();
+        let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    }
+
+    fn decimals(batch: &arrow_array::RecordBatch, column: &str) -> Vec<Option<Decimal>> {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::Decimal128Type;
+
+        batch
+            .column_by_name(column)
+            .unwrap()
+            .as_primitive::<Decimal128Type>()
+            .iter()
+            .map(|value| value.map(|value| Decimal::from_i128_with_scale(value, 4)))
+            .collect()
+    }
+
+    fn strings(batch: &arrow_array::RecordBatch, column: &str) -> Vec<Option<String>> {
+        use arrow_array::cast::AsArray;
+
+        batch
+            .column_by_name(column)
+            .unwrap()
+            .as_string::<i32>()
+            .iter()
+            .map(|value| value.map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn accounts_export_as_decimal128_with_scale_4() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(2, 1, dec!(1.5))).unwrap();
+        engine.apply(make_deposit(1, 2, dec!(10.1234))).unwrap();
+        engine.apply(make_deposit(1, 3, dec!(2))).unwrap();
+        engine.apply(make_dispute(1, 3)).unwrap();
+
+        let dir = temp_dir();
+        let path = dir.path().join("accounts.parquet");
+        write_accounts_parquet(&engine, std::fs::File::create(&path).unwrap()).unwrap();
+        let batch = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(batch.schema(), accounts_schema());
+        assert_eq!(
+            batch.schema().field_with_name("total").unwrap().data_type(),
+            &arrow_schema::DataType::Decimal128(38, EXPORT_SCALE)
+        );
+        assert_eq!(
+            decimals(&batch, "available"),
+            vec![Some(dec!(10.1234)), Some(dec!(1.5))]
+        );
+        assert_eq!(decimals(&batch, "held"), vec![Some(dec!(2)), Some(dec!(0))]);
+        assert_eq!(
+            decimals(&batch, "total"),
+            vec![Some(dec!(12.1234)), Some(dec!(1.5))]
+        );
+    }
+
+    #[test]
+    fn history_export_records_every_row() {
+        let rows = vec![
+            make_deposit(1, 1, dec!(100)),
+            make_withdrawal(1, 2, dec!(500)),
+            make_dispute(1, 1),
+            make_resolve(1, 1),
+            make_dispute(1, 1),
+            make_chargeback(1, 1),
+        ];
+        let mut engine = Engine::new();
+        let dir = temp_dir();
+        let path = dir.path().join("history.parquet");
+        let mut history = HistoryWriter::new(std::fs::File::create(&path).unwrap()).unwrap();
+        for (line, row) in rows.into_iter().enumerate() {
+            let result = engine.apply(row.clone());
+            history
+                .push(HistoryRecord::applied(
+                    "in.csv",
+                    line as u64 + 2,
+                    &row,
+                    &result,
+                ))
+                .unwrap();
+        }
+        let unparsed = RejectionRecord::unparsed("in.csv", 8, &["refund", "1", "9", "5"]);
+        history.push(HistoryRecord::unparsed(&unparsed)).unwrap();
+        history.finish().unwrap();
+        let batch = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(batch.schema(), history_schema());
+        assert_eq!(batch.num_rows(), 7);
+        assert_eq!(
+            strings(&batch, "result"),
+            ["accepted", "rejected", "accepted", "accepted", "accepted", "accepted", "rejected"]
+                .map(|result| Some(result.to_string()))
+        );
+        assert_eq!(
+            strings(&batch, "reason"),
+            vec![
+                None,
+                Some("insufficient_funds".to_string()),
+                None,
+                None,
+                None,
+                None,
+                Some(PARSE_ERROR.to_string()),
+            ]
+        );
+        assert_eq!(
+            strings(&batch, "state"),
+            vec![
+                Some("processed".to_string()),
+                None,
+                Some("disputed".to_string()),
+                Some("resolved".to_string()),
+                Some("disputed".to_string()),
+                Some("charged_back".to_string()),
+                None,
+            ]
+        );
+        assert_eq!(
+            decimals(&batch, "amount"),
+            vec![
+                Some(dec!(100)),
+                Some(dec!(500)),
+                None,
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn history_export_leaves_out_amounts_beyond_scale_4() {
+        let row = make_deposit(1, 1, dec!(1.00001));
+        let result = Engine::new().apply(row.clone());
+        assert!(result.is_err());
+
+        let dir = temp_dir();
+        let path = dir.path().join("precision.parquet");
+        let mut history = HistoryWriter::new(std::fs::File::create(&path).unwrap()).unwrap();
+        history
+            .push(HistoryRecord::applied("in.csv", 2, &row, &result))
+            .unwrap();
+        history.finish().unwrap();
+        let batch = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(decimals(&batch, "amount"), vec![None]);
+        assert_eq!(strings(&batch, "type"), vec![Some("deposit".to_string())]);
+    }
+
+    // =========================================================================
+    // Audit Ledger Tests
+    // =========================================================================
+
+    #[test]
+    fn outcome_has_the_account_before_and_after() {
+        let mut engine = Engine::new();
+        let outcome = engine.apply(make_deposit(1, 1, dec!(10))).unwrap();
+        assert_eq!(outcome.before, AccountRecord::default());
+        assert_eq!(outcome.account.available, dec!(10));
+
+        let outcome = engine.apply(make_dispute(1, 1)).unwrap();
+        assert_eq!(outcome.before.available, dec!(10));
+        assert_eq!(outcome.before.held, dec!(0));
+        assert_eq!(outcome.account.available, dec!(0));
+        assert_eq!(outcome.account.held, dec!(10));
+    }
+
+    #[test]
+    fn ledger_record_shows_the_change_and_the_result() {
+        let mut engine = Engine::new();
+        engine.apply(make_deposit(1, 1, dec!(10.50))).unwrap();
+        engine.apply(make_dispute(1, 1)).unwrap();
+        let outcome = engine.apply(make_chargeback(1, 1)).unwrap();
+
+        let record = LedgerRecord::new("in.csv", 4, &outcome);
+        assert_eq!(record.tx_type, "chargeback");
+        assert_eq!((record.client, record.tx), (1, 1));
+        assert_eq!(record.available_delta, dec!(0));
+        assert_eq!(record.held_delta, dec!(-10.5));
+        assert_eq!(record.available, dec!(0));
+        assert_eq!(record.held, dec!(0));
+        assert_eq!(record.total, dec!(0));
+        assert!(record.locked);
     }
 }
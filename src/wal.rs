@@ -0,0 +1,342 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{EngineConfig, RedisputePolicy, TxIdPolicy};
+use crate::engine::Engine;
+use crate::error::EngineError;
+use crate::model::{TransactionRow, TransactionType};
+
+// Bumped whenever the entry layout changes
+pub const WAL_VERSION: u32 = 1;
+
+// How many entries may sit in the buffer before they are fsync'd
+pub const DEFAULT_SYNC_EVERY: usize = 1000;
+
+#[derive(Debug)]
+pub enum WalError {
+    Io(std::io::Error),
+    UnsupportedVersion(u32),
+    // The log was written with different engine settings, replaying it
+    // under these would not rebuild the same state
+    ConfigMismatch,
+    // A damaged entry with more entries after it, so not a torn final write
+    Corrupt { line: u64 },
+    // An entry the engine refused on replay, the log doesn't belong to this state
+    Diverged { seq: u64, error: EngineError },
+    // The engine was restored from a snapshot taken after more entries than
+    // the log holds, so the log isn't the one the snapshot was taken with
+    Behind { entries: u64, applied: u64 },
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalError::Io(err) => write!(f, "WAL I/O error: {}", err),
+            WalError::UnsupportedVersion(version) => write!(
+                f,
+                "WAL version: {} is not supported (expected {})",
+                version, WAL_VERSION
+            ),
+            WalError::ConfigMismatch => {
+                write!(f, "WAL was written with different engine settings")
+            }
+            WalError::Corrupt { line } => write!(f, "WAL is corrupt at line: {}", line),
+            WalError::Diverged { seq, error } => {
+                write!(f, "WAL entry: {} could not be replayed: {}", seq, error)
+            }
+            WalError::Behind { entries, applied } => write!(
+                f,
+                "WAL has {} entries, but the snapshot was taken after entry: {}",
+                entries, applied
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl From<std::io::Error> for WalError {
+    fn from(err: std::io::Error) -> Self {
+        WalError::Io(err)
+    }
+}
+
+// First line of every log
+#[derive(Debug, Serialize, Deserialize)]
+struct WalHeader {
+    version: u32,
+    dispute_withdrawals: bool,
+    tx_id_policy: String,
+    #[serde(default = "default_redispute")]
+    redispute: String,
+}
+
+// Logs from before the policy existed always allowed re-disputes
+fn default_redispute() -> String {
+    RedisputePolicy::Allow.as_str().to_string()
+}
+
+impl WalHeader {
+    fn new(config: &EngineConfig) -> Self {
+        WalHeader {
+            version: WAL_VERSION,
+            dispute_withdrawals: config.dispute_withdrawals,
+            tx_id_policy: config.tx_id_policy.as_str().to_string(),
+            redispute: config.redispute.as_str().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WalEntry {
+    seq: u64,
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    amount: Option<Decimal>,
+}
+
+// What was found in an existing log
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WalReplay {
+    pub entries: u64,
+    // The last write was cut short by a crash and has been ignored
+    pub torn_tail: bool,
+    // Length of the log up to the end of the last good entry
+    valid_len: u64,
+}
+
+// Every line is `<crc32 of json, hex> <json>\n`, so a half written line is
+// told apart from a good one without trusting anything inside it
+fn encode_line(json: &str) -> String {
+    format!("{:08x} {}\n", crc32fast::hash(json.as_bytes()), json)
+}
+
+fn decode_line(line: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(line.strip_suffix(b"\n")?).ok()?;
+    let (crc, json) = line.split_once(' ')?;
+    let crc = u32::from_str_radix(crc, 16).ok()?;
+    (crc32fast::hash(json.as_bytes()) == crc).then_some(json)
+}
+
+// Append-only log of every transaction that changed the Engine's state, so
+// a crashed run can be rebuilt exactly. Rows are logged once apply() has
+// accepted them, the in memory state dies with the process anyway, so
+// nothing is lost by logging after rather than before. Not for an Engine on
+// a durable store, which commits its state itself: a reopened log would
+// apply its entries over it a second time
+#[derive(Debug)]
+pub struct Wal {
+    writer: BufWriter<File>,
+    next_seq: u64,
+    sync_every: usize,
+    unsynced: usize,
+}
+
+impl Wal {
+    // Replays whatever is already in the log into `engine`, then appends to
+    // it. A torn final entry is cut off, a new log starts with a header
+    pub fn open(
+        path: impl AsRef<Path>,
+        engine: &mut Engine,
+        sync_every: usize,
+    ) -> Result<(Wal, WalReplay), WalError> {
+        Wal::open_after(path, engine, sync_every, 0)
+    }
+
+    // As open(), for an engine restored from a snapshot taken once `applied`
+    // entries were in: those are only checked, the rest are replayed
+    pub fn open_after(
+        path: impl AsRef<Path>,
+        engine: &mut Engine,
+        sync_every: usize,
+        applied: u64,
+    ) -> Result<(Wal, WalReplay), WalError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let replay = replay_into(BufReader::new(&file), engine, true, applied)?;
+        if replay.torn_tail {
+            file.set_len(replay.valid_len)?;
+            file.sync_all()?;
+        }
+        drop(file);
+
+        // Appends always go to the end, wherever the reads above left off
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        if replay.valid_len == 0 {
+            let header = serde_json::to_string(&WalHeader::new(&engine.config))
+                .expect("WAL header always serializes");
+            file.write_all(encode_line(&header).as_bytes())?;
+            file.sync_all()?;
+        }
+        let wal = Wal {
+            writer: BufWriter::new(file),
+            next_seq: replay.entries + 1,
+            sync_every: sync_every.max(1),
+            unsynced: 0,
+        };
+        Ok((wal, replay))
+    }
+
+    pub fn append(&mut self, transaction: &TransactionRow) -> Result<(), WalError> {
+        let entry = WalEntry {
+            seq: self.next_seq,
+            tx_type: transaction.tx_type,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+        };
+        let json = serde_json::to_string(&entry).expect("WAL entry always serializes");
+        self.writer.write_all(encode_line(&json).as_bytes())?;
+        self.next_seq += 1;
+
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Flushes and fsyncs everything appended so far
+    pub fn sync(&mut self) -> Result<(), WalError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // The seq of the last entry appended, or replayed when nothing has been
+    // appended yet. A snapshot taken now records it for open_after()
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+}
+
+// Reject and IdempotentReplay both refuse a row that reuses a tx ID (and a
+// skipped replay isn't logged), so a log written under one replays the same
+// under the other. Warn applies reused ids, so it only matches itself
+fn replays_alike(logged: &EngineConfig, engine: &EngineConfig) -> bool {
+    let reuses_ids = |config: &EngineConfig| config.tx_id_policy == TxIdPolicy::Warn;
+    logged.dispute_withdrawals == engine.dispute_withdrawals
+        && logged.redispute == engine.redispute
+        && reuses_ids(logged) == reuses_ids(engine)
+}
+
+// Rebuilds an Engine from the log at `path` alone, with the settings in its
+// header, without touching the file. Every checksum and sequence number is
+// checked on the way
+pub fn replay_wal(path: impl AsRef<Path>) -> Result<(Engine, WalReplay), WalError> {
+    let mut engine = Engine::new();
+    let replay = replay_into(BufReader::new(File::open(path)?), &mut engine, false, 0)?;
+    Ok((engine, replay))
+}
+
+// Replays the log into `engine` as Wal::open_after() does, for a run that
+// must leave it as it was: nothing is appended, a torn final entry stays
+// where it is and a missing log is an empty one
+pub fn replay_wal_into(
+    path: impl AsRef<Path>,
+    engine: &mut Engine,
+    applied: u64,
+) -> Result<WalReplay, WalError> {
+    match File::open(path) {
+        Ok(file) => replay_into(BufReader::new(file), engine, true, applied),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            replay_into(std::io::empty(), engine, true, applied)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// With `check_config` the header must match the engine's settings, without it
+// the (still empty) engine takes them from the header. Entries up to `applied`
+// are already in the engine and are only checked
+fn replay_into(
+    mut reader: impl BufRead,
+    engine: &mut Engine,
+    check_config: bool,
+    applied: u64,
+) -> Result<WalReplay, WalError> {
+    let mut replay = WalReplay::default();
+    let mut line = Vec::new();
+    let mut line_number = 0;
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+
+        let Some(json) = decode_line(&line) else {
+            // Only the very last line can be a crash artifact
+            if reader.fill_buf()?.is_empty() {
+                replay.torn_tail = true;
+                break;
+            }
+            return Err(WalError::Corrupt { line: line_number });
+        };
+
+        if line_number == 1 {
+            let header: WalHeader =
+                serde_json::from_str(json).map_err(|_| WalError::Corrupt { line: line_number })?;
+            if header.version != WAL_VERSION {
+                return Err(WalError::UnsupportedVersion(header.version));
+            }
+            let tx_id_policy: TxIdPolicy = header
+                .tx_id_policy
+                .parse()
+                .map_err(|_| WalError::Corrupt { line: line_number })?;
+            let redispute: RedisputePolicy = header
+                .redispute
+                .parse()
+                .map_err(|_| WalError::Corrupt { line: line_number })?;
+            let config = EngineConfig {
+                dispute_withdrawals: header.dispute_withdrawals,
+                tx_id_policy,
+                redispute,
+            };
+            if !check_config {
+                engine.config = config;
+            } else if !replays_alike(&config, &engine.config) {
+                return Err(WalError::ConfigMismatch);
+            }
+        } else {
+            let entry: WalEntry =
+                serde_json::from_str(json).map_err(|_| WalError::Corrupt { line: line_number })?;
+            if entry.seq != replay.entries + 1 {
+                return Err(WalError::Corrupt { line: line_number });
+            }
+            if entry.seq > applied {
+                let row = TransactionRow::new(entry.tx_type, entry.client, entry.tx, entry.amount);
+                engine.apply(row).map_err(|error| WalError::Diverged {
+                    seq: entry.seq,
+                    error,
+                })?;
+            }
+            replay.entries += 1;
+        }
+        replay.valid_len += line.len() as u64;
+    }
+
+    if replay.entries < applied {
+        return Err(WalError::Behind {
+            entries: replay.entries,
+            applied,
+        });
+    }
+    Ok(replay)
+}
@@ -3,12 +3,14 @@
 // Fixed a race condition by hand in run_engine
 
 use assert_cmd::cargo::cargo_bin_cmd;
+use serde_json::json;
 use std::collections::HashMap;
 
-/// Run the payments engine with the given input file and return stdout
-fn run_engine(input_file: &str) -> String {
+/// Run the payments engine with the given input file and flags and return stdout
+fn run_engine(input_file: &str, extra_args: &[&str]) -> String {
     let mut cmd = cargo_bin_cmd!("take_home");
     let output = cmd
+        .args(extra_args)
         .args([input_file])
         .env("NO_LOG", "1")
         .output()
@@ -48,7 +50,12 @@ fn parse_output(output: &str) -> HashMap<u16, (String, String, String, bool)> {
 
 /// Run engine and compare output against expected file
 fn run_and_compare(test_name: &str) {
-    let output = run_engine(&format!("test_data/{}_input.csv", test_name));
+    run_and_compare_with_args(test_name, test_name, &[]);
+}
+
+/// Run engine with flags on `input_name` and compare against `expected_name`
+fn run_and_compare_with_args(input_name: &str, test_name: &str, extra_args: &[&str]) {
+    let output = run_engine(&format!("test_data/{}_input.csv", input_name), extra_args);
     let expected = std::fs::read_to_string(format!("test_data/{}_expected.csv", test_name))
         .expect("Failed to read expected file");
 
@@ -145,6 +152,46 @@ fn test_13_dispute_blocks_withdrawal() {
     run_and_compare("13_dispute_blocks_withdrawal");
 }
 
+// =============================================================================
+// Withdrawal Dispute Tests
+// =============================================================================
+
+#[test]
+fn test_28_withdrawal_dispute() {
+    run_and_compare_with_args(
+        "28_withdrawal_dispute",
+        "28_withdrawal_dispute",
+        &["--dispute-withdrawals"],
+    );
+}
+
+#[test]
+fn test_28_withdrawal_dispute_disabled() {
+    run_and_compare_with_args(
+        "28_withdrawal_dispute",
+        "28_withdrawal_dispute_disabled",
+        &[],
+    );
+}
+
+#[test]
+fn test_29_withdrawal_dispute_resolve() {
+    run_and_compare_with_args(
+        "29_withdrawal_dispute_resolve",
+        "29_withdrawal_dispute_resolve",
+        &["--dispute-withdrawals"],
+    );
+}
+
+#[test]
+fn test_30_withdrawal_chargeback() {
+    run_and_compare_with_args(
+        "30_withdrawal_chargeback",
+        "30_withdrawal_chargeback",
+        &["--dispute-withdrawals"],
+    );
+}
+
 // =============================================================================
 // Error Handling / Edge Cases
 // =============================================================================
@@ -179,6 +226,29 @@ fn test_18_duplicate_tx_id() {
     run_and_compare("18_duplicate_tx_id");
 }
 
+#[test]
+fn test_31_duplicate_tx_id_across_types() {
+    run_and_compare("31_duplicate_tx_id_across_types");
+}
+
+#[test]
+fn test_31_duplicate_tx_id_across_types_warn() {
+    run_and_compare_with_args(
+        "31_duplicate_tx_id_across_types",
+        "31_duplicate_tx_id_across_types_warn",
+        &["--tx-id-policy", "warn"],
+    );
+}
+
+#[test]
+fn test_32_replayed_batch() {
+    run_and_compare_with_args(
+        "32_replayed_batch",
+        "32_replayed_batch",
+        &["--tx-id-policy", "replay"],
+    );
+}
+
 #[test]
 fn test_19_double_dispute() {
     run_and_compare("19_double_dispute");
@@ -228,6 +298,25 @@ fn test_23_max_values() {
     run_and_compare("23_max_values");
 }
 
+#[test]
+fn test_33_balance_overflow() {
+    run_and_compare("33_balance_overflow");
+}
+
+#[test]
+fn test_36_redispute() {
+    run_and_compare("36_redispute");
+}
+
+#[test]
+fn test_36_redispute_deny() {
+    run_and_compare_with_args(
+        "36_redispute",
+        "36_redispute_deny",
+        &["--redispute", "deny"],
+    );
+}
+
 // =============================================================================
 // Complex Scenarios
 // =============================================================================
@@ -241,3 +330,1593 @@ fn test_21_complex_scenario() {
 fn test_comprehensive_scenario() {
     run_and_compare("comprehensive_test");
 }
+
+// =============================================================================
+// Output Ordering Tests
+// =============================================================================
+
+/// Run engine and compare stdout byte for byte, row order included
+fn run_and_compare_exact(input_name: &str, test_name: &str, extra_args: &[&str]) {
+    let output = run_engine(&format!("test_data/{}_input.csv", input_name), extra_args);
+    let expected = std::fs::read_to_string(format!("test_data/{}_expected.csv", test_name))
+        .expect("Failed to read expected file");
+
+    assert_eq!(output, expected, "{}: output mismatch", test_name);
+}
+
+#[test]
+fn test_output_ordered_by_client() {
+    run_and_compare_exact("11_multiple_clients", "11_multiple_clients", &[]);
+    run_and_compare_exact("comprehensive_test", "comprehensive_test", &[]);
+}
+
+#[test]
+fn test_output_sort_by_available() {
+    run_and_compare_exact(
+        "comprehensive_test",
+        "comprehensive_test_by_available",
+        &["--sort-by", "available"],
+    );
+}
+
+#[test]
+fn test_41_unchanged_balances_keep_their_scale() {
+    run_and_compare_exact("41_unchanged_balances", "41_unchanged_balances", &[]);
+}
+
+// =============================================================================
+// Rejections Report Tests
+// =============================================================================
+
+#[test]
+fn test_34_rejections() {
+    run_and_compare("34_rejections");
+}
+
+#[test]
+fn test_34_rejections_report() {
+    let rejections = format!("{}/34_rejections.csv", env!("CARGO_TARGET_TMPDIR"));
+    run_engine(
+        "test_data/34_rejections_input.csv",
+        &["--rejections", &rejections],
+    );
+
+    let output = std::fs::read_to_string(&rejections).expect("Failed to read rejections file");
+    let expected = std::fs::read_to_string("test_data/34_rejections_rejections_expected.csv")
+        .expect("Failed to read expected file");
+
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_37_awkward_fields() {
+    run_and_compare("37_awkward_fields");
+}
+
+#[test]
+fn test_37_awkward_fields_fast_parse() {
+    run_and_compare_with_args("37_awkward_fields", "37_awkward_fields", &["--fast-parse"]);
+}
+
+#[test]
+fn test_37_awkward_fields_report() {
+    let expected =
+        std::fs::read_to_string("test_data/37_awkward_fields_rejections_expected.csv").unwrap();
+
+    for flags in [&[][..], &["--fast-parse"]] {
+        let (_, _, rejections) = run_with_rejections(
+            "test_data/37_awkward_fields_input.csv",
+            "37_awkward_fields",
+            flags,
+        );
+        assert_eq!(rejections, expected, "{:?}", flags);
+    }
+}
+
+#[test]
+fn test_42_reordered_header_report() {
+    // A rejected row's fields are found by column name, not by position
+    let expected =
+        std::fs::read_to_string("test_data/42_reordered_header_rejections_expected.csv").unwrap();
+    let expected_output =
+        std::fs::read_to_string("test_data/42_reordered_header_expected.csv").unwrap();
+
+    for flags in [&[][..], &["--fast-parse"]] {
+        let (_, output, rejections) = run_with_rejections(
+            "test_data/42_reordered_header_input.csv",
+            "42_reordered_header",
+            flags,
+        );
+        assert_eq!(
+            parse_output(&output),
+            parse_output(&expected_output),
+            "{:?}",
+            flags
+        );
+        assert_eq!(rejections, expected, "{:?}", flags);
+    }
+}
+
+#[test]
+fn test_38_jsonl() {
+    // The comprehensive test's rows as JSON lines, picked by the extension
+    let output = run_engine("test_data/38_jsonl_input.jsonl", &[]);
+    let expected = std::fs::read_to_string("test_data/38_jsonl_expected.csv").unwrap();
+
+    assert_eq!(parse_output(&output), parse_output(&expected));
+}
+
+#[test]
+fn test_39_jsonl_awkward() {
+    let (code, output, rejections) = run_with_rejections(
+        "test_data/39_jsonl_awkward_input.jsonl",
+        "39_jsonl_awkward",
+        &[],
+    );
+    let expected = std::fs::read_to_string("test_data/39_jsonl_awkward_expected.csv").unwrap();
+    let expected_rejections =
+        std::fs::read_to_string("test_data/39_jsonl_awkward_rejections_expected.csv").unwrap();
+
+    assert_eq!(code, 3);
+    assert_eq!(output, expected);
+    assert_eq!(rejections, expected_rejections);
+}
+
+#[test]
+fn input_format_overrides_the_extension() {
+    let jsonl = std::fs::read_to_string("test_data/38_jsonl_input.jsonl").unwrap();
+    let expected = std::fs::read_to_string("test_data/38_jsonl_expected.csv").unwrap();
+
+    // stdin is csv unless told otherwise
+    let (_, output) = run_cli(&["-", "--input-format", "jsonl"], Some(&jsonl));
+    assert_eq!(parse_output(&output), parse_output(&expected));
+    let (code, output) = run_cli(&["-"], Some(&jsonl));
+    assert_eq!((code, parse_output(&output).len()), (3, 0));
+
+    let (code, output) = run_cli(
+        &["test_data/38_jsonl_input.jsonl", "--input-format", "csv"],
+        None,
+    );
+    assert_eq!((code, parse_output(&output).len()), (3, 0));
+}
+
+#[test]
+fn jsonl_and_csv_inputs_mix() {
+    let (_, mixed) = run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "test_data/38_jsonl_input.jsonl",
+        ],
+        None,
+    );
+    let (_, csv_only) = run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "test_data/comprehensive_test_input.csv",
+        ],
+        None,
+    );
+
+    assert_eq!(mixed, csv_only);
+}
+
+// =============================================================================
+// CLI Tests
+// =============================================================================
+
+/// Run the payments engine with raw arguments, returning (exit code, stdout)
+fn run_cli(args: &[&str], stdin: Option<&str>) -> (i32, String) {
+    let mut cmd = cargo_bin_cmd!("take_home");
+    cmd.args(args).env("NO_LOG", "1");
+    if let Some(stdin) = stdin {
+        cmd.write_stdin(stdin);
+    }
+    let output = cmd.output().expect("Failed to execute command");
+
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn test_cli_exit_codes() {
+    let (success, _) = run_cli(&["test_data/11_multiple_clients_input.csv"], None);
+    let (partial, _) = run_cli(&["test_data/02_insufficient_funds_input.csv"], None);
+    let (fatal, _) = run_cli(&["test_data/does_not_exist.csv"], None);
+    let (usage, _) = run_cli(&[], None);
+
+    assert_eq!(success, 0);
+    assert_eq!(partial, 3);
+    assert_eq!(fatal, 1);
+    assert_eq!(usage, 2);
+}
+
+#[test]
+fn test_cli_process_subcommand_matches_default() {
+    let input = "test_data/comprehensive_test_input.csv";
+    let (_, default) = run_cli(&[input], None);
+    let (_, process) = run_cli(&["process", input], None);
+
+    assert_eq!(default, process);
+}
+
+#[test]
+fn test_cli_reads_stdin() {
+    let input = std::fs::read_to_string("test_data/11_multiple_clients_input.csv").unwrap();
+    let expected = std::fs::read_to_string("test_data/11_multiple_clients_expected.csv").unwrap();
+
+    let (code, output) = run_cli(&["-"], Some(&input));
+
+    assert_eq!(code, 0);
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_cli_output_file() {
+    let output_path = format!("{}/cli_output.csv", env!("CARGO_TARGET_TMPDIR"));
+    let (_, stdout) = run_cli(
+        &[
+            "test_data/11_multiple_clients_input.csv",
+            "--output",
+            &output_path,
+        ],
+        None,
+    );
+
+    let output = std::fs::read_to_string(&output_path).unwrap();
+    let expected = std::fs::read_to_string("test_data/11_multiple_clients_expected.csv").unwrap();
+    assert!(stdout.is_empty());
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_cli_json_formats() {
+    let input = "test_data/comprehensive_test_input.csv";
+    let (_, csv) = run_cli(&[input], None);
+    let (_, json) = run_cli(&[input, "--format", "json"], None);
+    let (_, jsonl) = run_cli(&[input, "--format", "jsonl"], None);
+
+    let accounts: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+    let lines: Vec<serde_json::Value> = jsonl
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(accounts, lines);
+
+    // Same values as the csv, amounts kept as the same strings
+    let expected = parse_output(&csv);
+    assert_eq!(accounts.len(), expected.len());
+    for account in &accounts {
+        let values = &expected[&(account["client"].as_u64().unwrap() as u16)];
+        assert_eq!(account["available"], values.0.as_str());
+        assert_eq!(account["held"], values.1.as_str());
+        assert_eq!(account["total"], values.2.as_str());
+        assert_eq!(account["locked"], values.3);
+    }
+}
+
+#[test]
+fn test_cli_json_keeps_four_decimal_places() {
+    let (_, json) = run_cli(
+        &["-", "--format", "json"],
+        Some("type,client,tx,amount\ndeposit,1,1,1.2345\ndeposit,1,2,0.0001\n"),
+    );
+
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+        json!([{"client": 1, "available": "1.2346", "held": "0", "total": "1.2346", "locked": false}])
+    );
+}
+
+#[test]
+fn test_cli_details() {
+    let input = "test_data/comprehensive_test_input.csv";
+    let (_, csv) = run_cli(&[input, "--details"], None);
+    let (_, json) = run_cli(&[input, "--details", "--format", "json"], None);
+
+    assert_eq!(
+        csv,
+        "client,available,held,total,locked,status,open_disputes\n\
+         1,75,0,75,true,locked,0\n\
+         2,150,0,150,false,active,0\n\
+         3,0,500,500,false,disputed,1\n"
+    );
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&json).unwrap()[2],
+        json!({
+            "client": 3, "available": "0", "held": "500", "total": "500", "locked": false,
+            "status": "disputed", "open_disputes": 1
+        })
+    );
+}
+
+#[test]
+fn test_cli_json_for_every_report() {
+    let (_, empty) = run_cli(
+        &[
+            "validate",
+            "test_data/11_multiple_clients_input.csv",
+            "--format",
+            "json",
+        ],
+        None,
+    );
+    assert_eq!(empty, "[]\n");
+
+    let (_, rejections) = run_cli(
+        &[
+            "validate",
+            "test_data/02_insufficient_funds_input.csv",
+            "--format",
+            "jsonl",
+        ],
+        None,
+    );
+    let rejection: serde_json::Value = serde_json::from_str(rejections.trim()).unwrap();
+    assert_eq!(rejection["reason"], "insufficient_funds");
+    assert_eq!(rejection["line"], 4);
+
+    let (_, transaction) = run_cli(
+        &[
+            "inspect",
+            "test_data/comprehensive_test_input.csv",
+            "--tx",
+            "7",
+            "--format",
+            "json",
+        ],
+        None,
+    );
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&transaction).unwrap()[0]["state"],
+        "disputed"
+    );
+}
+
+#[test]
+fn test_cli_validate() {
+    let (code, output) = run_cli(&["validate", "test_data/34_rejections_input.csv"], None);
+    let expected =
+        std::fs::read_to_string("test_data/34_rejections_rejections_expected.csv").unwrap();
+
+    assert_eq!(code, 3);
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_cli_replay() {
+    let (code, output) = run_cli(&["replay", "test_data/32_replayed_batch_input.csv"], None);
+    let expected = std::fs::read_to_string("test_data/32_replayed_batch_expected.csv").unwrap();
+
+    assert_eq!(code, 0);
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_cli_inspect() {
+    let input = "test_data/comprehensive_test_input.csv";
+
+    let (_, account) = run_cli(&["inspect", input, "--client", "3"], None);
+    let (_, transaction) = run_cli(&["inspect", input, "--tx", "7"], None);
+
+    assert_eq!(
+        account,
+        "client,available,held,total,locked\n3,0,500,500,false\n"
+    );
+    assert_eq!(
+        transaction,
+        "type,client,tx,amount,state\ndeposit,3,7,500,disputed\n"
+    );
+}
+
+#[test]
+fn validate_and_inspect_leave_the_state_alone() {
+    let dir = env!("CARGO_TARGET_TMPDIR");
+    let day1 = "test_data/35_multi_file_day1_input.csv";
+    let day2 = "test_data/35_multi_file_day2_input.csv";
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+
+    for (flag, name) in [
+        ("--wal", "read_only.wal"),
+        ("--tx-store", "read_only.redb"),
+        ("--sqlite", "read_only.db"),
+    ] {
+        let path = format!("{}/{}", dir, name);
+        let _ = std::fs::remove_file(&path);
+        run_cli(&[day1, flag, &path], None);
+
+        // Run twice, each sees day 2 as new
+        let validated = run_cli(&["validate", day2, flag, &path], None);
+        assert_eq!(run_cli(&["validate", day2, flag, &path], None), validated);
+        let inspected = run_cli(&["inspect", day2, "--client", "1", flag, &path], None);
+        assert_eq!(
+            run_cli(&["inspect", day2, "--client", "1", flag, &path], None),
+            inspected,
+            "{}",
+            flag
+        );
+
+        let (_, output) = run_cli(&[day2, flag, &path], None);
+        assert_eq!(output, expected, "{}", flag);
+    }
+
+    // Nor is a store created that wasn't there
+    let missing = format!("{}/read_only_missing.db", dir);
+    let _ = std::fs::remove_file(&missing);
+    run_cli(&["validate", day1, "--sqlite", &missing], None);
+    assert!(!std::path::Path::new(&missing).exists());
+}
+
+// =============================================================================
+// Multiple Input Tests
+// =============================================================================
+
+#[test]
+fn test_35_multi_file() {
+    let rejections = format!("{}/35_multi_file.csv", env!("CARGO_TARGET_TMPDIR"));
+    let (_, output) = run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "test_data/35_multi_file_day2_input.csv",
+            "--rejections",
+            &rejections,
+        ],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    let expected_rejections =
+        std::fs::read_to_string("test_data/35_multi_file_rejections_expected.csv").unwrap();
+    assert_eq!(output, expected);
+    assert_eq!(
+        std::fs::read_to_string(&rejections).unwrap(),
+        expected_rejections
+    );
+}
+
+#[test]
+fn test_35_multi_file_with_stdin() {
+    let day2 = std::fs::read_to_string("test_data/35_multi_file_day2_input.csv").unwrap();
+
+    let (_, output) = run_cli(
+        &["test_data/35_multi_file_day1_input.csv", "-"],
+        Some(&day2),
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_35_multi_file_resumed_from_snapshot() {
+    let state = format!("{}/35_multi_file_state.json", env!("CARGO_TARGET_TMPDIR"));
+
+    let (code, _) = run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "--save-state",
+            &state,
+        ],
+        None,
+    );
+    // Day one has an overdrawn withdrawal, the snapshot is still written
+    assert_eq!(code, 3);
+
+    let (_, output) = run_cli(
+        &[
+            "test_data/35_multi_file_day2_input.csv",
+            "--load-state",
+            &state,
+        ],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_cli_unreadable_snapshot_is_fatal() {
+    let (code, output) = run_cli(
+        &[
+            "test_data/01_basic_deposits_withdrawals_input.csv",
+            "--load-state",
+            "test_data/01_basic_deposits_withdrawals_input.csv",
+        ],
+        None,
+    );
+
+    assert_eq!(code, 1);
+    assert_eq!(output, "");
+}
+
+#[test]
+fn test_35_multi_file_resumed_from_wal() {
+    let wal = format!("{}/35_multi_file.wal", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+
+    run_cli(
+        &["test_data/35_multi_file_day1_input.csv", "--wal", &wal],
+        None,
+    );
+    let (_, output) = run_cli(
+        &["test_data/35_multi_file_day2_input.csv", "--wal", &wal],
+        None,
+    );
+    let (code, replayed) = run_cli(&["replay-wal", &wal], None);
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+    assert_eq!(code, 0);
+    assert_eq!(replayed, expected);
+}
+
+#[test]
+fn test_35_multi_file_resumed_from_snapshot_and_wal() {
+    let wal = format!("{}/35_multi_file_both.wal", env!("CARGO_TARGET_TMPDIR"));
+    let state = format!("{}/35_multi_file_both.json", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+
+    run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "--wal",
+            &wal,
+            "--save-state",
+            &state,
+        ],
+        None,
+    );
+    // Day 1 is in the snapshot and the WAL, it is only applied once
+    let (code, output) = run_cli(
+        &[
+            "test_data/35_multi_file_day2_input.csv",
+            "--wal",
+            &wal,
+            "--load-state",
+            &state,
+            "--save-state",
+            &state,
+        ],
+        None,
+    );
+    let (_, replayed) = run_cli(&["replay-wal", &wal], None);
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    // Only the duplicate deposit of day 2 is rejected
+    assert_eq!(code, 3);
+    assert_eq!(output, expected);
+    assert_eq!(replayed, expected);
+}
+
+#[test]
+fn test_35_multi_file_replay_reopens_a_process_wal() {
+    let wal = format!("{}/35_multi_file_replay.wal", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+
+    run_cli(
+        &["test_data/35_multi_file_day1_input.csv", "--wal", &wal],
+        None,
+    );
+    let (_, output) = run_cli(
+        &[
+            "replay",
+            "test_data/35_multi_file_day2_input.csv",
+            "--wal",
+            &wal,
+        ],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_cli_replay_wal_rejects_corruption() {
+    let wal = format!("{}/corrupt.wal", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+    run_cli(
+        &["test_data/35_multi_file_day1_input.csv", "--wal", &wal],
+        None,
+    );
+    let contents = std::fs::read_to_string(&wal).unwrap();
+    std::fs::write(&wal, contents.replacen("100.0", "900.0", 1)).unwrap();
+
+    let (code, output) = run_cli(&["replay-wal", &wal], None);
+
+    assert_eq!(code, 1);
+    assert_eq!(output, "");
+}
+
+#[test]
+fn test_35_multi_file_with_disk_tx_store() {
+    let store = format!("{}/35_multi_file.redb", env!("CARGO_TARGET_TMPDIR"));
+    let state = format!(
+        "{}/35_multi_file_disk_state.json",
+        env!("CARGO_TARGET_TMPDIR")
+    );
+    let _ = std::fs::remove_file(&store);
+
+    run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "--tx-store",
+            &store,
+            "--save-state",
+            &state,
+        ],
+        None,
+    );
+    let (_, output) = run_cli(
+        &[
+            "test_data/35_multi_file_day2_input.csv",
+            "--tx-store",
+            &store,
+            "--load-state",
+            &state,
+        ],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+    // The snapshot refers to the store rather than copying it
+    let snapshot = std::fs::read_to_string(&state).unwrap();
+    assert!(snapshot.contains("\"transactions_in_store\": true"));
+    assert!(!snapshot.contains("\"tx\""));
+}
+
+#[test]
+fn test_35_multi_file_with_disk_tx_store_alone() {
+    let store = format!("{}/35_multi_file_alone.redb", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&store);
+
+    // Accounts are kept in the store too, so day 2 resumes without a snapshot
+    run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "--tx-store",
+            &store,
+        ],
+        None,
+    );
+    let (_, output) = run_cli(
+        &[
+            "test_data/35_multi_file_day2_input.csv",
+            "--tx-store",
+            &store,
+        ],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_35_multi_file_with_sqlite() {
+    let db = format!("{}/35_multi_file.db", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&db);
+
+    // Accounts are kept in the database, so day 2 resumes without a snapshot
+    run_cli(
+        &["test_data/35_multi_file_day1_input.csv", "--sqlite", &db],
+        None,
+    );
+    let (_, output) = run_cli(
+        &["test_data/35_multi_file_day2_input.csv", "--sqlite", &db],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    let mut accounts = String::from("client,available,held,total,locked\n");
+    let mut statement = conn
+        .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+        .unwrap();
+    let mut rows = statement.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let (client, available, held, total, locked): (u16, String, String, String, bool) = (
+            row.get(0).unwrap(),
+            row.get(1).unwrap(),
+            row.get(2).unwrap(),
+            row.get(3).unwrap(),
+            row.get(4).unwrap(),
+        );
+        accounts.push_str(&format!(
+            "{},{},{},{},{}\n",
+            client, available, held, total, locked
+        ));
+    }
+    assert_eq!(accounts, expected);
+
+    let events: Vec<(u32, String)> = conn
+        .prepare("SELECT tx, event FROM dispute_events ORDER BY id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(events, vec![(1, "dispute".to_string())]);
+    let state: String = conn
+        .query_row("SELECT state FROM transactions WHERE tx = 1", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(state, "disputed");
+}
+
+#[test]
+fn sqlite_and_tx_store_conflict() {
+    let (code, _) = run_cli(
+        &[
+            "test_data/01_basic_deposits_withdrawals_input.csv",
+            "--sqlite",
+            "a.db",
+            "--tx-store",
+            "b.redb",
+        ],
+        None,
+    );
+
+    assert_eq!(code, 2);
+}
+
+#[test]
+fn wal_conflicts_with_on_disk_stores() {
+    for store in ["--sqlite", "--tx-store"] {
+        let (code, _) = run_cli(
+            &[
+                "test_data/01_basic_deposits_withdrawals_input.csv",
+                store,
+                "a.db",
+                "--wal",
+                "a.wal",
+            ],
+            None,
+        );
+
+        assert_eq!(code, 2);
+    }
+}
+
+/// Run with extra flags, returning exit code, stdout and the --rejections file
+fn run_with_rejections(input_file: &str, name: &str, extra_args: &[&str]) -> (i32, String, String) {
+    let rejections = format!("{}/{}_rejections.csv", env!("CARGO_TARGET_TMPDIR"), name);
+    let mut args = vec![input_file, "--rejections", &rejections];
+    args.extend_from_slice(extra_args);
+    let (code, output) = run_cli(&args, None);
+    (code, output, std::fs::read_to_string(&rejections).unwrap())
+}
+
+fn every_input() -> Vec<String> {
+    let mut inputs: Vec<String> = std::fs::read_dir("test_data")
+        .unwrap()
+        .map(|entry| entry.unwrap().path().to_string_lossy().into_owned())
+        .filter(|path| path.ends_with("_input.csv"))
+        .collect();
+    inputs.sort();
+    inputs
+}
+
+#[test]
+fn workers_match_sequential_output_for_every_input() {
+    for input in &every_input() {
+        for flags in [
+            &[][..],
+            &["--dispute-withdrawals", "--tx-id-policy", "warn"],
+        ] {
+            let sequential = run_with_rejections(input, "sequential", flags);
+            let parallel =
+                run_with_rejections(input, "parallel", &[flags, &["--workers", "3"]].concat());
+
+            assert_eq!(parallel, sequential, "{} {:?}", input, flags);
+        }
+    }
+}
+
+#[test]
+fn workers_resume_from_snapshot_and_wal() {
+    let state = format!("{}/35_multi_file_workers.json", env!("CARGO_TARGET_TMPDIR"));
+    let wal = format!("{}/35_multi_file_workers.wal", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+
+    run_cli(
+        &[
+            "test_data/35_multi_file_day1_input.csv",
+            "--workers",
+            "2",
+            "--save-state",
+            &state,
+        ],
+        None,
+    );
+    let (_, output) = run_cli(
+        &[
+            "test_data/35_multi_file_day2_input.csv",
+            "--workers",
+            "2",
+            "--load-state",
+            &state,
+            "--wal",
+            &wal,
+        ],
+        None,
+    );
+
+    let expected = std::fs::read_to_string("test_data/35_multi_file_expected.csv").unwrap();
+    assert_eq!(output, expected);
+    // Day 2's applied rows are logged in input order, after the header
+    let logged: Vec<String> = std::fs::read_to_string(&wal)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let entry: serde_json::Value = serde_json::from_str(&line[9..]).unwrap();
+            format!("{} {}", entry["type"].as_str().unwrap(), entry["tx"])
+        })
+        .collect();
+    assert_eq!(logged, vec!["dispute 1", "deposit 4", "withdrawal 5"]);
+}
+
+#[test]
+fn fast_parse_matches_serde_for_every_input() {
+    for input in &every_input() {
+        let serde = run_with_rejections(input, "serde", &[]);
+        let fast = run_with_rejections(input, "fast_parse", &["--fast-parse"]);
+
+        assert_eq!(fast, serde, "{}", input);
+    }
+}
+
+// =============================================================================
+// Serve
+// =============================================================================
+
+/// A `serve` or `http` process, killed when dropped
+struct Server {
+    child: std::process::Child,
+    address: String,
+}
+
+impl Server {
+    fn start(command: &str, args: &[&str]) -> Server {
+        use std::io::BufRead;
+
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_take_home"))
+            .arg(command)
+            .args(args)
+            .env("NO_LOG", "1")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .expect("Failed to start server");
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let address = line
+            .trim()
+            .strip_prefix("listening on ")
+            .unwrap_or_else(|| panic!("unexpected announcement {:?}", line))
+            .to_string();
+        Server { child, address }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Sends `rows` one at a time over `stream`, returning the answer to each
+fn send_rows<S: std::io::Read + std::io::Write>(
+    stream: S,
+    header: &str,
+    rows: &[&str],
+) -> Vec<String> {
+    use std::io::BufRead;
+
+    let mut stream = std::io::BufReader::new(stream);
+    writeln!(stream.get_mut(), "{}", header).unwrap();
+    let mut answers = Vec::new();
+    for row in rows {
+        writeln!(stream.get_mut(), "{}", row).unwrap();
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        // The answers' own header comes back ahead of the first one
+        if line == "line,tx,result,reason\n" {
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+        }
+        answers.push(line.trim_end().to_string());
+    }
+    answers
+}
+
+#[test]
+fn serve_answers_every_row() {
+    let server = Server::start("serve", &["--listen", "127.0.0.1:0"]);
+    let stream = std::net::TcpStream::connect(&server.address).unwrap();
+
+    let answers = send_rows(
+        stream,
+        "type, client, tx, amount",
+        &[
+            "deposit, 1, 1, 5.0",
+            "withdrawal, 1, 2, 9",
+            "refund, 1, 3, 1",
+            "deposit, 1, 1, 2",
+            "dispute, 1, 1,",
+        ],
+    );
+
+    assert_eq!(
+        answers,
+        vec![
+            "2,1,accepted,",
+            "3,2,rejected,insufficient_funds",
+            "4,3,rejected,parse_error",
+            "5,1,rejected,duplicate_tx",
+            "6,1,accepted,",
+        ]
+    );
+}
+
+#[test]
+fn serve_applies_every_connection_to_one_state() {
+    let wal = format!("{}/serve_connections.wal", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+    let server = Server::start("serve", &["--listen", "127.0.0.1:0", "--wal", &wal]);
+
+    // Every connection deposits into client 1 and one client of its own
+    let connections: Vec<_> = (0..8u32)
+        .map(|id| {
+            let address = server.address.clone();
+            std::thread::spawn(move || {
+                let rows: Vec<String> = (0..25u32)
+                    .map(|i| {
+                        let client = if i % 2 == 0 { 1 } else { id + 2 };
+                        format!("deposit,{},{},1.5", client, id * 100 + i + 1)
+                    })
+                    .collect();
+                let rows: Vec<&str> = rows.iter().map(String::as_str).collect();
+                let stream = std::net::TcpStream::connect(address).unwrap();
+                send_rows(stream, "type,client,tx,amount", &rows)
+            })
+        })
+        .collect();
+    for connection in connections {
+        let answers = connection.join().unwrap();
+        assert_eq!(answers.len(), 25);
+        assert!(answers.iter().all(|answer| answer.ends_with(",accepted,")));
+    }
+    drop(server);
+
+    // Rows are only answered once they are in the WAL
+    let (code, output) = run_cli(&["replay-wal", &wal], None);
+    let accounts = parse_output(&output);
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 9);
+    assert_eq!(accounts[&1].0, "156.0");
+    assert_eq!(accounts[&2].0, "18.0");
+}
+
+#[test]
+fn serve_batches_one_connections_rows() {
+    use std::io::{BufRead, Write};
+
+    let wal = format!("{}/serve_batches.wal", env!("CARGO_TARGET_TMPDIR"));
+    let log = format!("{}/serve_batches.log", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+    let _ = std::fs::remove_file(&log);
+    let server = Server::start(
+        "serve",
+        &["--listen", "127.0.0.1:0", "--wal", &wal, "--log-file", &log],
+    );
+    let stream = std::net::TcpStream::connect(&server.address).unwrap();
+
+    // Every row is sent without waiting for any answer
+    let rows = 2000u32;
+    let mut input = stream.try_clone().unwrap();
+    let sender = std::thread::spawn(move || {
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..=rows {
+            csv.push_str(&format!("deposit,1,{},1\n", tx));
+        }
+        input.write_all(csv.as_bytes()).unwrap();
+    });
+    let answers: Vec<String> = std::io::BufReader::new(stream)
+        .lines()
+        .skip(1)
+        .take(rows as usize)
+        .map(Result::unwrap)
+        .collect();
+    sender.join().unwrap();
+    assert_eq!(answers.len(), rows as usize);
+    for (i, answer) in answers.iter().enumerate() {
+        assert_eq!(answer, &format!("{},{},accepted,", i + 2, i + 1));
+    }
+
+    // The log is flushed every second
+    let mut commits = Vec::new();
+    for _ in 0..50 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        commits = std::fs::read_to_string(&log)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split("Committed ").nth(1))
+            .map(|rest| rest.trim_end_matches(" rows").parse::<u32>().unwrap())
+            .collect();
+        if commits.iter().sum::<u32>() == rows {
+            break;
+        }
+    }
+    drop(server);
+    assert_eq!(commits.iter().sum::<u32>(), rows);
+    assert!(
+        commits.len() < rows as usize / 10,
+        "{} commits for {} rows",
+        commits.len(),
+        rows
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_listens_on_a_unix_socket() {
+    let socket = format!("{}/serve.sock", env!("CARGO_TARGET_TMPDIR"));
+    let server = Server::start("serve", &["--unix", &socket]);
+    assert_eq!(server.address, socket);
+    let stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+
+    let answers = send_rows(
+        stream,
+        "type,client,tx,amount",
+        &["deposit,7,1,1", "withdrawal,7,2,2"],
+    );
+
+    assert_eq!(
+        answers,
+        vec!["2,1,accepted,", "3,2,rejected,insufficient_funds"]
+    );
+}
+
+#[test]
+fn serve_needs_an_address() {
+    let (code, _) = run_cli(&["serve"], None);
+    assert_eq!(code, 2);
+}
+
+// =============================================================================
+// HTTP API
+// =============================================================================
+
+/// Status and JSON body of an API response, errors included
+fn api(response: Result<ureq::Response, ureq::Error>) -> (u16, serde_json::Value) {
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => panic!("request failed: {}", err),
+    };
+    (response.status(), response.into_json().unwrap())
+}
+
+fn post(server: &Server, body: serde_json::Value) -> (u16, serde_json::Value) {
+    api(ureq::post(&format!("http://{}/transactions", server.address)).send_json(body))
+}
+
+fn get(server: &Server, path: &str) -> (u16, serde_json::Value) {
+    api(ureq::get(&format!("http://{}{}", server.address, path)).call())
+}
+
+#[test]
+fn http_posts_single_and_batched_transactions() {
+    let server = Server::start("http", &["--listen", "127.0.0.1:0"]);
+
+    let (status, body) = post(
+        &server,
+        json!({"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({"tx": 1, "result": "accepted"}));
+
+    let (status, body) = post(
+        &server,
+        json!({"type": "withdrawal", "client": 1, "tx": 2, "amount": "20"}),
+    );
+    assert_eq!(status, 422);
+    assert_eq!(body["reason"], "insufficient_funds");
+
+    let (status, body) = post(
+        &server,
+        json!([
+            {"type": "deposit", "client": 2, "tx": 3, "amount": "4.0000"},
+            {"type": "deposit", "client": 2, "tx": 4, "amount": 1.5},
+            {"type": "dispute", "client": 1, "tx": 1},
+            {"type": "deposit", "client": 2, "tx": 3, "amount": "1"},
+        ]),
+    );
+    assert_eq!(status, 200);
+    let results: Vec<(&str, &str)> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            (
+                r["result"].as_str().unwrap(),
+                r["reason"].as_str().unwrap_or(""),
+            )
+        })
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            ("accepted", ""),
+            ("rejected", "parse_error"),
+            ("accepted", ""),
+            ("rejected", "duplicate_tx"),
+        ]
+    );
+
+    let (status, _) = post(&server, json!({"type": "refund", "client": 1, "tx": 9}));
+    assert_eq!(status, 400);
+    let (status, body) = post(
+        &server,
+        json!({"type": "deposit", "client": 1, "tx": 4294967297u64, "amount": "1"}),
+    );
+    assert_eq!(status, 400);
+    assert_eq!(body["reason"], "parse_error");
+    assert!(body["tx"].is_null());
+    let (status, _) =
+        api(ureq::post(&format!("http://{}/transactions", server.address)).send_string("not json"));
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn http_reads_accounts_and_transactions() {
+    let server = Server::start("http", &["--listen", "127.0.0.1:0"]);
+    post(
+        &server,
+        json!([
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "10.50"},
+            {"type": "deposit", "client": 1, "tx": 2, "amount": "2"},
+            {"type": "dispute", "client": 1, "tx": 2},
+        ]),
+    );
+
+    assert_eq!(
+        get(&server, "/accounts/1"),
+        (
+            200,
+            json!({"client": 1, "available": "10.5", "held": "2", "total": "12.5", "locked": false})
+        )
+    );
+    assert_eq!(
+        get(&server, "/transactions/2"),
+        (
+            200,
+            json!({"type": "deposit", "client": 1, "tx": 2, "amount": "2", "state": "disputed"})
+        )
+    );
+    assert_eq!(get(&server, "/transactions/1").1["state"], "processed");
+
+    assert_eq!(get(&server, "/accounts/7").0, 404);
+    assert_eq!(get(&server, "/transactions/7").0, 404);
+    assert_eq!(get(&server, "/accounts/abc").0, 400);
+    assert_eq!(get(&server, "/nothing").0, 404);
+    assert_eq!(
+        api(ureq::delete(&format!("http://{}/accounts/1", server.address)).call()).0,
+        405
+    );
+}
+
+#[test]
+fn http_pages_through_accounts() {
+    let server = Server::start("http", &["--listen", "127.0.0.1:0"]);
+    let deposits: Vec<_> = (1..=25u32)
+        .rev()
+        .map(|client| json!({"type": "deposit", "client": client, "tx": client, "amount": "1"}))
+        .collect();
+    post(&server, serde_json::Value::Array(deposits));
+
+    let (status, body) = get(&server, "/accounts?page=3&per_page=10");
+    assert_eq!(status, 200);
+    assert_eq!(body["total"], 25);
+    assert_eq!(body["page"], 3);
+    assert_eq!(body["per_page"], 10);
+    let clients: Vec<u64> = body["accounts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|account| account["client"].as_u64().unwrap())
+        .collect();
+    assert_eq!(clients, vec![21, 22, 23, 24, 25]);
+
+    assert_eq!(
+        get(&server, "/accounts").1["accounts"]
+            .as_array()
+            .unwrap()
+            .len(),
+        25
+    );
+    assert_eq!(get(&server, "/accounts?page=9").1["accounts"], json!([]));
+    assert_eq!(get(&server, "/accounts?per_page=0").0, 400);
+}
+
+#[test]
+fn http_matches_the_batch_run() {
+    let wal = format!("{}/http_batch.wal", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&wal);
+    let server = Server::start("http", &["--listen", "127.0.0.1:0", "--wal", &wal]);
+
+    // The comprehensive test's rows, posted a few at a time
+    let input = std::fs::read_to_string("test_data/comprehensive_test_input.csv").unwrap();
+    let rows: Vec<serde_json::Value> = input
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let (client, tx) = (
+                fields.get(1)?.parse::<u16>().ok()?,
+                fields.get(2)?.parse::<u32>().ok()?,
+            );
+            let mut row = json!({"type": fields[0], "client": client, "tx": tx});
+            if let Some(amount) = fields.get(3).filter(|amount| !amount.is_empty()) {
+                row["amount"] = json!(amount);
+            }
+            Some(row)
+        })
+        .collect();
+    for chunk in rows.chunks(7) {
+        assert_eq!(
+            post(&server, serde_json::Value::Array(chunk.to_vec())).0,
+            200
+        );
+    }
+
+    let (_, body) = get(&server, "/accounts?per_page=1000");
+    let expected = parse_output(&run_engine("test_data/comprehensive_test_input.csv", &[]));
+    let accounts = body["accounts"].as_array().unwrap();
+    assert_eq!(accounts.len(), expected.len());
+    for account in accounts {
+        let client = account["client"].as_u64().unwrap() as u16;
+        let values = &expected[&client];
+        assert_eq!(account["available"], values.0.as_str(), "client {}", client);
+        assert_eq!(account["held"], values.1.as_str(), "client {}", client);
+        assert_eq!(account["total"], values.2.as_str(), "client {}", client);
+        assert_eq!(account["locked"], values.3, "client {}", client);
+    }
+
+    // Everything answered is in the WAL
+    drop(server);
+    let (_, replayed) = run_cli(&["replay-wal", &wal], None);
+    assert_eq!(parse_output(&replayed), expected);
+}
+
+fn read_parquet(path: &str) -> Vec<arrow_array::RecordBatch> {
+    let file = std::fs::File::open(path).unwrap();
+    parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)
+        .unwrap()
+        .build()
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+}
+
+fn parquet_column(batches: &[arrow_array::RecordBatch], column: &str) -> Vec<String> {
+    batches
+        .iter()
+        .flat_map(|batch| {
+            let array = batch.column_by_name(column).unwrap();
+            (0..array.len())
+                .map(|row| {
+                    if array.is_null(row) {
+                        String::new()
+                    } else {
+                        parquet_value(array, row)
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn parquet_value(array: &arrow_array::ArrayRef, row: usize) -> String {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt16Type, UInt64Type};
+    use arrow_schema::DataType;
+
+    match array.data_type() {
+        DataType::Utf8 => array.as_string::<i32>().value(row).to_string(),
+        DataType::UInt16 => array.as_primitive::<UInt16Type>().value(row).to_string(),
+        DataType::UInt64 => array.as_primitive::<UInt64Type>().value(row).to_string(),
+        DataType::Boolean => array.as_boolean().value(row).to_string(),
+        DataType::Decimal128(_, scale) => rust_decimal::Decimal::from_i128_with_scale(
+            array.as_primitive::<Decimal128Type>().value(row),
+            *scale as u32,
+        )
+        .normalize()
+        .to_string(),
+        other => panic!("unexpected column type {}", other),
+    }
+}
+
+#[test]
+fn test_cli_export() {
+    let input = "test_data/34_rejections_input.csv";
+    let (_, csv) = run_cli(&[input], None);
+
+    for workers in ["1", "2"] {
+        let accounts = format!(
+            "{}/export_accounts_{}.parquet",
+            env!("CARGO_TARGET_TMPDIR"),
+            workers
+        );
+        let transactions = format!(
+            "{}/export_transactions_{}.parquet",
+            env!("CARGO_TARGET_TMPDIR"),
+            workers
+        );
+        let (code, stdout) = run_cli(
+            &[
+                "export",
+                input,
+                "--workers",
+                workers,
+                "--accounts",
+                &accounts,
+                "--transactions",
+                &transactions,
+            ],
+            None,
+        );
+        assert_eq!(code, 3);
+        assert_eq!(stdout, "");
+
+        // Same accounts as the csv report
+        let batches = read_parquet(&accounts);
+        let clients = parquet_column(&batches, "client");
+        let exported: HashMap<u16, (String, String, String, bool)> = clients
+            .iter()
+            .zip(parquet_column(&batches, "available"))
+            .zip(parquet_column(&batches, "held"))
+            .zip(parquet_column(&batches, "total"))
+            .zip(parquet_column(&batches, "locked"))
+            .map(|((((client, available), held), total), locked)| {
+                (
+                    client.parse().unwrap(),
+                    (available, held, total, locked == "true"),
+                )
+            })
+            .collect();
+        assert_eq!(exported, parse_output(&csv));
+
+        // Every row, in input order, with its fate and the state it left
+        let batches = read_parquet(&transactions);
+        assert_eq!(
+            parquet_column(&batches, "line"),
+            (2..=12).map(|line| line.to_string()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            parquet_column(&batches, "reason"),
+            [
+                "",
+                "insufficient_funds",
+                "parse_error",
+                "parse_error",
+                "client_mismatch",
+                "duplicate_tx",
+                "not_disputed",
+                "invalid_amount",
+                "",
+                "",
+                "account_locked",
+            ]
+        );
+        assert_eq!(
+            parquet_column(&batches, "state"),
+            [
+                "processed",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "disputed",
+                "charged_back",
+                ""
+            ]
+        );
+        assert_eq!(
+            parquet_column(&batches, "amount"),
+            ["100", "500", "", "", "", "20", "", "", "", "", "5"]
+        );
+    }
+}
+
+#[test]
+fn test_cli_export_needs_a_target() {
+    let (code, _) = run_cli(&["export", "test_data/34_rejections_input.csv"], None);
+    assert_eq!(code, 2);
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Writes `data` compressed as `name` in the temp dir, returning its path
+fn compressed_input(name: &str, data: &[u8]) -> String {
+    let path = format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name);
+    let compressed = if name.ends_with(".gz") {
+        gzip(data)
+    } else {
+        zstd::encode_all(data, 0).unwrap()
+    };
+    std::fs::write(&path, compressed).unwrap();
+    path
+}
+
+#[test]
+fn test_cli_compressed_inputs() {
+    let csv = std::fs::read("test_data/comprehensive_test_input.csv").unwrap();
+    let (_, expected) = run_cli(&["test_data/comprehensive_test_input.csv"], None);
+
+    // Found by their first bytes, not their names
+    let misnamed = format!("{}/compressed_gzip.csv", env!("CARGO_TARGET_TMPDIR"));
+    std::fs::write(&misnamed, gzip(&csv)).unwrap();
+    for path in [
+        compressed_input("compressed.csv.gz", &csv),
+        compressed_input("compressed.csv.zst", &csv),
+        misnamed,
+    ] {
+        let (code, output) = run_cli(&[&path], None);
+        assert_eq!((code, output.as_str()), (3, expected.as_str()), "{}", path);
+    }
+
+    // stdin too, and gzip members one after another read as one file
+    let (head, tail) = csv.split_at(csv.iter().position(|&b| b == b'\n').unwrap() + 40);
+    let output = cargo_bin_cmd!("take_home")
+        .arg("-")
+        .env("NO_LOG", "1")
+        .write_stdin([gzip(head), gzip(tail)].concat())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[test]
+fn test_cli_compressed_jsonl_input() {
+    let jsonl = std::fs::read("test_data/38_jsonl_input.jsonl").unwrap();
+    let expected = std::fs::read_to_string("test_data/38_jsonl_expected.csv").unwrap();
+
+    // The extension under .gz or .zst still picks the format
+    for name in ["compressed.jsonl.gz", "compressed.ndjson.zst"] {
+        let path = compressed_input(name, &jsonl);
+        let (_, output) = run_cli(&[&path], None);
+        assert_eq!(parse_output(&output), parse_output(&expected), "{}", name);
+    }
+}
+
+#[test]
+fn test_cli_compressed_outputs() {
+    let input = "test_data/34_rejections_input.csv";
+    let (_, expected) = run_cli(&[input], None);
+    let expected_rejections =
+        std::fs::read_to_string("test_data/34_rejections_rejections_expected.csv").unwrap();
+
+    let output = format!("{}/compressed_output.csv.zst", env!("CARGO_TARGET_TMPDIR"));
+    let rejections = format!(
+        "{}/compressed_rejections.csv.gz",
+        env!("CARGO_TARGET_TMPDIR")
+    );
+    let (code, stdout) = run_cli(
+        &[input, "--output", &output, "--rejections", &rejections],
+        None,
+    );
+    assert_eq!((code, stdout.as_str()), (3, ""));
+
+    let output = zstd::decode_all(std::fs::File::open(&output).unwrap()).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(std::fs::File::open(&rejections).unwrap()),
+        &mut decoded,
+    )
+    .unwrap();
+    assert_eq!(decoded, expected_rejections);
+}
+
+#[test]
+fn test_cli_compressed_json_report() {
+    let input = "test_data/comprehensive_test_input.csv";
+    let (_, expected) = run_cli(&["validate", input, "--format", "json"], None);
+
+    let output = format!(
+        "{}/compressed_validate.json.gz",
+        env!("CARGO_TARGET_TMPDIR")
+    );
+    run_cli(
+        &["validate", input, "--format", "json", "-o", &output],
+        None,
+    );
+
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(std::fs::File::open(&output).unwrap()),
+        &mut decoded,
+    )
+    .unwrap();
+    assert_eq!(decoded, expected);
+}
+
+#[test]
+fn test_40_ledger() {
+    run_and_compare("40_ledger");
+
+    let expected = std::fs::read_to_string("test_data/40_ledger_ledger_expected.csv").unwrap();
+    for flags in [&[][..], &["--workers", "2"], &["--fast-parse"]] {
+        let ledger = format!("{}/40_ledger.csv", env!("CARGO_TARGET_TMPDIR"));
+        let mut args = vec!["test_data/40_ledger_input.csv", "--ledger", &ledger];
+        args.extend_from_slice(flags);
+        let (code, _) = run_cli(&args, None);
+
+        assert_eq!(code, 3);
+        assert_eq!(
+            std::fs::read_to_string(&ledger).unwrap(),
+            expected,
+            "{:?}",
+            flags
+        );
+    }
+}
+
+#[test]
+fn test_cli_ledger_adds_up_to_the_report() {
+    for name in ["comprehensive_test", "37_awkward_fields"] {
+        let input = format!("test_data/{}_input.csv", name);
+        let ledger = format!("{}/{}_ledger.csv", env!("CARGO_TARGET_TMPDIR"), name);
+        let (_, output) = run_cli(&[&input, "--ledger", &ledger], None);
+        let accounts = parse_output(&output);
+
+        // Each client's deltas sum to its final balances, and its last row
+        // shows them exactly as the report does
+        let mut reader = csv::Reader::from_path(&ledger).unwrap();
+        let mut sums: HashMap<u16, (rust_decimal::Decimal, rust_decimal::Decimal)> = HashMap::new();
+        let mut last: HashMap<u16, (String, String, String, bool)> = HashMap::new();
+        for row in reader.records() {
+            let row = row.unwrap();
+            let client: u16 = row[2].parse().unwrap();
+            let sum = sums.entry(client).or_default();
+            sum.0 += row[5].parse::<rust_decimal::Decimal>().unwrap();
+            sum.1 += row[6].parse::<rust_decimal::Decimal>().unwrap();
+            last.insert(
+                client,
+                (
+                    row[7].to_string(),
+                    row[8].to_string(),
+                    row[9].to_string(),
+                    &row[10] == "true",
+                ),
+            );
+        }
+        assert_eq!(last, accounts, "{}", name);
+        for (client, (available, held)) in sums {
+            let account = &accounts[&client];
+            assert_eq!(available, account.0.parse().unwrap(), "{}", name);
+            assert_eq!(held, account.1.parse().unwrap(), "{}", name);
+        }
+    }
+}